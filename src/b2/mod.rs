@@ -0,0 +1,294 @@
+// B2 API calls that raze does not provide (yet)
+// Mirrors raze's conventions: blocking calls that take a shared 'Client' and return raze's types/errors
+// Requests and responses are (de)serialized with nanoserde, like the rest of the program
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use nanoserde::{DeJson, SerJson};
+use raze::api::{B2Auth, B2FileInfo, Sha1Variant, UploadAuth};
+use reqwest::blocking::Client;
+use reqwest::header::HeaderMap;
+
+/// File as returned by the B2 API
+/// Unlike raze::api::B2FileInfo this also contains the custom 'fileInfo' pairs
+// Option fields name their default explicitly here and in the other JSON structs,
+// what the DeJson derive generates for them otherwise trips clippy::question_mark
+#[derive(Debug, Clone, DeJson)]
+pub struct FileVersion {
+    #[nserde(rename = "accountId")]
+    pub account_id: String,
+    pub action: String,
+    #[nserde(rename = "bucketId")]
+    pub bucket_id: String,
+    #[nserde(rename = "contentLength")]
+    pub content_length: u64,
+    #[nserde(rename = "contentSha1")]
+    #[nserde(default_with = "Option::default")]
+    pub content_sha1: Option<String>,
+    #[nserde(rename = "contentType")]
+    #[nserde(default_with = "Option::default")]
+    pub content_type: Option<String>,
+    #[nserde(rename = "fileId")]
+    #[nserde(default_with = "Option::default")]
+    pub file_id: Option<String>,
+    #[nserde(rename = "fileInfo")]
+    #[nserde(default)]
+    pub file_info: HashMap<String, String>,
+    #[nserde(rename = "fileName")]
+    pub file_name: String,
+    #[nserde(rename = "uploadTimestamp")]
+    pub upload_timestamp: u64,
+}
+
+impl From<FileVersion> for B2FileInfo {
+    fn from(f: FileVersion) -> Self {
+        B2FileInfo {
+            account_id: f.account_id,
+            action: f.action,
+            bucket_id: f.bucket_id,
+            content_length: f.content_length,
+            content_sha1: f.content_sha1,
+            content_type: f.content_type,
+            file_id: f.file_id,
+            file_name: f.file_name,
+            upload_timestamp: f.upload_timestamp,
+        }
+    }
+}
+
+#[derive(DeJson)]
+struct ApiError {
+    status: u16,
+    code: String,
+    message: String,
+}
+
+/// Information about a file being uploaded with 'b2_upload_file'
+/// Same as raze::api::FileParameters, but with custom file info (sent as X-Bz-Info-* headers)
+/// Keys must be valid header names, values are percent-encoded before sending
+pub struct FileParameters<'a> {
+    pub file_path: &'a str,
+    pub file_size: u64,
+    pub content_type: Option<&'a str>,
+    pub content_sha1: Sha1Variant<'a>,
+    pub last_modified_millis: u64,
+    pub file_info: Vec<(&'a str, String)>,
+}
+
+/// A file being downloaded
+/// 'reader' streams the body, 'file_info' holds the decoded X-Bz-Info-* headers
+pub struct Download {
+    pub file_info: HashMap<String, String>,
+    pub reader: reqwest::blocking::Response,
+}
+
+/// <https://www.backblaze.com/b2/docs/b2_upload_file.html>
+///
+/// Behaves like raze::api::b2_upload_file, including the handling of Sha1Variant::HexAtEnd
+pub fn b2_upload_file<R: 'static + Read + Send>(client: &Client, auth: &UploadAuth, file: R, params: FileParameters) -> Result<B2FileInfo, raze::Error> {
+    let hash = match params.content_sha1 {
+        Sha1Variant::Precomputed(hash) => hash,
+        Sha1Variant::HexAtEnd => "hex_digits_at_end",
+        Sha1Variant::DoNotVerify => "do_not_verify",
+    };
+
+    // If we use hex digits at end, we need to add 40 bytes to account for the hex characters
+    let file_size = match params.content_sha1 {
+        Sha1Variant::HexAtEnd => params.file_size + 40,
+        _ => params.file_size,
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(reqwest::header::AUTHORIZATION, auth.authorization_token.parse().unwrap());
+    headers.insert(reqwest::header::CONTENT_TYPE, params.content_type.unwrap_or("b2/x-auto").parse().unwrap());
+    headers.insert(reqwest::header::CONTENT_LENGTH, file_size.into());
+    headers.insert("X-Bz-File-Name", percent_encode(params.file_path).parse().unwrap());
+    headers.insert("X-Bz-Content-Sha1", hash.parse().unwrap());
+    headers.insert("X-Bz-Info-src_last_modified_millis", params.last_modified_millis.into());
+    for (key, value) in params.file_info.iter() {
+        let name = match reqwest::header::HeaderName::from_bytes(format!("X-Bz-Info-{}", key).as_bytes()) {
+            Ok(n) => n,
+            Err(_e) => return Err(invalid_data(format!("Invalid file info key {:?}", key))),
+        };
+        headers.insert(name, percent_encode(value).parse().unwrap());
+    }
+
+    let body = reqwest::blocking::Body::sized(file, file_size);
+    let resp = match client.post(&auth.upload_url).headers(headers).body(body).send() {
+        Ok(v) => v,
+        Err(e) => return Err(raze::Error::ReqwestError(e)),
+    };
+    let file: FileVersion = parse_response(resp)?;
+    Ok(file.into())
+}
+
+/// <https://www.backblaze.com/b2/docs/b2_download_file_by_id.html>
+///
+/// Returns as soon as the headers are received, the body is read through 'Download::reader'
+pub fn b2_download_file_by_id<T: AsRef<str>>(client: &Client, auth: &B2Auth, file_id: T) -> Result<Download, raze::Error> {
+    let url = format!("{}/b2api/v2/b2_download_file_by_id?fileId={}", auth.download_url, percent_encode(file_id.as_ref()));
+    let resp = match client.get(&url)
+        .header(reqwest::header::AUTHORIZATION, &auth.authorization_token)
+        .send() {
+        Ok(v) => v,
+        Err(e) => return Err(raze::Error::ReqwestError(e)),
+    };
+    if !resp.status().is_success() {
+        return Err(error_from_response(resp));
+    }
+
+    let mut file_info = HashMap::new();
+    for (name, value) in resp.headers().iter() {
+        if let Some(key) = name.as_str().to_ascii_lowercase().strip_prefix("x-bz-info-") {
+            file_info.insert(key.to_string(), percent_decode(value.to_str().unwrap_or("")));
+        }
+    }
+
+    Ok(Download {
+        file_info,
+        reader: resp,
+    })
+}
+
+/// Response of 'b2_list_file_versions'
+/// If 'next_file_name' is set, there are more versions, starting at 'next_file_name' and 'next_file_id'
+#[derive(Debug, DeJson)]
+pub struct FileVersions {
+    pub files: Vec<FileVersion>,
+    #[nserde(rename = "nextFileName")]
+    #[nserde(default_with = "Option::default")]
+    pub next_file_name: Option<String>,
+    #[nserde(rename = "nextFileId")]
+    #[nserde(default_with = "Option::default")]
+    pub next_file_id: Option<String>,
+}
+
+/// <https://www.backblaze.com/b2/docs/b2_list_file_versions.html>
+///
+/// Lists all versions of all files (including hide markers), sorted by name and then newest first
+/// 'start' is the name and id to start at, as returned in the previous response
+pub fn b2_list_file_versions(client: &Client, auth: &B2Auth, bucket_id: &str, start: Option<(&str, &str)>, max_file_count: u32) -> Result<FileVersions, raze::Error> {
+    let mut body = format!("{{\"bucketId\":{},\"maxFileCount\":{}", bucket_id.to_string().serialize_json(), max_file_count);
+    if let Some((name, id)) = start {
+        body.push_str(&format!(",\"startFileName\":{},\"startFileId\":{}", name.to_string().serialize_json(), id.to_string().serialize_json()));
+    }
+    body.push('}');
+
+    let resp = match client.post(&auth.api_url_for("b2_list_file_versions"))
+        .header(reqwest::header::AUTHORIZATION, &auth.authorization_token)
+        .body(body)
+        .send() {
+        Ok(v) => v,
+        Err(e) => return Err(raze::Error::ReqwestError(e)),
+    };
+    parse_response(resp)
+}
+
+/// Calls 'b2_list_file_versions' until all versions in the bucket have been listed
+pub fn list_all_file_versions(client: &Client, auth: &B2Auth, bucket_id: &str, max_file_count: u32) -> Result<Vec<FileVersion>, raze::Error> {
+    let mut versions = vec![];
+    let mut start: Option<(String, String)> = None;
+    loop {
+        let start_ref = start.as_ref().map(|(n, i)| (n.as_str(), i.as_str()));
+        let mut resp = b2_list_file_versions(client, auth, bucket_id, start_ref, max_file_count)?;
+        versions.append(&mut resp.files);
+        match (resp.next_file_name, resp.next_file_id) {
+            (Some(name), Some(id)) => start = Some((name, id)),
+            _ => return Ok(versions),
+        }
+    }
+}
+
+/// <https://www.backblaze.com/b2/docs/b2_copy_file.html>
+///
+/// Copies a file within the bucket without downloading it, keeping its content type and file info
+/// B2 only copies files of up to 5 GB in a single call
+pub fn b2_copy_file(client: &Client, auth: &B2Auth, source_file_id: &str, file_name: &str) -> Result<B2FileInfo, raze::Error> {
+    let body = format!("{{\"sourceFileId\":{},\"fileName\":{}}}",
+                       source_file_id.to_string().serialize_json(), file_name.to_string().serialize_json());
+
+    let resp = match client.post(&auth.api_url_for("b2_copy_file"))
+        .header(reqwest::header::AUTHORIZATION, &auth.authorization_token)
+        .body(body)
+        .send() {
+        Ok(v) => v,
+        Err(e) => return Err(raze::Error::ReqwestError(e)),
+    };
+    let file: FileVersion = parse_response(resp)?;
+    Ok(file.into())
+}
+
+/// Deserializes a successful response, or turns a failed one into a raze::Error
+fn parse_response<T: DeJson>(resp: reqwest::blocking::Response) -> Result<T, raze::Error> {
+    if !resp.status().is_success() {
+        return Err(error_from_response(resp));
+    }
+    let text = match resp.text() {
+        Ok(t) => t,
+        Err(e) => return Err(raze::Error::ReqwestError(e)),
+    };
+    match DeJson::deserialize_json(&text) {
+        Ok(v) => Ok(v),
+        Err(e) => Err(invalid_data(format!("{:?} - {}", e, text))),
+    }
+}
+
+// B2 reports errors as a JSON object with 'status', 'code' and 'message'
+fn error_from_response(resp: reqwest::blocking::Response) -> raze::Error {
+    let text = match resp.text() {
+        Ok(t) => t,
+        Err(e) => return raze::Error::ReqwestError(e),
+    };
+    match ApiError::deserialize_json(&text) {
+        Ok(e) => raze::Error::B2Error(raze::B2ApiError {
+            status: e.status,
+            code: e.code,
+            message: e.message,
+        }),
+        Err(_e) => invalid_data(text),
+    }
+}
+
+fn invalid_data(msg: String) -> raze::Error {
+    raze::Error::IOError(std::io::Error::new(std::io::ErrorKind::InvalidData, msg))
+}
+
+/// Encodes a string for use in a B2 header or URL
+/// See https://www.backblaze.com/b2/docs/string_encoding.html
+/// '/' is left as-is, as B2 allows it and it keeps file names readable
+pub fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => out.push(b as char),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Reverses 'percent_encode' (and B2's encoding of headers in responses)
+/// Malformed escapes are kept as-is
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+            if let Ok(b) = u8::from_str_radix(hex, 16) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        } else if bytes[i] == b'+' {
+            out.push(b' ');
+            i += 1;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
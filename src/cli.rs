@@ -0,0 +1,296 @@
+// Command line interface
+// Running the program without arguments starts the GUI, otherwise the first argument is a command
+// Commands use the same config.cfg and backuplist.dat as the GUI
+
+use std::io::BufRead;
+use std::path::Path;
+
+use raze::api::{B2Auth, B2FileInfo};
+use reqwest::blocking::Client;
+
+use crate::files::{diff, selection};
+use crate::files::manifest::{self, Manifest};
+use crate::files::naming::Naming;
+use crate::ui::GUIConfig;
+
+const USAGE: &str = "Usage: backup_gui_wgpu [command]
+Starts the GUI if no command is given
+
+Commands:
+  restore <destination> [prefix] [--from <remote prefix>]
+                                   Download all files, or only those whose name starts with 'prefix', to 'destination'
+                                   With --from, the files another computer backed up under its remote prefix are restored
+  snapshots [--remote]             List the backup runs recorded on this computer, or in the bucket
+  diff [--remote] [from] [to]      List the files added, modified and removed between two snapshots
+                                   Snapshots are given by number (see 'snapshots') or date, e.g. 2020-06-30
+                                   Defaults to the two latest snapshots, or 'from' and the latest snapshot
+  purge [--dry-run] [--force]      List the files in the bucket that are no longer needed, then hide them after confirmation
+                                   With --dry-run, only the list is printed
+                                   Refuses if a selected path is missing or too many files would be hidden, unless --force is given
+  undo-purge                       Restore the files hidden by the last purge
+  retention [--dry-run]            List the old file versions the retention rules (see the advanced options) don't keep,
+                                   then delete them after confirmation. With --dry-run, only the list is printed
+  migrate-prefix [--from <prefix>] [--dry-run]
+                                   Copy files backed up under another remote prefix (by default, those from before
+                                   remote prefixes existed) under this computer's prefix, after confirmation
+  export-selection <file>          Write the selection (backuplist.dat) to 'file', with paths under the home directory as '~/'
+  import-selection <file> [--merge]
+                                   Replace the selection with the one in 'file', or add its rules to it with --merge
+                                   '~/' is expanded to the home directory, the previous selection is kept as backuplist.dat.bak
+  help                             Show this message";
+
+/// Runs the command in 'args' (not including the program name)
+/// Returns the exit code
+pub fn run(args: &[String]) -> i32 {
+    let cfg = GUIConfig::from_file("config.cfg");
+
+    let result = match args[0].as_str() {
+        "restore" => restore(&cfg, &args[1..]),
+        "snapshots" => snapshots(&cfg, &args[1..]),
+        "diff" => diff(&cfg, &args[1..]),
+        "purge" => purge(&cfg, &args[1..]),
+        "retention" => retention(&cfg, &args[1..]),
+        "undo-purge" => crate::purge::undo_last(&cfg).map(|n| println!("Restored {} files", n)),
+        "migrate-prefix" => migrate(&cfg, &args[1..]),
+        "export-selection" => export_selection(&args[1..]),
+        "import-selection" => import_selection(&args[1..]),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        },
+        _ => Err(format!("Unknown command {:?}\n{}", args[0], USAGE)),
+    };
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        },
+    }
+}
+
+fn restore(cfg: &GUIConfig, args: &[String]) -> Result<(), String> {
+    let (naming, args) = match args.iter().position(|a| a == "--from") {
+        Some(i) => match args.get(i + 1) {
+            Some(p) => (Naming::new(p), [&args[..i], &args[i + 2..]].concat()),
+            None => return Err(format!("Missing remote prefix after --from\n{}", USAGE)),
+        },
+        None => (Naming::from_config(cfg), args.to_vec()),
+    };
+    let destination = match args.first() {
+        Some(d) => d,
+        None => return Err(format!("Missing destination\n{}", USAGE)),
+    };
+    let prefix = args.get(1).map(|s| s.as_str()).unwrap_or("");
+    crate::restore::restore(cfg, &naming, prefix, Path::new(destination))
+}
+
+fn snapshots(cfg: &GUIConfig, args: &[String]) -> Result<(), String> {
+    if args.iter().any(|a| a == "--remote") {
+        // Only list the manifests, downloading them all could take a while
+        let (_client, _auth, files) = connect(cfg)?;
+        let manifests = manifest::manifests(&files);
+        if manifests.is_empty() {
+            println!("No snapshots in the bucket, they are recorded after each completed upload");
+        }
+        for (i, m) in manifests.iter().enumerate() {
+            let time = manifest::timestamp_from_name(&m.file_name).map(manifest::format_timestamp).unwrap_or_default();
+            println!("{:>4}  {}  {}", i + 1, time, m.file_name);
+        }
+        return Ok(());
+    }
+
+    let snapshots = manifest::local_snapshot_list();
+    if snapshots.is_empty() {
+        println!("No snapshots in {:?}, they are recorded after each completed upload", manifest::SNAPSHOT_DIR);
+    }
+    for (i, t) in snapshots.iter().enumerate() {
+        match Manifest::from_file(manifest::local_path(*t)) {
+            Ok(s) => println!("{:>4}  {}  {}  {} files  {}", i + 1, manifest::format_timestamp(s.timestamp), s.host,
+                              s.files.len(), manifest::format_size(s.total_size())),
+            Err(e) => println!("{:>4}  {}  {}", i + 1, manifest::format_timestamp(*t), e),
+        }
+    }
+    Ok(())
+}
+
+fn diff(cfg: &GUIConfig, args: &[String]) -> Result<(), String> {
+    let remote = args.iter().any(|a| a == "--remote");
+    let args: Vec<&String> = args.iter().filter(|a| *a != "--remote").collect();
+
+    let (old, new) = if remote {
+        let (client, auth, files) = connect(cfg)?;
+        let manifests = manifest::manifests(&files);
+        let timestamps: Vec<u64> = manifests.iter()
+            .map(|m| manifest::timestamp_from_name(&m.file_name).unwrap_or(0))
+            .collect();
+        let (from, to) = select_pair(&timestamps, &args)?;
+        let download = |i: usize| match &manifests[i].file_id {
+            Some(id) => Manifest::download(&client, &auth, id),
+            None => Err(format!("Manifest {:?} has no file id", manifests[i].file_name)),
+        };
+        (download(from)?, download(to)?)
+    } else {
+        let timestamps = manifest::local_snapshot_list();
+        let (from, to) = select_pair(&timestamps, &args)?;
+        (Manifest::from_file(manifest::local_path(timestamps[from]))?, Manifest::from_file(manifest::local_path(timestamps[to]))?)
+    };
+
+    println!("Comparing {} ({}) with {} ({})", manifest::format_timestamp(old.timestamp), old.host,
+             manifest::format_timestamp(new.timestamp), new.host);
+    let dirs = diff::diff(&old, &new);
+    for d in dirs.iter() {
+        let dir = if d.dir.is_empty() { "/" } else { &d.dir };
+        println!("{} ({})", dir, diff::format_delta(d.delta()));
+        for c in d.changes.iter() {
+            match c.kind {
+                diff::ChangeKind::Modified => println!("  {} {}  {} -> {}", c.kind.symbol(), c.file_name(),
+                                                       manifest::format_size(c.old_size), manifest::format_size(c.new_size)),
+                _ => println!("  {} {}  {}", c.kind.symbol(), c.file_name(), manifest::format_size(c.old_size.max(c.new_size))),
+            }
+        }
+    }
+    println!("{}", diff::summary(&dirs));
+    Ok(())
+}
+
+fn purge(cfg: &GUIConfig, args: &[String]) -> Result<(), String> {
+    let dry_run = args.iter().any(|a| a == "--dry-run");
+    let force = args.iter().any(|a| a == "--force");
+    let plan = crate::purge::plan(cfg)?;
+    if plan.items.is_empty() {
+        println!("{}", plan.nothing_to_purge());
+        return Ok(());
+    }
+    plan.print();
+    if dry_run {
+        return Ok(());
+    }
+    if !force && !plan.warnings().is_empty() {
+        return Err("Refusing to purge because of the warnings above, use --force to purge anyway".to_string());
+    }
+
+    println!("Hide these {} files? Type 'yes' to continue", plan.items.len());
+    let mut answer = String::new();
+    let _ = std::io::stdin().lock().read_line(&mut answer);
+    if answer.trim() != "yes" {
+        println!("Purge cancelled, nothing was hidden");
+        return Ok(());
+    }
+    match crate::purge::execute(plan)? {
+        0 => Ok(()),
+        failed => Err(format!("{} files could not be hidden", failed)),
+    }
+}
+
+fn retention(cfg: &GUIConfig, args: &[String]) -> Result<(), String> {
+    let dry_run = args.iter().any(|a| a == "--dry-run");
+    let plan = crate::retention::plan(cfg)?;
+    if plan.deletions.is_empty() {
+        println!("Nothing to delete, {} versions are kept", plan.kept);
+        return Ok(());
+    }
+    plan.print();
+    if dry_run {
+        return Ok(());
+    }
+
+    println!("Permanently delete these {} versions? Type 'yes' to continue", plan.deletions.len());
+    let mut answer = String::new();
+    let _ = std::io::stdin().lock().read_line(&mut answer);
+    if answer.trim() != "yes" {
+        println!("Cancelled, nothing was deleted");
+        return Ok(());
+    }
+    match crate::retention::execute(plan) {
+        0 => Ok(()),
+        failed => Err(format!("{} versions could not be deleted", failed)),
+    }
+}
+
+fn migrate(cfg: &GUIConfig, args: &[String]) -> Result<(), String> {
+    let dry_run = args.iter().any(|a| a == "--dry-run");
+    let from = match args.iter().position(|a| a == "--from") {
+        Some(i) => match args.get(i + 1) {
+            Some(p) => p.as_str(),
+            None => return Err(format!("Missing remote prefix after --from\n{}", USAGE)),
+        },
+        None => "",
+    };
+    let plan = crate::migrate::plan(cfg, from)?;
+    if plan.copies.is_empty() {
+        println!("Nothing to copy, {} files are already there", plan.existing);
+        return Ok(());
+    }
+    plan.print();
+    if dry_run {
+        return Ok(());
+    }
+
+    println!("Copy these {} files? Type 'yes' to continue", plan.copies.len());
+    let mut answer = String::new();
+    let _ = std::io::stdin().lock().read_line(&mut answer);
+    if answer.trim() != "yes" {
+        println!("Cancelled, nothing was copied");
+        return Ok(());
+    }
+    match crate::migrate::execute(plan) {
+        0 => Ok(()),
+        failed => Err(format!("{} files could not be copied", failed)),
+    }
+}
+
+fn export_selection(args: &[String]) -> Result<(), String> {
+    let file = match args.first() {
+        Some(f) => f,
+        None => return Err(format!("Missing file to export to\n{}", USAGE)),
+    };
+    let count = selection::export("backuplist.dat", file)?;
+    println!("Exported {} rules to {:?}", count, file);
+    Ok(())
+}
+
+fn import_selection(args: &[String]) -> Result<(), String> {
+    let merge = args.iter().any(|a| a == "--merge");
+    let file = match args.iter().find(|a| *a != "--merge") {
+        Some(f) => f,
+        None => return Err(format!("Missing file to import\n{}", USAGE)),
+    };
+    for w in selection::import(file, "backuplist.dat", merge)? {
+        println!("Warning: {}", w);
+    }
+    println!("Imported the selection from {:?}", file);
+    Ok(())
+}
+
+// Picks the two snapshots to compare from the arguments of 'diff'
+fn select_pair(timestamps: &[u64], args: &[&String]) -> Result<(usize, usize), String> {
+    if timestamps.len() < 2 {
+        return Err("At least two snapshots are needed to compare".to_string());
+    }
+    let select = |arg: &String| match manifest::select(timestamps, arg) {
+        Some(i) => Ok(i),
+        None => Err(format!("No snapshot matches {:?}", arg)),
+    };
+    let latest = timestamps.len() - 1;
+    match args {
+        [] => Ok((latest - 1, latest)),
+        [from] => Ok((select(from)?, latest)),
+        [from, to, ..] => Ok((select(from)?, select(to)?)),
+    }
+}
+
+// Authorizes and lists all files under our remote prefix
+fn connect(cfg: &GUIConfig) -> Result<(Client, B2Auth, Vec<B2FileInfo>), String> {
+    let client = reqwest::blocking::Client::builder().timeout(None).build().unwrap();
+    let keystring = format!("{}:{}", cfg.app_key_id, cfg.app_key);
+    let auth = match raze::api::b2_authorize_account(&client, keystring) {
+        Ok(a) => a,
+        Err(_e) => return Err("Authentication failed".to_string()),
+    };
+    let files = match raze::util::list_all_files(&client, &auth, &cfg.bucket_id, 1000) {
+        Ok(f) => Naming::from_config(cfg).strip_all(f),
+        Err(e) => return Err(format!("Failed to get remote files - {:?}", e)),
+    };
+    Ok((client, auth, files))
+}
@@ -0,0 +1,235 @@
+// Chunked (deduplicated) repository format
+//
+// Instead of uploading each file as-is, files are split into content-defined chunks (see files::chunker)
+// Each chunk is stored once, named after the SHA1 of its content, under CHUNK_PREFIX
+// At the end of each upload run a manifest is written, listing the chunks of every file (see files::manifest)
+//
+// This means identical files are only stored once, and modifying part of a large file
+// only uploads the chunks around the modification
+// Purging becomes garbage collection: chunks not referenced by any kept manifest are hidden
+// Every manifest is a snapshot that can be restored (see restore.rs), so manifests are only retired
+// by the retention policy (see retention::Policy), and kept for good if it isn't set
+
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::mpsc::Sender;
+
+use raze::api::{B2FileInfo, UploadAuth};
+use reqwest::blocking::Client;
+
+use crate::files::chunker::Chunker;
+use crate::files::compression;
+use crate::files::manifest::{self, Manifest, ManifestEntry};
+use crate::files::naming::Naming;
+use crate::files::objects;
+use crate::files::posix::PosixMeta;
+use crate::files::sparse::{self, DataReader};
+use crate::files::tracked_reader::TrackedReader;
+use crate::retention::Policy;
+
+/// Prefix of chunks in the bucket
+pub const CHUNK_PREFIX: &str = ".backup_gui/chunks/";
+
+/// Name of the chunk with the given hash in the bucket
+/// The first 2 characters are used as a directory, so the web interface can still list them
+pub fn chunk_name(hash: &str) -> String {
+    format!("{}{}/{}", CHUNK_PREFIX, &hash[..2], hash)
+}
+
+/// State shared between the upload threads during a chunked upload
+pub struct ChunkedUpload {
+    // Names of chunks that are stored in the bucket, or are being uploaded by another thread
+    known_chunks: Mutex<HashSet<String>>,
+    // Chunks that failed to upload, files using them are left out of the manifest
+    failed_chunks: Mutex<HashSet<String>>,
+    // Files in the previous manifest, by name
+    previous: HashMap<String, ManifestEntry>,
+    // Files in this run, becomes the new manifest
+    entries: Mutex<Vec<ManifestEntry>>,
+    naming: Naming,
+}
+
+impl ChunkedUpload {
+    /// 'remote' is the list of files in the bucket (relative to the remote prefix), 'previous' the latest manifest (if any)
+    pub fn new(remote: &[B2FileInfo], previous: Option<Manifest>, naming: Naming) -> Self {
+        let known_chunks = remote.iter()
+            .filter(|f| f.file_name.starts_with(CHUNK_PREFIX))
+            .map(|f| f.file_name.clone())
+            .collect();
+        // Only chunked files can be reused, the previous run may have used another format
+        let previous = match previous {
+            Some(m) => m.files.into_iter().filter(|f| f.is_chunked()).map(|f| (f.name.clone(), f)).collect(),
+            None => HashMap::new(),
+        };
+        ChunkedUpload {
+            known_chunks: Mutex::new(known_chunks),
+            failed_chunks: Mutex::new(HashSet::new()),
+            previous,
+            entries: Mutex::new(vec![]),
+            naming,
+        }
+    }
+
+    /// If the file is unchanged since the previous run, adds it to this run and returns true
+    /// Like the plain format, a file is considered unchanged if its size and modification time are
+    /// Its metadata is updated to 'posix', which can change without the content changing
+    pub fn reuse_unchanged(&self, name: &str, size: u64, modified: u64, posix: &Option<PosixMeta>) -> bool {
+        match self.previous.get(name) {
+            Some(e) if e.size == size && e.modified == modified => {
+                self.entries.lock().unwrap().push(e.clone().with_posix(posix.clone()));
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Keeps the previous version of a file that failed to upload, so its chunks aren't garbage collected
+    pub fn keep_previous(&self, name: &str) {
+        if let Some(e) = self.previous.get(name) {
+            self.entries.lock().unwrap().push(e.clone());
+        }
+    }
+
+    /// Splits a file into chunks and uploads the ones that aren't stored yet, then adds it to this run
+    /// 'progress' receives the number of bytes of the file processed
+    #[allow(clippy::too_many_arguments)]
+    pub fn upload_file(&self, client: &Client, upauth: &UploadAuth, path: &Path, name: &str, size: u64, modified: u64,
+                       posix: Option<PosixMeta>, compress: bool, bandwidth: usize, progress: Sender<usize>) -> Result<(), String> {
+        let file = std::fs::File::open(path).map_err(|e| format!("{:?}", e))?;
+        let compress = compress && compression::should_compress(path, size);
+        // Only the data of sparse files is read, see files::sparse
+        let extents = sparse::data_extents(path, size);
+        let reader: Box<dyn Read> = match &extents {
+            Some(e) => Box::new(DataReader::new(file, e.clone())),
+            None => Box::new(file),
+        };
+        let mut chunker = Chunker::new(TrackedReader::wrap(reader, progress));
+
+        let mut chunks = vec![];
+        let mut file_hasher = sha1::Sha1::new();
+        while let Some(data) = chunker.next_chunk().map_err(|e| format!("{:?}", e))? {
+            file_hasher.update(&data);
+            let mut hasher = sha1::Sha1::new();
+            hasher.update(&data);
+            let hash = hasher.digest().to_string();
+            let chunk = chunk_name(&hash);
+
+            // Insert before uploading, so other threads don't upload the same chunk at the same time
+            let is_new = self.known_chunks.lock().unwrap().insert(chunk.clone());
+            if is_new {
+                match objects::upload(client, upauth, &self.naming.remote(&chunk), data, "application/octet-stream", compress, bandwidth, None) {
+                    Ok(_) => {
+                        self.failed_chunks.lock().unwrap().remove(&chunk);
+                    },
+                    Err(e) => {
+                        self.known_chunks.lock().unwrap().remove(&chunk);
+                        self.failed_chunks.lock().unwrap().insert(chunk);
+                        return Err(e);
+                    },
+                }
+            }
+            chunks.push(hash);
+        }
+
+        self.entries.lock().unwrap().push(ManifestEntry {
+            name: name.to_string(),
+            size,
+            modified,
+            sha1: Some(file_hasher.digest().to_string()),
+            file_id: None,
+            pack: None,
+            offset: 0,
+            chunks,
+            link: None,
+            posix,
+            dir: false,
+            hardlink: None,
+            sparse: extents,
+        });
+        Ok(())
+    }
+
+    /// Adds a symbolic link to this run, it is only recorded in the manifest, see files::links
+    pub fn add_link(&self, name: &str, target: &str, modified: u64) {
+        self.entries.lock().unwrap().push(ManifestEntry {
+            name: name.to_string(),
+            size: target.len() as u64,
+            modified,
+            sha1: None,
+            file_id: None,
+            pack: None,
+            offset: 0,
+            chunks: vec![],
+            link: Some(target.to_string()),
+            posix: None,
+            dir: false,
+            hardlink: None,
+            sparse: None,
+        });
+    }
+
+    /// Returns the files for the manifest of this run
+    /// Files referencing a chunk that failed to upload (by another thread) fall back to their previous version
+    pub fn finish(self) -> Vec<ManifestEntry> {
+        let failed = self.failed_chunks.into_inner().unwrap();
+        let previous = self.previous;
+        self.entries.into_inner().unwrap().into_iter()
+            .filter_map(|e| {
+                if e.chunks.iter().any(|c| failed.contains(&chunk_name(c))) {
+                    println!("{:?} uses a chunk that failed to upload, keeping its previous version", e.name);
+                    previous.get(&e.name).cloned()
+                } else {
+                    Some(e)
+                }
+            })
+            .collect()
+    }
+}
+
+/// Returns the names of chunks and manifests that are no longer needed
+/// That is the manifests the retention policy doesn't keep (the latest is always kept),
+/// and every chunk none of the kept manifests reference
+/// Chunks uploaded after the latest manifest are kept, they belong to a run that is still going or was interrupted
+/// Returns an error if there is no manifest, as every chunk would be considered unused
+pub fn garbage(client: &Client, auth: &raze::api::B2Auth, remote: &[B2FileInfo], policy: &Policy, now: u64) -> Result<Vec<String>, String> {
+    // Newest first, like the policy expects
+    let mut manifests = manifest::manifests(remote);
+    manifests.reverse();
+    let latest = match manifests.first() {
+        Some(m) => *m,
+        None => return Err("No manifest found, finish an upload first".to_string()),
+    };
+    let times: Vec<u64> = manifests.iter()
+        .map(|m| manifest::timestamp_from_name(&m.file_name).unwrap_or(m.upload_timestamp))
+        .collect();
+    let mut keep = match policy.is_enabled() {
+        true => policy.keep(&times, now),
+        false => vec![true; manifests.len()],
+    };
+    keep[0] = true;
+
+    let mut garbage = vec![];
+    let mut referenced = HashSet::new();
+    for (file, keep) in manifests.iter().zip(keep) {
+        if !keep {
+            garbage.push(file.file_name.clone());
+            continue;
+        }
+        let file_id = match &file.file_id {
+            Some(id) => id,
+            None => return Err(format!("Manifest {:?} has no file id", file.file_name)),
+        };
+        let kept = Manifest::download(client, auth, file_id)?;
+        referenced.extend(kept.files.iter().flat_map(|f| f.chunks.iter()).map(|c| chunk_name(c)));
+    }
+
+    for file in remote {
+        let name = &file.file_name;
+        if name.starts_with(CHUNK_PREFIX) && !referenced.contains(name) && file.upload_timestamp <= latest.upload_timestamp {
+            garbage.push(name.clone());
+        }
+    }
+    Ok(garbage)
+}
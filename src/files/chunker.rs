@@ -0,0 +1,177 @@
+// Content-defined chunking, used by the chunked repository format
+//
+// Files are split where a rolling 'gear' hash of the content matches a mask, rather than at fixed offsets
+// This means inserting or appending data only changes the chunks around the modification,
+// all other chunks keep their boundaries and thus their hashes, and don't need to be uploaded again
+//
+// This is a variant of FastCDC (https://www.usenix.org/conference/atc16/technical-sessions/presentation/xia)
+// with 'normalized chunking': a stricter mask is used before the average size, a looser one after it
+
+use std::io::Read;
+
+/// Chunks are never smaller than this, unless the file is
+pub const MIN_SIZE: usize = 256 * 1024;
+/// Target chunk size
+pub const AVG_SIZE: usize = 1024 * 1024;
+/// Chunks are never larger than this
+pub const MAX_SIZE: usize = 4 * 1024 * 1024;
+
+// 22 bits set: makes a cut before AVG_SIZE less likely than 1/AVG_SIZE
+const MASK_SMALL: u64 = 0x0000_aaaa_aaaa_aaa0;
+// 18 bits set: makes a cut after AVG_SIZE more likely than 1/AVG_SIZE
+const MASK_LARGE: u64 = 0x0000_aaaa_aaaa_a000;
+
+// Random value for each byte, mixed into the rolling hash
+// Generated with splitmix64, so the table (and thus all chunk boundaries) is stable across builds
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6261_636b_7570_6775; // "backupgu"
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Splits the contents of a 'Read' into chunks
+pub struct Chunker<R: Read> {
+    inner: R,
+    buffer: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    pub fn new(reader: R) -> Self {
+        Chunker {
+            inner: reader,
+            buffer: Vec::with_capacity(MAX_SIZE),
+            eof: false,
+        }
+    }
+
+    /// Returns the next chunk, or None once the reader is exhausted
+    pub fn next_chunk(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        // Keep at least MAX_SIZE bytes buffered, so we can always find the next cut point
+        let mut read_buffer = [0u8; 64 * 1024];
+        while self.buffer.len() < MAX_SIZE && !self.eof {
+            let n = self.inner.read(&mut read_buffer)?;
+            if n == 0 {
+                self.eof = true;
+            }
+            self.buffer.extend_from_slice(&read_buffer[..n]);
+        }
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+
+        let cut = cut_point(&self.buffer);
+        let remainder = self.buffer.split_off(cut);
+        Ok(Some(std::mem::replace(&mut self.buffer, remainder)))
+    }
+}
+
+// Finds the end of the first chunk in 'data'
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_SIZE {
+        return data.len();
+    }
+    let end = data.len().min(MAX_SIZE);
+    let normal = end.min(AVG_SIZE);
+
+    let mut hash = 0u64;
+    let mut i = MIN_SIZE;
+    while i < normal {
+        hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+        if hash & MASK_SMALL == 0 {
+            return i + 1;
+        }
+        i += 1;
+    }
+    while i < end {
+        hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+        if hash & MASK_LARGE == 0 {
+            return i + 1;
+        }
+        i += 1;
+    }
+    end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Deterministic pseudo-random data (xorshift), which has no structure for the chunker to latch onto
+    fn data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect()
+    }
+
+    fn chunks(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut chunker = Chunker::new(data);
+        let mut chunks = vec![];
+        while let Some(c) = chunker.next_chunk().unwrap() {
+            chunks.push(c);
+        }
+        chunks
+    }
+
+    #[test]
+    fn chunks_are_within_bounds() {
+        let input = data(16 * 1024 * 1024, 1);
+        let chunks = chunks(&input);
+        assert!(chunks.len() > 1);
+        let (last, rest) = chunks.split_last().unwrap();
+        for c in rest {
+            assert!(c.len() >= MIN_SIZE && c.len() <= MAX_SIZE, "chunk of {} bytes", c.len());
+        }
+        assert!(last.len() <= MAX_SIZE);
+        assert_eq!(chunks.concat(), input);
+    }
+
+    #[test]
+    fn cuts_at_max_size_without_a_match() {
+        // A constant input never changes the hash enough to match, every chunk is cut at MAX_SIZE
+        let input = vec![0u8; 2 * MAX_SIZE + 10];
+        let sizes: Vec<usize> = chunks(&input).iter().map(|c| c.len()).collect();
+        assert_eq!(sizes, vec![MAX_SIZE, MAX_SIZE, 10]);
+    }
+
+    #[test]
+    fn small_input_is_one_chunk() {
+        let input = data(MIN_SIZE - 1, 2);
+        assert_eq!(chunks(&input), vec![input]);
+        assert!(chunks(&[]).is_empty());
+    }
+
+    #[test]
+    fn chunking_is_deterministic() {
+        let input = data(8 * 1024 * 1024, 3);
+        assert_eq!(chunks(&input), chunks(&input));
+    }
+
+    #[test]
+    fn boundaries_survive_an_insertion_at_the_start() {
+        let original = data(16 * 1024 * 1024, 4);
+        let mut modified = data(1000, 5);
+        modified.extend_from_slice(&original);
+
+        let before = chunks(&original);
+        let after = chunks(&modified);
+        // Only the chunks around the insertion change
+        let unchanged = before.iter().filter(|c| after.contains(c)).count();
+        assert!(unchanged >= before.len() - 2, "{} of {} chunks unchanged", unchanged, before.len());
+    }
+}
@@ -0,0 +1,110 @@
+// Optional zstd compression of files before they're uploaded
+//
+// Compressed files are marked with the 'b2gui-compression' file info, so restore knows to decompress them
+// Files that are unlikely to get smaller are uploaded as-is, this is decided by:
+// 1. Their extension, i.e. formats that are already compressed (jpg, mp4, zip etc.)
+// 2. The entropy of a sample from the start of the file
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// File info key marking a compressed file, the value is the compression used
+pub const INFO_KEY: &str = "b2gui-compression";
+/// Value of INFO_KEY for zstd compressed files
+pub const ZSTD: &str = "zstd";
+
+// zstd level used, 3 is zstd's default and a good trade-off between speed and ratio
+const LEVEL: i32 = 3;
+// Size of the sample used to estimate entropy
+const SAMPLE_SIZE: usize = 64 * 1024;
+// Samples with more bits of entropy per byte than this are considered incompressible
+const MAX_ENTROPY: f64 = 7.5;
+// Files smaller than this are not worth compressing
+const MIN_SIZE: u64 = 512;
+
+// Extensions of formats that are already compressed
+const SKIP_EXTENSIONS: &[&str] = &[
+    // Images
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "heif", "avif", "jxl",
+    // Audio and video
+    "mp3", "mp4", "m4a", "m4v", "aac", "ogg", "oga", "opus", "flac", "mkv", "webm", "avi", "mov", "wmv",
+    // Archives and compressed data
+    "zip", "gz", "tgz", "bz2", "tbz2", "xz", "txz", "zst", "lz4", "lzma", "7z", "rar", "br", "cab",
+    // Packages and documents that are zip files internally
+    "jar", "apk", "deb", "rpm", "docx", "xlsx", "pptx", "odt", "ods", "odp", "epub",
+];
+
+/// Returns true if the file at 'path' is worth compressing
+/// Checks the extension first, then falls back to sampling the start of the file
+pub fn should_compress(path: &Path, size: u64) -> bool {
+    if size < MIN_SIZE {
+        return false;
+    }
+    if let Some(ext) = path.extension() {
+        let ext = ext.to_string_lossy().to_ascii_lowercase();
+        if SKIP_EXTENSIONS.contains(&ext.as_str()) {
+            return false;
+        }
+    }
+    match sample_entropy(path) {
+        Some(e) => e <= MAX_ENTROPY,
+        None => false,
+    }
+}
+
+/// Compresses what 'input' reads (e.g. a file) to a temporary file 'temp'
+/// Returns the size of the compressed file
+pub fn compress_to<R: Read>(mut input: R, temp: &Path) -> std::io::Result<u64> {
+    let output = File::create(temp)?;
+    let mut encoder = zstd::stream::Encoder::new(output, LEVEL)?;
+    std::io::copy(&mut input, &mut encoder)?;
+    let mut output = encoder.finish()?;
+    output.flush()?;
+    Ok(std::fs::metadata(temp)?.len())
+}
+
+/// Compresses a buffer in memory, used for chunks which are small enough to not need a temporary file
+pub fn compress_bytes(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    zstd::stream::encode_all(data, LEVEL)
+}
+
+/// Path of the temporary file used by upload worker 'worker'
+/// Each worker compresses one file at a time, so this is unique per worker
+pub fn temp_path(worker: usize) -> PathBuf {
+    std::env::temp_dir().join(format!("backup_gui_{}_{}.zst", std::process::id(), worker))
+}
+
+/// Wraps 'reader' in a decoder if 'compression' (the value of INFO_KEY) requires it
+pub fn decoder<'a, R: Read + 'a>(reader: R, compression: Option<&String>) -> std::io::Result<Box<dyn Read + 'a>> {
+    match compression.map(|c| c.as_str()) {
+        None => Ok(Box::new(reader)),
+        Some(ZSTD) => Ok(Box::new(zstd::stream::Decoder::new(reader)?)),
+        Some(c) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unknown compression {:?}", c))),
+    }
+}
+
+// Shannon entropy (in bits per byte) of the start of the file
+// Compressed or encrypted data is close to 8, text is typically around 4-5
+fn sample_entropy(path: &Path) -> Option<f64> {
+    let file = File::open(path).ok()?;
+    let mut buffer = Vec::with_capacity(SAMPLE_SIZE);
+    file.take(SAMPLE_SIZE as u64).read_to_end(&mut buffer).ok()?;
+    if buffer.is_empty() {
+        return None;
+    }
+
+    let mut counts = [0usize; 256];
+    for b in buffer.iter() {
+        counts[*b as usize] += 1;
+    }
+    let len = buffer.len() as f64;
+    let entropy = counts.iter()
+        .filter(|c| **c > 0)
+        .map(|c| {
+            let p = *c as f64 / len;
+            -p * p.log2()
+        })
+        .sum();
+    Some(entropy)
+}
@@ -0,0 +1,206 @@
+// Comparing two snapshots (manifests), e.g. to see what a backup run added or removed
+// Changes are grouped by the directory they're in
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::files::manifest::{self, Manifest, ManifestEntry};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Removed,
+}
+
+impl ChangeKind {
+    /// Single character used when listing changes
+    pub fn symbol(&self) -> char {
+        match self {
+            ChangeKind::Added => '+',
+            ChangeKind::Modified => '~',
+            ChangeKind::Removed => '-',
+        }
+    }
+}
+
+/// A single changed file
+/// The size of a file that doesn't exist (i.e. old_size of an added file) is 0
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub name: String,
+    pub old_size: u64,
+    pub new_size: u64,
+}
+
+impl Change {
+    /// Difference in size, positive if the file grew
+    pub fn delta(&self) -> i64 {
+        self.new_size as i64 - self.old_size as i64
+    }
+
+    /// Name of the file without its directory
+    pub fn file_name(&self) -> &str {
+        match self.name.rfind('/') {
+            Some(i) => &self.name[i+1..],
+            None => &self.name,
+        }
+    }
+}
+
+/// All changes in a single directory (not including subdirectories)
+#[derive(Debug, Clone)]
+pub struct DirChanges {
+    pub dir: String,
+    pub changes: Vec<Change>,
+}
+
+impl DirChanges {
+    pub fn delta(&self) -> i64 {
+        self.changes.iter().map(|c| c.delta()).sum()
+    }
+}
+
+/// Lists the files that were added, modified and removed going from 'old' to 'new', grouped by directory
+/// A file is considered modified if its size, modification time or hash changed
+/// Directories are left out, their modification time changes with every file added or removed
+pub fn diff(old: &Manifest, new: &Manifest) -> Vec<DirChanges> {
+    let old_files: HashMap<&str, &ManifestEntry> = old.files.iter().map(|f| (f.name.as_str(), f)).collect();
+    let new_files: HashMap<&str, &ManifestEntry> = new.files.iter().map(|f| (f.name.as_str(), f)).collect();
+
+    let mut changes = vec![];
+    for f in new.files.iter().filter(|f| !f.dir) {
+        match old_files.get(f.name.as_str()) {
+            None => changes.push(Change { kind: ChangeKind::Added, name: f.name.clone(), old_size: 0, new_size: f.size }),
+            Some(o) => {
+                let hash_changed = match (&o.sha1, &f.sha1) {
+                    (Some(a), Some(b)) => a != b,
+                    _ => false,
+                };
+                if o.size != f.size || o.modified != f.modified || hash_changed {
+                    changes.push(Change { kind: ChangeKind::Modified, name: f.name.clone(), old_size: o.size, new_size: f.size });
+                }
+            },
+        }
+    }
+    for f in old.files.iter().filter(|f| !f.dir) {
+        if !new_files.contains_key(f.name.as_str()) {
+            changes.push(Change { kind: ChangeKind::Removed, name: f.name.clone(), old_size: f.size, new_size: 0 });
+        }
+    }
+
+    // BTreeMap keeps the directories sorted
+    let mut dirs: BTreeMap<String, Vec<Change>> = BTreeMap::new();
+    for c in changes {
+        let dir = match c.name.rfind('/') {
+            Some(i) => c.name[..i+1].to_string(),
+            None => String::new(),
+        };
+        dirs.entry(dir).or_default().push(c);
+    }
+    dirs.into_iter()
+        .map(|(dir, mut changes)| {
+            changes.sort_by(|a, b| a.name.cmp(&b.name));
+            DirChanges { dir, changes }
+        })
+        .collect()
+}
+
+/// Number of added, modified and removed files
+pub fn count(dirs: &[DirChanges]) -> (usize, usize, usize) {
+    let mut counts = (0, 0, 0);
+    for c in dirs.iter().flat_map(|d| d.changes.iter()) {
+        match c.kind {
+            ChangeKind::Added => counts.0 += 1,
+            ChangeKind::Modified => counts.1 += 1,
+            ChangeKind::Removed => counts.2 += 1,
+        }
+    }
+    counts
+}
+
+/// Formats a size difference with its sign, e.g. '+1.5 MiB'
+pub fn format_delta(delta: i64) -> String {
+    let sign = if delta < 0 { '-' } else { '+' };
+    format!("{}{}", sign, manifest::format_size(delta.unsigned_abs()))
+}
+
+/// One line summary of a diff, e.g. '3 added, 1 modified, 0 removed (+1.5 MiB)'
+pub fn summary(dirs: &[DirChanges]) -> String {
+    let (added, modified, removed) = count(dirs);
+    let delta: i64 = dirs.iter().map(|d| d.delta()).sum();
+    format!("{} added, {} modified, {} removed ({})", added, modified, removed, format_delta(delta))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, size: u64, modified: u64, sha1: &str) -> ManifestEntry {
+        ManifestEntry {
+            name: name.to_string(),
+            size,
+            modified,
+            sha1: Some(sha1.to_string()),
+            file_id: None,
+            pack: None,
+            offset: 0,
+            chunks: vec![],
+            link: None,
+            posix: None,
+            dir: false,
+            hardlink: None,
+            sparse: None,
+        }
+    }
+
+    fn manifest(files: Vec<ManifestEntry>) -> Manifest {
+        Manifest { version: manifest::VERSION, timestamp: 0, host: String::new(), rules: vec![], files }
+    }
+
+    fn kinds(dirs: &[DirChanges]) -> Vec<(String, ChangeKind)> {
+        dirs.iter().flat_map(|d| d.changes.iter()).map(|c| (c.name.clone(), c.kind)).collect()
+    }
+
+    #[test]
+    fn lists_added_modified_and_removed_files() {
+        let old = manifest(vec![
+            entry("home/a.txt", 10, 1, "a"),
+            entry("home/b.txt", 20, 1, "b"),
+            entry("home/docs/c.txt", 30, 1, "c"),
+            entry("home/d.txt", 40, 1, "d"),
+        ]);
+        let new = manifest(vec![
+            entry("home/a.txt", 10, 1, "a"),
+            entry("home/b.txt", 25, 2, "b2"),
+            entry("home/d.txt", 40, 1, "d2"),
+            entry("home/docs/e.txt", 50, 2, "e"),
+        ]);
+        let dirs = diff(&old, &new);
+
+        assert_eq!(dirs.iter().map(|d| d.dir.as_str()).collect::<Vec<_>>(), vec!["home/", "home/docs/"]);
+        assert_eq!(kinds(&dirs), vec![
+            ("home/b.txt".to_string(), ChangeKind::Modified),
+            ("home/d.txt".to_string(), ChangeKind::Modified),
+            ("home/docs/c.txt".to_string(), ChangeKind::Removed),
+            ("home/docs/e.txt".to_string(), ChangeKind::Added),
+        ]);
+        assert_eq!(count(&dirs), (1, 2, 1));
+        assert_eq!(dirs.iter().map(|d| d.delta()).sum::<i64>(), 5 - 30 + 50);
+    }
+
+    #[test]
+    fn unchanged_files_and_directories_are_left_out() {
+        let mut dir = entry("home/", 0, 1, "");
+        dir.sha1 = None;
+        dir.dir = true;
+        let old = manifest(vec![dir.clone(), entry("home/a.txt", 10, 1, "a")]);
+        dir.modified = 2;
+        let mut unhashed = entry("home/a.txt", 10, 1, "");
+        unhashed.sha1 = None;
+        let new = manifest(vec![dir, unhashed]);
+
+        assert!(diff(&old, &new).is_empty());
+        assert!(diff(&old, &old).is_empty());
+    }
+}
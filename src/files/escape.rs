@@ -0,0 +1,161 @@
+// Reversible encoding of file names that aren't valid UTF-8 or that B2 doesn't allow (Unix only)
+//
+// On Unix a file name is any sequence of bytes, but names in B2 and in backuplist.dat are UTF-8 strings,
+// and B2 doesn't allow control characters (below 0x20), DEL or '\\' in them
+// Names that are valid UTF-8 without those characters are used as they are, so existing backups keep their names
+// Any other name (e.g. Latin-1 "caf\xe9", or one with a newline) is written as MARKER followed by its bytes,
+// where every byte that isn't printable ASCII, as well as '%' and '\\', becomes %XX: "%%caf%E9"
+// A valid name that starts with MARKER itself is encoded the same way, so decoding is never ambiguous
+//
+// Names are encoded one path component at a time, '/' can't be part of a name
+// On Windows names are UTF-16 and always converted, unpaired surrogates are lost
+
+use std::ffi::OsStr;
+#[cfg(unix)]
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Start of an encoded name
+pub const MARKER: &str = "%%";
+
+/// Encodes a single file name
+#[cfg(unix)]
+pub fn encode_name(name: &OsStr) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    encode_bytes(name.as_bytes())
+}
+
+#[cfg(not(unix))]
+pub fn encode_name(name: &OsStr) -> String {
+    name.to_string_lossy().into_owned()
+}
+
+/// Encodes a path, with '/' as the separator
+/// Leading and trailing separators are kept, e.g. the trailing '/' of a directory
+#[cfg(unix)]
+pub fn encode_path<P: AsRef<Path>>(path: P) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    let components: Vec<String> = path.as_ref().as_os_str().as_bytes()
+        .split(|b| *b == b'/')
+        .map(encode_bytes)
+        .collect();
+    components.join("/")
+}
+
+#[cfg(not(unix))]
+pub fn encode_path<P: AsRef<Path>>(path: P) -> String {
+    path.as_ref().to_string_lossy().replace("\\", "/")
+}
+
+/// Whether any component of a path written by 'encode_path' was encoded
+pub fn is_encoded(path: &str) -> bool {
+    path.split('/').any(|c| c.starts_with(MARKER))
+}
+
+/// Decodes a path written by 'encode_path'
+#[cfg(unix)]
+pub fn decode_path(path: &str) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    let components: Vec<Vec<u8>> = path.split('/').map(decode_bytes).collect();
+    PathBuf::from(OsString::from_vec(components.join(&b'/')))
+}
+
+#[cfg(not(unix))]
+pub fn decode_path(path: &str) -> PathBuf {
+    PathBuf::from(path)
+}
+
+// Encodes a single component
+#[cfg(unix)]
+fn encode_bytes(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) if !s.starts_with(MARKER) && !s.chars().any(forbidden) => s.to_string(),
+        _ => {
+            let mut encoded = MARKER.to_string();
+            for b in bytes {
+                match b {
+                    b' '..=b'~' if *b != b'%' && *b != b'\\' => encoded.push(*b as char),
+                    b => encoded.push_str(&format!("%{:02X}", b)),
+                }
+            }
+            encoded
+        },
+    }
+}
+
+// Characters B2 doesn't allow in names
+#[cfg(unix)]
+fn forbidden(c: char) -> bool {
+    c < ' ' || c == '\u{7f}' || c == '\\'
+}
+
+// Decodes a single component, anything that isn't a valid escape is kept as it is
+#[cfg(unix)]
+fn decode_bytes(s: &str) -> Vec<u8> {
+    let encoded = match s.strip_prefix(MARKER) {
+        Some(e) => e.as_bytes(),
+        None => return s.as_bytes().to_vec(),
+    };
+    let mut bytes = vec![];
+    let mut i = 0;
+    while i < encoded.len() {
+        let hex = encoded.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match (encoded[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(b)) => {
+                bytes.push(b);
+                i += 3;
+            },
+            (b, _) => {
+                bytes.push(b);
+                i += 1;
+            },
+        }
+    }
+    bytes
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStrExt;
+
+    fn round_trip(bytes: &[u8]) -> String {
+        let path = Path::new(OsStr::from_bytes(bytes));
+        let encoded = encode_path(path);
+        assert_eq!(decode_path(&encoded), path, "{:?}", encoded);
+        encoded
+    }
+
+    #[test]
+    fn valid_names_are_kept() {
+        assert_eq!(round_trip(b"/home/user/caf\xc3\xa9 50%.txt"), "/home/user/caf\u{e9} 50%.txt");
+        assert_eq!(round_trip(b"dir/"), "dir/");
+        assert!(!is_encoded("/home/user/caf\u{e9} 50%.txt"));
+    }
+
+    #[test]
+    fn latin1_names_are_encoded() {
+        assert_eq!(round_trip(b"/home/caf\xe9/a"), "/home/%%caf%E9/a");
+        assert!(is_encoded("/home/%%caf%E9/a"));
+    }
+
+    #[test]
+    fn names_starting_with_the_marker_are_encoded() {
+        assert_eq!(round_trip(b"%%notes"), "%%%25%25notes");
+        assert_eq!(round_trip(b"50%%"), "50%%");
+    }
+
+    #[test]
+    fn forbidden_characters_are_encoded() {
+        assert_eq!(round_trip(b"a\\b"), "%%a%5Cb");
+        assert_eq!(round_trip(b"line\nbreak"), "%%line%0Abreak");
+        assert_eq!(round_trip(b"tab\there\x7f"), "%%tab%09here%7F");
+    }
+
+    #[test]
+    fn invalid_escapes_are_kept() {
+        assert_eq!(decode_path("%%a%zz"), Path::new("a%zz"));
+        assert_eq!(decode_path("%%a%4"), Path::new("a%4"));
+        assert_eq!(decode_path("%%a%41"), Path::new("aA"));
+    }
+}
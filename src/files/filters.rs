@@ -0,0 +1,383 @@
+// Selection filters, which skip files by size, age and type while collecting the files to upload
+//
+// Set in the advanced options, see GUIConfig::filter_max_size, filter_max_age and filter_types
+// Unlike pattern rules (see patterns.rs), filters don't affect purge: files uploaded before a filter was set are kept
+// Skipped files are remembered, so the upload can carry what the previous backup recorded for them into the new manifest
+// A type is either a file extension (e.g. "iso") or one of the classes below (e.g. "video"), matched case-insensitively
+//
+// Directories holding a CACHEDIR.TAG (see https://bford.info/cachedir/) and, on Linux, files with the nodump
+// attribute (chattr +d) are skipped as well, see GUIConfig::skip_cachedir and skip_nodump
+// Reading the attribute means opening the file, so it is only checked for files that are about to be uploaded, see 'skip_nodump'
+//
+// Virtual file systems (e.g. /proc, see mounts.rs) are never entered
+// With GUIConfig::one_file_system, neither are other mount points nor any other device boundary (e.g. btrfs subvolumes),
+// otherwise a selected directory includes the file systems mounted inside it
+//
+// When symlinks are followed (see links.rs), the directories being enumerated are tracked by (device, inode),
+// so a link back to one of them isn't followed into an endless loop, see 'enter_dir'
+//
+// Everything that is skipped is counted, so the upload page can tell the user what was left out
+// So are files whose names had to be escaped or shortened for B2 (see naming.rs), which are stored all the same
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::files::{escape, links, manifest, mounts};
+use crate::files::naming::Naming;
+use crate::ui::{GUIConfig, RepositoryMode, SymlinkPolicy};
+
+/// Classes of files by their extensions, a rough stand-in for MIME types
+const CLASSES: &[(&str, &[&str])] = &[
+    ("video", &["mp4", "mkv", "avi", "mov", "wmv", "flv", "webm", "m4v", "mpg", "mpeg", "m2ts", "vob"]),
+    ("audio", &["mp3", "flac", "wav", "ogg", "m4a", "aac", "wma", "opus", "aiff"]),
+    ("image", &["jpg", "jpeg", "png", "gif", "bmp", "tif", "tiff", "webp", "heic", "raw", "cr2", "nef", "dng"]),
+    ("archive", &["zip", "rar", "7z", "tar", "gz", "tgz", "bz2", "xz", "zst", "lz4"]),
+    ("disk-image", &["iso", "img", "dmg", "vhd", "vhdx", "vmdk", "qcow2", "vdi"]),
+];
+
+/// Start of a valid CACHEDIR.TAG
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Number and total size of skipped files
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Skipped {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl Skipped {
+    fn add(&mut self, bytes: u64) {
+        self.files += 1;
+        self.bytes += bytes;
+    }
+}
+
+/// The filters in effect, and what they have skipped so far
+#[derive(Debug, Default)]
+pub struct Filters {
+    // In bytes, 0 = no limit
+    max_size: u64,
+    // Files last modified before this are skipped
+    cutoff: Option<SystemTime>,
+    max_age_days: u32,
+    // Lowercase, without the leading '.'
+    extensions: Vec<String>,
+    cachedir: bool,
+    nodump: bool,
+    // Mount points of virtual file systems, without a trailing '/'
+    virtual_mounts: Vec<String>,
+    // Mount points of real file systems, without a trailing '/'
+    mount_points: Vec<String>,
+    one_file_system: bool,
+    follow_links: bool,
+    // Whether .gitignore files are read along with .backupignore files, see patterns.rs
+    respect_gitignore: bool,
+    // (device, inode) of the directories being enumerated, outermost first, None if it couldn't be read
+    ancestors: Mutex<Vec<Option<(u64, u64)>>>,
+    too_large: Mutex<Skipped>,
+    too_old: Mutex<Skipped>,
+    by_type: Mutex<Skipped>,
+    // Only the number of directories is counted, not what is in them
+    cache_dirs: Mutex<Skipped>,
+    no_dump: Mutex<Skipped>,
+    // Files skipped by the checks in 'skip'
+    skipped: Mutex<Vec<PathBuf>>,
+    other_devices: Mutex<Skipped>,
+    cycles: Mutex<Skipped>,
+    // Names of queued files are checked against B2's rules if set, see 'check_name'
+    naming: Option<Naming>,
+    // Only plain objects are named after their file, so only they can have names that are too long
+    check_length: bool,
+    escaped: Mutex<u64>,
+    shortened: Mutex<u64>,
+}
+
+impl Filters {
+    pub fn from_config(cfg: &GUIConfig) -> Self {
+        let mut extensions = vec![];
+        for t in cfg.filter_types.iter() {
+            let t = t.trim().trim_start_matches('.').to_lowercase();
+            match CLASSES.iter().find(|(class, _)| *class == t) {
+                Some((_, exts)) => extensions.extend(exts.iter().map(|e| e.to_string())),
+                None if !t.is_empty() => extensions.push(t),
+                None => (),
+            }
+        }
+        let cutoff = match cfg.filter_max_age {
+            0 => None,
+            days => SystemTime::now().checked_sub(Duration::from_secs(days as u64 * 86400)),
+        };
+        Filters {
+            max_size: cfg.filter_max_size as u64 * 1_000_000, // MB to B
+            cutoff,
+            max_age_days: cfg.filter_max_age,
+            extensions,
+            cachedir: cfg.skip_cachedir,
+            nodump: cfg.skip_nodump && cfg!(target_os = "linux"),
+            naming: Some(Naming::from_config(cfg)),
+            check_length: cfg.repository_mode == RepositoryMode::Files,
+            ..Self::boundaries_only(cfg)
+        }
+    }
+
+    /// Only stops at virtual file systems and, if set, device boundaries, no files are skipped
+    /// Used by purge, see the top of this file
+    pub fn boundaries_only(cfg: &GUIConfig) -> Self {
+        let (virtual_mounts, real_mounts): (Vec<mounts::Mount>, Vec<mounts::Mount>) = mounts::list().into_iter()
+            .filter(|m| m.point != "/")
+            .partition(|m| m.is_virtual());
+        Filters {
+            virtual_mounts: virtual_mounts.into_iter().map(|m| m.point).collect(),
+            mount_points: real_mounts.into_iter().map(|m| m.point).collect(),
+            one_file_system: cfg.one_file_system,
+            follow_links: links::policy() == SymlinkPolicy::Follow,
+            respect_gitignore: cfg.respect_gitignore,
+            ..Default::default()
+        }
+    }
+
+    /// Whether the file at 'path' is skipped, counts and remembers it if so
+    pub fn skip<P: AsRef<Path>>(&self, path: P) -> bool {
+        let path = path.as_ref();
+        let skip = self.check(path);
+        if skip {
+            self.skipped.lock().unwrap().push(path.to_path_buf());
+        }
+        skip
+    }
+
+    // The checks of 'skip'
+    fn check(&self, path: &Path) -> bool {
+        if self.max_size == 0 && self.cutoff.is_none() && self.extensions.is_empty() {
+            return false;
+        }
+        // Metadata is only needed for the size and age filters, but the size is counted for all of them
+        let metadata = match std::fs::metadata(path) {
+            Ok(m) => m,
+            Err(_e) => return false,
+        };
+        let size = metadata.len();
+
+        let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+        if let Some(ext) = extension {
+            if self.extensions.contains(&ext) {
+                self.by_type.lock().unwrap().add(size);
+                return true;
+            }
+        }
+        if self.max_size > 0 && size > self.max_size {
+            self.too_large.lock().unwrap().add(size);
+            return true;
+        }
+        if let (Some(cutoff), Ok(modified)) = (self.cutoff, metadata.modified()) {
+            if modified < cutoff {
+                self.too_old.lock().unwrap().add(size);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Whether the file at 'path', which is about to be uploaded, has the nodump attribute and is skipped, counts it if so
+    /// Unlike 'skip', it isn't remembered, the upload keeps the previous version of the file itself
+    pub fn skip_nodump<P: AsRef<Path>>(&self, path: P, size: u64) -> bool {
+        if self.nodump && has_nodump(path.as_ref()) {
+            self.no_dump.lock().unwrap().add(size);
+            return true;
+        }
+        false
+    }
+
+    pub fn respect_gitignore(&self) -> bool {
+        self.respect_gitignore
+    }
+
+    /// Takes the files skipped so far
+    pub fn take_skipped(&self) -> Vec<PathBuf> {
+        std::mem::take(&mut *self.skipped.lock().unwrap())
+    }
+
+    /// Whether the directory at 'path' is skipped with everything in it, counts it if so
+    pub fn skip_dir<P: AsRef<Path>>(&self, path: P) -> bool {
+        let path = path.as_ref();
+        let name = path.to_string_lossy();
+        let name = name.trim_end_matches('/');
+        // Not counted, nothing there is ever backed up
+        if self.virtual_mounts.iter().any(|p| p == name) {
+            return true;
+        }
+        if self.one_file_system && (self.mount_points.iter().any(|p| p == name) || crosses_device(path)) {
+            self.other_devices.lock().unwrap().add(0);
+            return true;
+        }
+        if !self.cachedir {
+            return false;
+        }
+        let mut signature = [0u8; CACHEDIR_SIGNATURE.len()];
+        let tagged = std::fs::File::open(path.join("CACHEDIR.TAG"))
+            .and_then(|mut f| f.read_exact(&mut signature))
+            .is_ok();
+        if tagged && signature == CACHEDIR_SIGNATURE {
+            self.cache_dirs.lock().unwrap().add(0);
+            return true;
+        }
+        false
+    }
+
+    /// Checks the name the queued file at 'path' is stored under against B2's naming rules
+    /// Names that had to be escaped or shortened are logged and counted, the file is stored either way
+    pub fn check_name<P: AsRef<Path>>(&self, path: P) {
+        let naming = match &self.naming {
+            Some(n) => n,
+            None => return,
+        };
+        let path = path.as_ref();
+        let name = naming.name_for_path(path);
+        if escape::is_encoded(&name) {
+            println!("{:?} has characters B2 doesn't allow in names, storing it as {:?}", path, name);
+            *self.escaped.lock().unwrap() += 1;
+        }
+        if self.check_length && naming.is_too_long(&name) {
+            println!("Name of {:?} is too long for B2, storing it as {:?}", path, naming.object_name(&name));
+            *self.shortened.lock().unwrap() += 1;
+        }
+    }
+
+    /// Called before enumerating the directory at 'path', returns false if it is already being enumerated,
+    /// i.e. a followed link leads back to it. If it returns true, 'leave_dir' must be called when done
+    pub fn enter_dir<P: AsRef<Path>>(&self, path: P) -> bool {
+        if !self.follow_links {
+            return true;
+        }
+        let id = dir_id(path.as_ref());
+        let mut ancestors = self.ancestors.lock().unwrap();
+        if id.is_some() && ancestors.contains(&id) {
+            println!("Not following {:?}, it leads back into a parent directory", path.as_ref());
+            self.cycles.lock().unwrap().add(0);
+            return false;
+        }
+        ancestors.push(id);
+        true
+    }
+
+    pub fn leave_dir(&self) {
+        if self.follow_links {
+            self.ancestors.lock().unwrap().pop();
+        }
+    }
+
+    /// Describes what has been skipped or renamed, None if nothing was
+    pub fn summary(&self) -> Option<String> {
+        let mut parts = vec![];
+        let too_large = *self.too_large.lock().unwrap();
+        if too_large.files > 0 {
+            parts.push(format!("{} larger than {} ({})", too_large.files, manifest::format_size(self.max_size),
+                               manifest::format_size(too_large.bytes)));
+        }
+        let too_old = *self.too_old.lock().unwrap();
+        if too_old.files > 0 {
+            parts.push(format!("{} older than {} days ({})", too_old.files, self.max_age_days,
+                               manifest::format_size(too_old.bytes)));
+        }
+        let by_type = *self.by_type.lock().unwrap();
+        if by_type.files > 0 {
+            parts.push(format!("{} by type ({})", by_type.files, manifest::format_size(by_type.bytes)));
+        }
+        let cache_dirs = *self.cache_dirs.lock().unwrap();
+        if cache_dirs.files > 0 {
+            parts.push(format!("{} cache directories", cache_dirs.files));
+        }
+        let no_dump = *self.no_dump.lock().unwrap();
+        if no_dump.files > 0 {
+            parts.push(format!("{} marked nodump ({})", no_dump.files, manifest::format_size(no_dump.bytes)));
+        }
+        let other_devices = *self.other_devices.lock().unwrap();
+        if other_devices.files > 0 {
+            parts.push(format!("{} directories on other file systems", other_devices.files));
+        }
+        let cycles = *self.cycles.lock().unwrap();
+        if cycles.files > 0 {
+            parts.push(format!("{} links leading into a cycle", cycles.files));
+        }
+        let mut lines = vec![];
+        if !parts.is_empty() {
+            lines.push(format!("Skipped files: {}", parts.join(", ")));
+        }
+        let mut renamed = vec![];
+        let escaped = *self.escaped.lock().unwrap();
+        if escaped > 0 {
+            renamed.push(format!("{} with characters B2 doesn't allow", escaped));
+        }
+        let shortened = *self.shortened.lock().unwrap();
+        if shortened > 0 {
+            renamed.push(format!("{} with names too long for B2", shortened));
+        }
+        if !renamed.is_empty() {
+            lines.push(format!("Renamed files: {}", renamed.join(", ")));
+        }
+        match lines.is_empty() {
+            true => None,
+            false => Some(lines.join(". ")),
+        }
+    }
+}
+
+// Whether the file has the nodump attribute, read with the same ioctl as lsattr
+#[cfg(target_os = "linux")]
+fn has_nodump(path: &Path) -> bool {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    const FS_NODUMP_FL: libc::c_long = 0x40;
+
+    // Non-blocking, so opening e.g. a FIFO doesn't hang
+    let file = match std::fs::OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(path) {
+        Ok(f) => f,
+        Err(_e) => return false,
+    };
+    let mut flags: libc::c_long = 0;
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) };
+    result == 0 && flags & FS_NODUMP_FL != 0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn has_nodump(_path: &Path) -> bool {
+    false
+}
+
+// Whether the directory is on another device than the one it is in
+#[cfg(unix)]
+fn crosses_device(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let parent = match path.parent() {
+        Some(p) => p,
+        None => return false,
+    };
+    match (std::fs::metadata(path), std::fs::metadata(parent)) {
+        (Ok(m), Ok(p)) => m.dev() != p.dev(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn crosses_device(_path: &Path) -> bool {
+    false
+}
+
+// Identifies a directory, following links
+#[cfg(unix)]
+fn dir_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|m| (m.dev(), m.ino()))
+}
+
+// There are no inode numbers in the standard library on Windows, the resolved path is used instead
+#[cfg(not(unix))]
+fn dir_id(path: &Path) -> Option<(u64, u64)> {
+    use std::hash::{Hash, Hasher};
+    let resolved = std::fs::canonicalize(path).ok()?;
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    resolved.hash(&mut hasher);
+    Some((0, hasher.finish()))
+}
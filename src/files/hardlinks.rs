@@ -0,0 +1,145 @@
+// Hard links (Unix only)
+//
+// A file with several names (hard links) is uploaded once, under the name that sorts first
+// The other names are only recorded in the manifest, pointing to that one (see ManifestEntry::hardlink),
+// and restore links them to it again
+// Files are told apart by (device, inode), only files with more than one link are tracked
+//
+// Files are queued while the upload runs, so all names of a file are only known once everything is queued
+// Until then the upload threads set them aside ('defer'), afterwards the first name of each file is queued again ('release')
+// The entries for the other names are made at the end of the run ('entries'), so they only refer to names that were uploaded
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::files::manifest::ManifestEntry;
+use crate::files::posix::PosixMeta;
+
+/// Names of the multiply linked files seen during an upload run
+#[derive(Default)]
+pub struct HardLinks {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // Names of each file, as they were set aside
+    files: HashMap<(u64, u64), Vec<LinkName>>,
+    // Whether the first names were queued again
+    released: bool,
+}
+
+// A name of a multiply linked file, with what its manifest entry needs
+struct LinkName {
+    name: String,
+    path: PathBuf,
+    size: u64,
+    modified: u64,
+    posix: Option<PosixMeta>,
+}
+
+impl HardLinks {
+    /// Sets the file at 'path' aside if it has several names, returns whether it did
+    /// Once the first names are released, files are no longer set aside, as those are what's left to upload
+    pub fn defer(&self, path: &Path, name: &str, size: u64, modified: u64, posix: &Option<PosixMeta>) -> bool {
+        let id = match file_id(path) {
+            Some(id) => id,
+            None => return false,
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.released {
+            return false;
+        }
+        state.files.entry(id).or_default().push(LinkName {
+            name: name.to_string(),
+            path: path.to_path_buf(),
+            size,
+            modified,
+            posix: posix.clone(),
+        });
+        true
+    }
+
+    /// Returns the path of the first name of each file set aside, to be uploaded
+    /// Only the first call returns any, later files aren't set aside
+    pub fn release(&self) -> Vec<PathBuf> {
+        let mut state = self.state.lock().unwrap();
+        if state.released {
+            return vec![];
+        }
+        state.released = true;
+        state.files.values_mut()
+            .map(|names| {
+                names.sort_by(|a, b| a.name.cmp(&b.name));
+                names[0].path.clone()
+            })
+            .collect()
+    }
+
+    /// Entries for the other names of each file, pointing to the first
+    pub fn entries(&self) -> Vec<ManifestEntry> {
+        let mut state = self.state.lock().unwrap();
+        let mut entries = vec![];
+        for names in state.files.values_mut() {
+            names.sort_by(|a, b| a.name.cmp(&b.name));
+            let first = &names[0].name;
+            for n in names[1..].iter() {
+                entries.push(ManifestEntry::hard_link(&n.name, first, n.size, n.modified, n.posix.clone()));
+            }
+        }
+        entries
+    }
+}
+
+// (device, inode) of a file with more than one link
+#[cfg(unix)]
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let m = std::fs::metadata(path).ok()?;
+    match m.is_file() && m.nlink() > 1 {
+        true => Some((m.dev(), m.ino())),
+        false => None,
+    }
+}
+
+#[cfg(not(unix))]
+fn file_id(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_name_is_the_smallest_whatever_the_order() {
+        let dir = std::env::temp_dir().join(format!("backup_gui_hardlinks_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b, c) = (dir.join("a"), dir.join("b"), dir.join("c"));
+        std::fs::write(&a, b"data").unwrap();
+        std::fs::hard_link(&a, &b).unwrap();
+        std::fs::hard_link(&a, &c).unwrap();
+        let single = dir.join("single");
+        std::fs::write(&single, b"data").unwrap();
+
+        let links = HardLinks::default();
+        assert!(links.defer(&c, "c", 4, 0, &None));
+        assert!(links.defer(&a, "a", 4, 0, &None));
+        assert!(links.defer(&b, "b", 4, 0, &None));
+        assert!(!links.defer(&single, "single", 4, 0, &None));
+
+        assert_eq!(links.release(), vec![a.clone()]);
+        assert!(links.release().is_empty());
+        // Once released, the first name is uploaded like any other file
+        assert!(!links.defer(&a, "a", 4, 0, &None));
+
+        let mut entries: Vec<(String, String)> = links.entries().into_iter()
+            .map(|e| (e.name, e.hardlink.unwrap()))
+            .collect();
+        entries.sort();
+        assert_eq!(entries, vec![("b".to_string(), "a".to_string()), ("c".to_string(), "a".to_string())]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,80 @@
+// Journal of purges, used to undo them
+// Every file version a purge creates is logged as soon as it exists, one JSON object per line in JOURNAL_FILE
+// That is the hide marker of each hidden file, and the new pack index if packed files were removed
+// Deleting these versions makes the bucket look like it did before the purge
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+
+use nanoserde::{DeJson, SerJson};
+
+/// Local file holding the journal
+pub const JOURNAL_FILE: &str = "purge_journal.dat";
+
+/// A file version created by a purge
+#[derive(Debug, Clone, DeJson, SerJson)]
+pub struct JournalEntry {
+    // When the purge started, in milliseconds since the Unix epoch, identifies the purge
+    pub purge: u64,
+    // Name in the bucket, including the remote prefix
+    pub name: String,
+    // Version to delete to undo this entry
+    pub file_id: String,
+}
+
+/// Appends entries for a single purge
+pub struct Journal {
+    purge: u64,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    /// Opens the journal for a purge starting now
+    pub fn start() -> std::io::Result<Self> {
+        let purge = match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+            Ok(d) => d.as_millis() as u64,
+            Err(_e) => 0,
+        };
+        let file = OpenOptions::new().create(true).append(true).open(JOURNAL_FILE)?;
+        Ok(Journal { purge, file: Mutex::new(file) })
+    }
+
+    /// Logs a version created by the purge
+    pub fn record(&self, name: &str, file_id: &str) {
+        let entry = JournalEntry { purge: self.purge, name: name.to_string(), file_id: file_id.to_string() };
+        let line = format!("{}\n", entry.serialize_json());
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            println!("Failed to write to {} - {:?}", JOURNAL_FILE, e);
+        }
+    }
+}
+
+/// Reads all entries in the journal, oldest first
+/// Malformed lines are skipped
+pub fn read() -> Vec<JournalEntry> {
+    let text = match std::fs::read_to_string(JOURNAL_FILE) {
+        Ok(t) => t,
+        Err(_e) => return vec![],
+    };
+    text.lines()
+        .filter(|l| !l.trim().is_empty())
+        .filter_map(|l| match JournalEntry::deserialize_json(l) {
+            Ok(e) => Some(e),
+            Err(e) => {
+                println!("Malformed journal entry {:?} - {:?}", l, e);
+                None
+            },
+        })
+        .collect()
+}
+
+/// Replaces the journal with the given entries
+pub fn write(entries: &[JournalEntry]) -> std::io::Result<()> {
+    let mut text = String::new();
+    for e in entries {
+        text.push_str(&e.serialize_json());
+        text.push('\n');
+    }
+    std::fs::write(JOURNAL_FILE, text)
+}
@@ -0,0 +1,86 @@
+// Symbolic links, see GUIConfig::symlinks
+//
+// Skip: links are left out, both in the file tree and when collecting the files to upload
+// Store: the link itself is backed up and restored as a link
+//     In the plain format it is a small object holding the target, marked by its content type (CONTENT_TYPE)
+//     In the chunked format the target is only recorded in the manifest, see ManifestEntry::link
+// Follow: links are backed up as what they point to, linked directories are descended into
+//     A directory that is already being enumerated further up is not entered again, see Filters::enter_dir
+//
+// Targets are stored as read from the link, relative targets stay relative, names that aren't UTF-8 are encoded (see escape.rs)
+
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::files::escape;
+use crate::ui::SymlinkPolicy;
+
+/// Content type of objects holding a link
+pub const CONTENT_TYPE: &str = "application/x-b2gui-symlink";
+
+// The policy in effect, see 'set_policy'
+static POLICY: AtomicU8 = AtomicU8::new(0);
+
+/// Set from GUIConfig::symlinks, see files::apply_config
+pub fn set_policy(policy: SymlinkPolicy) {
+    let n = match policy {
+        SymlinkPolicy::Skip => 0,
+        SymlinkPolicy::Store => 1,
+        SymlinkPolicy::Follow => 2,
+    };
+    POLICY.store(n, Ordering::Relaxed);
+}
+
+pub fn policy() -> SymlinkPolicy {
+    match POLICY.load(Ordering::Relaxed) {
+        1 => SymlinkPolicy::Store,
+        2 => SymlinkPolicy::Follow,
+        _ => SymlinkPolicy::Skip,
+    }
+}
+
+/// Target of the link at 'path', if links are stored and it is one
+pub fn stored_target<P: AsRef<Path>>(path: P) -> Option<String> {
+    if policy() != SymlinkPolicy::Store {
+        return None;
+    }
+    let path = path.as_ref();
+    match std::fs::symlink_metadata(path) {
+        Ok(m) if m.file_type().is_symlink() => (),
+        _ => return None,
+    }
+    std::fs::read_link(path).ok().map(escape::encode_path)
+}
+
+/// Modification time of the link itself, in milliseconds since the Unix epoch
+pub fn modified<P: AsRef<Path>>(path: P) -> u64 {
+    std::fs::symlink_metadata(path).ok()
+        .and_then(|m| m.modified().ok())
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Creates a link at 'path' pointing to 'target', replacing any file already there
+pub fn create(target: &str, path: &Path) -> io::Result<()> {
+    if std::fs::symlink_metadata(path).is_ok() {
+        std::fs::remove_file(path)?;
+    }
+    create_link(&escape::decode_path(target), path)
+}
+
+#[cfg(unix)]
+fn create_link(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+// Windows has separate links for files and directories, relative targets are resolved from the link's directory
+#[cfg(windows)]
+fn create_link(target: &Path, path: &Path) -> io::Result<()> {
+    let resolved = path.parent().map_or(target.to_path_buf(), |p| p.join(target));
+    if resolved.is_dir() {
+        std::os::windows::fs::symlink_dir(target, path)
+    } else {
+        std::os::windows::fs::symlink_file(target, path)
+    }
+}
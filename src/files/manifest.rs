@@ -0,0 +1,331 @@
+// Manifests (snapshots) describe the contents of a backup at the end of an upload run
+//
+// Every completed upload run writes one, regardless of the storage format
+// They are stored as JSON in the bucket under MANIFEST_PREFIX, named after the time the run finished
+// A copy is kept locally in SNAPSHOT_DIR, so past runs can be inspected without downloading anything
+// The most recent manifest is the current state of the backup, older ones are kept (or hidden) like any other file
+
+use std::path::{Path, PathBuf};
+
+use nanoserde::{DeJson, SerJson};
+use raze::api::{B2Auth, B2FileInfo};
+use reqwest::blocking::Client;
+
+use crate::files::naming::Naming;
+use crate::files::objects;
+use crate::files::posix::PosixMeta;
+use crate::files::selection::Selection;
+use crate::files::sparse::Extent;
+
+/// Prefix of all files the program stores in the bucket besides the backed up files
+/// Purge never treats these as deleted local files
+pub const REPO_PREFIX: &str = ".backup_gui/";
+/// Prefix of manifests in the bucket
+pub const MANIFEST_PREFIX: &str = ".backup_gui/manifests/";
+
+/// Local directory holding a copy of every manifest written
+pub const SNAPSHOT_DIR: &str = "snapshots";
+
+/// Current version of the manifest format, bumped on incompatible changes
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, DeJson, SerJson)]
+pub struct Manifest {
+    pub version: u32,
+    // When the upload run finished, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+    // Computer the backup was made from
+    #[nserde(default)]
+    pub host: String,
+    // Selection rules (the lines of backuplist.dat) used for the run
+    #[nserde(default)]
+    pub rules: Vec<String>,
+    pub files: Vec<ManifestEntry>,
+}
+
+/// A single backed up file or directory
+/// 'name' is the name the file would have in B2 in the plain format, i.e. its path with '/' separators
+/// Where the content is stored depends on the format:
+///     Plain: the object 'file_id', 'sha1' is the SHA1 of the object (which is compressed if the file was)
+///     Packed: 'size' bytes at 'offset' in the pack with hash 'pack', see files::packed
+///     Chunked: 'chunks' are the hashes of its content, in order, 'sha1' is the SHA1 of the whole file, see files::chunked
+#[derive(Debug, Clone, DeJson, SerJson)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub size: u64,
+    pub modified: u64,
+    #[nserde(default_with = "Option::default")]
+    pub sha1: Option<String>,
+    #[nserde(default_with = "Option::default")]
+    pub file_id: Option<String>,
+    #[nserde(default_with = "Option::default")]
+    pub pack: Option<String>,
+    #[nserde(default)]
+    pub offset: u64,
+    #[nserde(default)]
+    pub chunks: Vec<String>,
+    // Target of a symbolic link that is stored as a link, see files::links
+    #[nserde(default_with = "Option::default")]
+    pub link: Option<String>,
+    // Permissions, ownership, times and extended attributes, see files::posix
+    #[nserde(default_with = "Option::default")]
+    pub posix: Option<PosixMeta>,
+    // Directories are recorded so restore can recreate them, even if empty, their names end in '/'
+    #[nserde(default)]
+    pub dir: bool,
+    // Another name of the same file, which holds its content, see files::hardlinks
+    #[nserde(default_with = "Option::default")]
+    pub hardlink: Option<String>,
+    // Data extents of a sparse file, only these are stored, see files::sparse
+    #[nserde(default_with = "Option::default")]
+    pub sparse: Option<Vec<Extent>>,
+}
+
+impl ManifestEntry {
+    /// Entry for a file stored as a plain object
+    pub fn plain(name: &str, size: u64, modified: u64, stored: &B2FileInfo) -> Self {
+        ManifestEntry {
+            name: name.to_string(),
+            size,
+            modified,
+            sha1: stored.content_sha1.clone(),
+            file_id: stored.file_id.clone(),
+            pack: None,
+            offset: 0,
+            chunks: vec![],
+            link: None,
+            posix: None,
+            dir: false,
+            hardlink: None,
+            sparse: None,
+        }
+    }
+
+    /// Entry for a directory, which only exists in the manifest
+    pub fn directory(name: &str, modified: u64, posix: Option<PosixMeta>) -> Self {
+        let name = match name.ends_with('/') {
+            true => name.to_string(),
+            false => format!("{}/", name),
+        };
+        ManifestEntry {
+            name,
+            size: 0,
+            modified,
+            sha1: None,
+            file_id: None,
+            pack: None,
+            offset: 0,
+            chunks: vec![],
+            link: None,
+            posix,
+            dir: true,
+            hardlink: None,
+            sparse: None,
+        }
+    }
+
+    /// Entry for another name of the file stored as 'first', see files::hardlinks
+    pub fn hard_link(name: &str, first: &str, size: u64, modified: u64, posix: Option<PosixMeta>) -> Self {
+        ManifestEntry {
+            name: name.to_string(),
+            size,
+            modified,
+            sha1: None,
+            file_id: None,
+            pack: None,
+            offset: 0,
+            chunks: vec![],
+            link: None,
+            posix,
+            dir: false,
+            hardlink: Some(first.to_string()),
+            sparse: None,
+        }
+    }
+
+    /// Marks the entry as a symbolic link to 'target'
+    pub fn with_link(mut self, target: &str) -> Self {
+        self.link = Some(target.to_string());
+        self
+    }
+
+    /// Sets the metadata of the file, see files::posix
+    pub fn with_posix(mut self, posix: Option<PosixMeta>) -> Self {
+        self.posix = posix;
+        self
+    }
+
+    /// Sets the data extents of a sparse file, see files::sparse
+    pub fn with_sparse(mut self, sparse: Option<Vec<Extent>>) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    /// Whether the file is stored as chunks, rather than as a plain object or in a pack
+    pub fn is_chunked(&self) -> bool {
+        self.file_id.is_none() && self.pack.is_none() && self.link.is_none() && !self.dir && self.hardlink.is_none()
+    }
+}
+
+impl Manifest {
+    /// Creates a manifest for this computer with the given files and rules, timestamped now
+    pub fn new(mut files: Vec<ManifestEntry>, rules: Vec<String>) -> Self {
+        let timestamp = match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+            Ok(d) => d.as_millis() as u64,
+            Err(_e) => 0,
+        };
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        Manifest {
+            version: VERSION,
+            timestamp,
+            host: crate::files::hostname(),
+            rules,
+            files,
+        }
+    }
+
+    /// Total size of all files in the manifest
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|f| f.size).sum()
+    }
+
+    /// Name of the manifest in the bucket
+    /// The timestamp is zero-padded, so sorting by name sorts by time
+    pub fn file_name(&self) -> String {
+        format!("{}{:016}.json", MANIFEST_PREFIX, self.timestamp)
+    }
+
+    /// Uploads the manifest to the bucket, under the remote prefix
+    pub fn upload(&self, client: &Client, auth: &B2Auth, bucket_id: &str, naming: &Naming) -> Result<(), String> {
+        objects::upload_json(client, auth, bucket_id, &naming.remote(&self.file_name()), self).map(|_| ())
+    }
+
+    /// Saves a copy of the manifest in SNAPSHOT_DIR
+    pub fn save_local(&self) -> std::io::Result<()> {
+        std::fs::create_dir_all(SNAPSHOT_DIR)?;
+        std::fs::write(local_path(self.timestamp), self.serialize_json())
+    }
+
+    /// Reads a manifest from a local file
+    pub fn from_file<T: AsRef<Path>>(path: T) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path.as_ref()).map_err(|e| format!("{:?}", e))?;
+        DeJson::deserialize_json(&json).map_err(|e| format!("Malformed manifest {:?} - {:?}", path.as_ref(), e))
+    }
+
+    /// Downloads and parses the manifest with the given file id
+    pub fn download(client: &Client, auth: &B2Auth, file_id: &str) -> Result<Self, String> {
+        let manifest: Manifest = objects::download_json(client, auth, file_id)?;
+        if manifest.version > VERSION {
+            return Err(format!("Manifest version {} is newer than supported ({})", manifest.version, VERSION));
+        }
+        Ok(manifest)
+    }
+}
+
+/// Returns the manifests in a list of remote files, oldest first
+pub fn manifests(remote: &[B2FileInfo]) -> Vec<&B2FileInfo> {
+    let mut manifests: Vec<&B2FileInfo> = remote.iter()
+        .filter(|f| f.file_name.starts_with(MANIFEST_PREFIX))
+        .collect();
+    manifests.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    manifests
+}
+
+/// Timestamp of a manifest in the bucket, parsed from its name
+pub fn timestamp_from_name(name: &str) -> Option<u64> {
+    name.strip_prefix(MANIFEST_PREFIX)?.strip_suffix(".json")?.parse().ok()
+}
+
+/// Picks a snapshot from a list of timestamps (oldest first) given by the user
+/// 'arg' is either the number of the snapshot as listed (starting at 1),
+/// or the start of its time as formatted by 'format_timestamp', e.g. '2020-06-30', in which case the latest match is used
+pub fn select(timestamps: &[u64], arg: &str) -> Option<usize> {
+    if let Ok(n) = arg.parse::<usize>() {
+        if n >= 1 && n <= timestamps.len() {
+            return Some(n - 1);
+        }
+    }
+    timestamps.iter().rposition(|t| format_timestamp(*t).starts_with(arg))
+}
+
+/// Downloads the most recent manifest in a list of remote files
+/// Returns None if there are no manifests yet
+pub fn latest(client: &Client, auth: &B2Auth, remote: &[B2FileInfo]) -> Result<Option<Manifest>, String> {
+    let file = match manifests(remote).last() {
+        Some(f) => *f,
+        None => return Ok(None),
+    };
+    let file_id = match &file.file_id {
+        Some(id) => id,
+        None => return Err(format!("Manifest {:?} has no file id", file.file_name)),
+    };
+    Manifest::download(client, auth, file_id).map(Some)
+}
+
+/// Path of the local copy of the manifest with the given timestamp
+pub fn local_path(timestamp: u64) -> PathBuf {
+    Path::new(SNAPSHOT_DIR).join(format!("{:016}.json", timestamp))
+}
+
+/// Lists the timestamps of the manifests in SNAPSHOT_DIR, oldest first
+/// Use 'local_path' and 'Manifest::from_file' to read them
+pub fn local_snapshot_list() -> Vec<u64> {
+    let read = match std::fs::read_dir(SNAPSHOT_DIR) {
+        Ok(r) => r,
+        Err(_e) => return vec![],
+    };
+    let mut timestamps: Vec<u64> = read
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_str()?.strip_suffix(".json")?.parse().ok())
+        .collect();
+    timestamps.sort_unstable();
+    timestamps
+}
+
+/// Reads the selection rules from a backuplist.dat file, returns no rules if it can't be read
+/// Comments are left out, see files::selection
+pub fn read_rules<T: AsRef<Path>>(path: T) -> Vec<String> {
+    match Selection::read(path) {
+        Ok(s) => s.rules,
+        Err(_e) => vec![],
+    }
+}
+
+/// Formats a timestamp (milliseconds since the Unix epoch) as 'YYYY-MM-DD HH:MM:SS UTC'
+pub fn format_timestamp(millis: u64) -> String {
+    let secs = millis / 1000;
+    let time = secs % 86400;
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, time / 3600, (time / 60) % 60, time % 60)
+}
+
+/// Converts days since the Unix epoch to a (year, month, day) date in the proleptic Gregorian calendar
+/// Months and days start at 1
+/// See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Formats a number of bytes with a binary unit, e.g. '1.5 MiB'
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
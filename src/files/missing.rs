@@ -0,0 +1,58 @@
+// Record of when files were first seen missing locally
+// Purge only hides files that have been missing for a while, so files that are moved back or
+// reappear after a brief mount failure don't get hidden. Stored as JSON in MISSING_FILE
+
+use std::collections::HashMap;
+
+use nanoserde::{DeJson, SerJson};
+
+/// Local file holding the record
+pub const MISSING_FILE: &str = "missing.dat";
+
+#[derive(Debug, Default, DeJson, SerJson)]
+pub struct MissingFiles {
+    // Name of each missing file (as in B2) and when it was first seen missing, in milliseconds since the Unix epoch
+    pub since: HashMap<String, u64>,
+}
+
+impl MissingFiles {
+    /// Reads the record, or starts an empty one if it doesn't exist or can't be parsed
+    pub fn load() -> Self {
+        let json = match std::fs::read_to_string(MISSING_FILE) {
+            Ok(s) => s,
+            Err(_e) => return Self::default(),
+        };
+        match DeJson::deserialize_json(&json) {
+            Ok(m) => m,
+            Err(e) => {
+                println!("Malformed {} - {:?}", MISSING_FILE, e);
+                Self::default()
+            },
+        }
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        std::fs::write(MISSING_FILE, self.serialize_json())
+    }
+
+    /// Updates the record with the files that are currently missing
+    /// Files seen for the first time are recorded as missing since 'now',
+    /// files that are no longer missing (i.e. are back, or were purged) are forgotten
+    pub fn update(&mut self, missing: &[String], now: u64) {
+        let mut since = HashMap::with_capacity(missing.len());
+        for name in missing {
+            let first = self.since.get(name).copied().unwrap_or(now);
+            since.insert(name.clone(), first);
+        }
+        self.since = since;
+    }
+
+    /// Whether the file has been missing for at least 'grace' milliseconds
+    /// Files not in the record haven't been seen missing yet
+    pub fn expired(&self, name: &str, grace: u64, now: u64) -> bool {
+        match self.since.get(name) {
+            Some(t) => now.saturating_sub(*t) >= grace,
+            None => grace == 0,
+        }
+    }
+}
@@ -9,20 +9,42 @@ use std::path::{Path, PathBuf};
 use std::fs;
 #[cfg(windows)]
 use std::ffi::OsString;
-use std::fs::File;
-use std::io::{Write, BufRead};
 use std::sync::{Arc, Mutex};
 use std::cmp::Ordering;
 use std::sync::atomic::AtomicBool;
 
+use crate::ui::SymlinkPolicy;
+use filters::Filters;
+use patterns::Patterns;
+use selection::Selection;
+
 pub mod tracked_reader;
+pub mod compression;
+pub mod escape;
+pub mod chunker;
+pub mod chunked;
+pub mod manifest;
+pub mod journal;
+pub mod links;
+pub mod missing;
+pub mod mounts;
+pub mod filters;
+pub mod hardlinks;
+pub mod naming;
+pub mod patterns;
+pub mod diff;
+pub mod objects;
+pub mod packed;
+pub mod posix;
+pub mod selection;
+pub mod sparse;
 
 // On Linux, we have the single root '/' instead of drives
 // On Windows, there can be any number of drives, so we need to fetch them all
 // This code uses winapi to get all the drives
 // It also renames them to i.e. C:/ instead of C:\ for consistency
 #[cfg(windows)]
-pub fn get_roots() -> Result<DirEntry,&'static str> {
+pub fn get_roots(_gitignore: bool) -> Result<DirEntry,&'static str> {
     use winapi::um::fileapi::GetLogicalDriveStringsW;
     use std::os::windows::ffi::OsStringExt;
 
@@ -47,11 +69,12 @@ pub fn get_roots() -> Result<DirEntry,&'static str> {
         let root_element = DirEntry {
             kind: EntryKind::Directory,
             name: "".to_string(),
-            path: "".to_string(),
+            path: PathBuf::new(),
             action: Arc::new(Mutex::new(Action::Exclude)),
             children: Arc::new(Mutex::new(vec![])),
             indexed: Arc::new(AtomicBool::new(true)),
-            expanded: Arc::new(AtomicBool::new(true))
+            expanded: Arc::new(AtomicBool::new(true)),
+            ignored: false,
         };
         // Add found rives to the root element
         for x in drive_string.split('\0').filter(|x| !x.is_empty()) {
@@ -60,11 +83,12 @@ pub fn get_roots() -> Result<DirEntry,&'static str> {
                 DirEntry {
                     kind: EntryKind::Directory,
                     name: name.to_owned(),
-                    path: name.to_owned(),
+                    path: PathBuf::from(&name),
                     action: Arc::new(Mutex::new(Action::Exclude)),
                     children: Arc::new(Mutex::new(vec![])),
                     indexed: Arc::new(AtomicBool::new(false)),
                     expanded: Arc::new(AtomicBool::new(false)),
+                    ignored: false,
                 }
             );
         }
@@ -79,28 +103,87 @@ pub fn get_roots() -> Result<DirEntry,&'static str> {
     }
 }
 
-// On Linux the root element is just '/'
-// As there is only a single root, we do not need a dummy element
+// On Linux every real file system is a root of its own, listed with its type and free space, see mounts.rs
+// Like on Windows, a dummy root element holds them
+// Elsewhere (or if the mount table can't be read) the root element is just '/'
+// As there is only a single root then, we do not need a dummy element
 // Note that we have to strip the root '/' when uploading files
 // This is because B2 will not emulate folders otherwise
 // TODO What about macOS?
 #[cfg(not(windows))]
-pub fn get_roots() -> Result<DirEntry,&'static str> {
+pub fn get_roots(gitignore: bool) -> Result<DirEntry,&'static str> {
+    let file_systems: Vec<mounts::Mount> = mounts::list().into_iter().filter(|m| !m.is_virtual()).collect();
+    if !file_systems.is_empty() {
+        let root_element = DirEntry {
+            kind: EntryKind::Directory,
+            name: "".to_string(),
+            path: PathBuf::new(),
+            action: Arc::new(Mutex::new(Action::Exclude)),
+            children: Arc::new(Mutex::new(vec![])),
+            indexed: Arc::new(AtomicBool::new(true)),
+            expanded: Arc::new(AtomicBool::new(true)),
+            ignored: false,
+        };
+        for m in file_systems {
+            let path = match m.point.as_str() {
+                "/" => "/".to_string(),
+                p => format!("{}/", p),
+            };
+            root_element.children.lock().unwrap().push(
+                DirEntry {
+                    kind: EntryKind::Directory,
+                    name: m.label(),
+                    path: PathBuf::from(path),
+                    action: Arc::new(Mutex::new(Action::Exclude)),
+                    children: Arc::new(Mutex::new(vec![])),
+                    indexed: Arc::new(AtomicBool::new(false)),
+                    expanded: Arc::new(AtomicBool::new(false)),
+                    ignored: false,
+                }
+            );
+        }
+        return Ok(root_element);
+    }
+
     // Dummy element for parity with windows, see get_roots for windows for details
     let root_element = DirEntry {
         kind: EntryKind::Directory,
         name: "/".to_owned(),
-        path: "/".to_owned(),
+        path: PathBuf::from("/"),
         action: Arc::new(Mutex::new(Action::Exclude)),
         children: Arc::new(Mutex::new(vec![])),
         indexed: Arc::new(AtomicBool::new(false)),
         expanded: Arc::new(AtomicBool::new(false)),
+        ignored: false,
     };
-    root_element.expand();
+    root_element.expand(gitignore);
 
     Ok(root_element)
 }
 
+// Applies the settings that enumerating files depends on, see links.rs
+// Called whenever the config is loaded or saved, as the file tree has no access to it
+pub fn apply_config(cfg: &crate::ui::GUIConfig) {
+    links::set_policy(cfg.symlinks);
+}
+
+// Name of this computer, used to tell backups from different computers apart
+// Windows sets COMPUTERNAME, on Linux the kernel exposes it in /proc
+// Falls back to the 'hostname' command (e.g. on macOS), and 'unknown' if all else fails
+pub fn hostname() -> String {
+    let name = if cfg!(windows) {
+        std::env::var("COMPUTERNAME").ok()
+    } else {
+        std::fs::read_to_string("/proc/sys/kernel/hostname").ok()
+            .or_else(|| std::process::Command::new("hostname").output().ok()
+                .map(|o| String::from_utf8_lossy(&o.stdout).into_owned()))
+    };
+    match name.map(|n| n.trim().to_string()) {
+        Some(n) if !n.is_empty() => n,
+        _ => "unknown".to_string(),
+    }
+}
+
 /// Type of a 'DirEntry'
 /// Each entry in the filesystem either a directory or a file
 #[derive(Debug,Clone,PartialEq)]
@@ -140,13 +223,16 @@ pub enum Action {
 pub struct DirEntry {
     pub kind: EntryKind,
     pub name: String, // Readable Display Name
-    pub path: String, // Full path to this entry
+    pub path: PathBuf, // Full path to this entry, kept as is, as names need not be UTF-8 (see escape.rs)
     pub action: Arc<Mutex<Action>>,
     pub children: Arc<Mutex<Vec<DirEntry>>>,
     // Whether or not the entry has had it's children vector populated yet
     pub indexed: Arc<AtomicBool>,
     // Whether or not to show children in the tree
     pub expanded: Arc<AtomicBool>,
+    // Whether an ignore file (.backupignore) above this entry excludes it, see patterns.rs
+    // Only used for display, the files to upload are checked against the ignore files as they are collected
+    pub ignored: bool,
 }
 
 
@@ -188,9 +274,12 @@ impl DirEntry {
     /// Only populates once, use 'refresh_children' to force repopulate
     ///
     /// Silently ignores most errors, as they're almost all permission-related
-    /// Symlinks are IGNORED to prevent cycles
+    /// Symlinks are handled according to the policy, see links.rs: skipped, shown as files or shown as what they point to
+    /// Children excluded by an ignore file in this directory or above are marked 'ignored', .gitignore files count if 'gitignore' is set
+    /// Mount points of virtual file systems (e.g. /proc) are marked 'ignored', real ones are listed like any directory
+    /// even though they are roots of their own as well (see get_roots)
     /// Sorts elements, see Ord impl for DirEntry
-    pub fn expand(&self) {
+    pub fn expand(&self, gitignore: bool) {
         // Only index once, see 'refresh_children'
         if self.indexed.load(std::sync::atomic::Ordering::Relaxed) {
             return;
@@ -200,30 +289,33 @@ impl DirEntry {
         if read.is_err() {
             eprintln!("{:?}", read.err().unwrap());
         } else {
+            let ignore = Patterns::inherited(&self.path, gitignore);
+            let mounts = mounts::list();
             for entry in read.ok().unwrap() {
                 if entry.is_err() {
                     println!("IO Error: {:?}", entry.err().unwrap());
                     continue;
                 }
                 let entry = entry.unwrap();
-                // Ignore symlinks to prevent cycles
-                if entry.file_type().unwrap().is_symlink() {
+                let is_link = matches!(entry.file_type(), Ok(t) if t.is_symlink());
+                if is_link && links::policy() == SymlinkPolicy::Skip {
                     continue;
                 }
 
                 // Check if it is a directory
                 // If it is, add a '/' to the end of the display name
-                let is_dir = entry.path().is_dir();
-                let entry_name;
-                let path;
-                if is_dir {
-                    entry_name = format!("{}/",entry.file_name().to_string_lossy());
-                    path = format!("{}{}",self.path,entry_name).replace("//","/");
+                // Stored links are files, whatever they point to
+                let is_dir = !(is_link && links::policy() == SymlinkPolicy::Store) && entry.path().is_dir();
+                let file_name = entry.file_name();
+                let entry_name = if is_dir {
+                    format!("{}/",file_name.to_string_lossy())
                 } else {
-                    entry_name = format!("{}",entry.file_name().to_string_lossy());
-                    path = format!("{}/{}",self.path,entry_name).replace("//","/");
-                }
+                    format!("{}",file_name.to_string_lossy())
+                };
+                let path = self.path.join(&file_name);
 
+                let virtual_mount = is_dir && mounts.iter().any(|m| m.is_virtual() && m.point != "/" && Path::new(&m.point) == path);
+                let ignored = self.ignored || virtual_mount || ignore.is_excluded(&path, is_dir);
                 self.children.lock().unwrap().push(
                     DirEntry {
                         kind: EntryKind::from_bool(is_dir),
@@ -233,6 +325,7 @@ impl DirEntry {
                         children: Arc::new(Mutex::new(vec![])),
                         indexed: Arc::new(AtomicBool::new(false)),
                         expanded: Arc::new(AtomicBool::from(false)),
+                        ignored,
                     }
                 )
             }
@@ -256,18 +349,23 @@ impl DirEntry {
     /// Recursive part of serialize
     /// 'mark' is true if we think the current dir should be uploaded based on the parents
     /// It is flipped to 'false' and the node written if we encounter an 'exclude' while it is true
-    pub fn serialize_rec(&self, file: &mut File, mark: bool) {
+    pub fn serialize_rec(&self, rules: &mut Vec<String>, mark: bool) {
         let mut mark = mark;
+        // Directories end in '/', names that aren't UTF-8 are encoded, see escape.rs
+        let path = match self.kind {
+            EntryKind::Directory => format!("{}/", escape::encode_path(&self.path).trim_end_matches('/')),
+            EntryKind::File => escape::encode_path(&self.path),
+        };
         if *self.action.lock().unwrap() == Action::Upload && !mark {
-            file.write_all(format!("UPLOAD {}\n",self.path).as_bytes()).unwrap();
+            rules.push(format!("{}{}", selection::UPLOAD, path));
             mark = true;
         } else if *self.action.lock().unwrap() == Action::Exclude && mark {
-            file.write_all(format!("EXCLUDE {}\n",self.path).as_bytes()).unwrap();
+            rules.push(format!("{}{}", selection::EXCLUDE, path));
             mark = false;
         }
 
         for child in self.children.lock().unwrap().iter() {
-            child.serialize_rec(file, mark);
+            child.serialize_rec(rules, mark);
         }
     }
 
@@ -280,7 +378,7 @@ impl DirEntry {
     /// expand '/' and search for 'some'
     /// If 'some' is found, it is expanded and it'll search for 'path'
     /// etc.
-    pub fn expand_for_path(&self, path: &str, action: Action) {
+    pub fn expand_for_path(&self, path: &str, action: Action, gitignore: bool) {
         let x = path.find('/');
         let name;
         let remainder;
@@ -296,8 +394,12 @@ impl DirEntry {
         };
 
         for child in self.children.lock().unwrap().iter() {
-            // Find name without trailing '/'
-            let child_name = child.name.replace("/","");
+            // Names in the path are encoded, see escape.rs
+            // Drives on Windows have no file name, their display name is used without the trailing '/'
+            let child_name = match child.path.file_name() {
+                Some(n) => escape::encode_name(n),
+                None => child.name.replace("/",""),
+            };
 
             if child_name == name {
                 // Nothing left: we have a file
@@ -306,8 +408,8 @@ impl DirEntry {
                 if remainder.is_empty() || remainder == "/" {
                     child.change_action(action);
                 } else {
-                    child.expand();
-                    child.expand_for_path(remainder, action);
+                    child.expand(gitignore);
+                    child.expand_for_path(remainder, action, gitignore);
                     return;
                 }
             }
@@ -315,22 +417,62 @@ impl DirEntry {
     }
 
 
+    /// Like 'expand_for_path', but for the dummy root element holding the file systems on Linux, see get_roots
+    /// The path belongs to the deepest mount point it is under
+    fn expand_for_root(&self, path: &str, action: Action, gitignore: bool) {
+        let children = self.children.lock().unwrap();
+        let root = children.iter()
+            .map(|r| (r, escape::encode_path(&r.path)))
+            .filter(|(_, p)| path.starts_with(p.as_str()) || format!("{}/", path) == *p)
+            .max_by_key(|(_, p)| p.len());
+        match root {
+            Some((r, root_path)) => {
+                let remainder = path.get(root_path.len()..).unwrap_or("");
+                if remainder.is_empty() {
+                    r.change_action(action);
+                } else {
+                    r.expand(gitignore);
+                    r.expand_for_path(remainder, action, gitignore);
+                }
+            },
+            None => println!("No file system found for {}", path),
+        }
+    }
+
     /// Intended to be run on the root element
     /// Runs through the file-tree, appending all FILES marked 'Upload' to a queue
-    pub fn get_files_for_upload(&self, queue: &Arc<Mutex<Vec<PathBuf>>>) {
+    /// Directories marked 'Upload' are queued as well, so they are recorded even if empty
+    /// Files and directories excluded by a pattern rule are skipped, see patterns.rs, as are files skipped by the filters
+    /// Returns the directories that couldn't be read, their files are missing from the queue
+    pub fn get_files_for_upload(&self, queue: &Arc<Mutex<Vec<PathBuf>>>, patterns: &Patterns, filters: &Filters) -> Vec<PathBuf> {
         println!("Building upload file list...");
         use std::time::SystemTime;
         let t = SystemTime::now();
+        let mut unreadable = vec![];
+        let local = patterns.for_dir(&self.path, filters.respect_gitignore());
+        let patterns = local.as_ref().unwrap_or(patterns);
         for child in self.children.lock().unwrap().iter() {
-            child.get_files(queue);
+            if !patterns.is_excluded(&child.path, true) {
+                child.get_files(queue, patterns, filters, &mut unreadable);
+            }
         }
         println!("Finished building list in {:?}", t.elapsed().unwrap());
+        unreadable
     }
 
     /// Recursive part of 'get_files_for_upload'
     /// 'self' is always a directory
-    fn get_files(&self, queue: &Arc<Mutex<Vec<PathBuf>>>) {
+    fn get_files(&self, queue: &Arc<Mutex<Vec<PathBuf>>>, patterns: &Patterns, filters: &Filters, unreadable: &mut Vec<PathBuf>) {
         let mut buffer = vec![]; // Buffer files to add to minimize locking
+        // Rules of the ignore files in this directory apply to everything below it
+        let local = patterns.for_dir(&self.path, filters.respect_gitignore());
+        let patterns = local.as_ref().unwrap_or(patterns);
+        if !filters.enter_dir(&self.path) {
+            return;
+        }
+        if *self.action.lock().unwrap() == Action::Upload {
+            buffer.push(self.path.clone());
+        }
 
         // There are 3 cases for each child:
         // 1. File marked upload - add to upload queue
@@ -339,19 +481,29 @@ impl DirEntry {
         // Note that if a directory is not indexed but the is marked upload, there can be no 'exclude' files in it
         // This is because an 'exclude' file is always indexed automatically on startup or when changed to 'exclude'
         for entry in self.children.lock().unwrap().iter() {
+            if patterns.is_excluded(&entry.path, entry.kind == EntryKind::Directory) {
+                continue;
+            }
+            if entry.kind == EntryKind::Directory && filters.skip_dir(&entry.path) {
+                continue;
+            }
             if entry.kind == EntryKind::File && *entry.action.lock().unwrap() == Action::Upload {
-                buffer.push(PathBuf::from(entry.path.clone()));
+                if !filters.skip(&entry.path) {
+                    filters.check_name(&entry.path);
+                    buffer.push(entry.path.clone());
+                }
             } else if entry.kind == EntryKind::Directory {
                 if entry.indexed.load(std::sync::atomic::Ordering::Relaxed) {
-                    entry.get_files(queue);
+                    entry.get_files(queue, patterns, filters, unreadable);
                 } else if *entry.action.lock().unwrap() == Action::Upload {
-                    get_files_all(entry.path.clone(), queue);
+                    get_files_all(entry.path.clone(), queue, patterns, filters, unreadable);
                 }
             }
         }
         {
             queue.lock().unwrap().append(&mut buffer);
         }
+        filters.leave_dir();
     }
 
     /// Write the current config to a file
@@ -372,47 +524,34 @@ impl DirEntry {
     /// INHERIT      dir5/
     /// Here, dir3, dir4 and dir5 will be uploaded
     /// Note dir3+dir4 work despite the parent being 'exclude'
-    pub fn serialize<T: AsRef<Path>>(&self, file: T) {
+    ///
+    /// Pattern rules and comments are edited separately, the ones already in the file are kept
+    /// See files::selection for the format
+    pub fn serialize<T: AsRef<Path>>(&self, file: T) -> Result<(), String> {
         let path = file.as_ref();
-        let mut file = std::fs::File::create(path).unwrap();
-
+        let mut selection = Selection::load(path)?;
+        let mut rules = vec![];
         for child in self.children.lock().unwrap().iter() {
-            child.serialize_rec(&mut file, false);
+            child.serialize_rec(&mut rules, false);
         }
+        rules.extend(selection.rules.into_iter().filter(|r| patterns::is_rule(r)));
+        selection.rules = rules;
+        selection.write(path)
     }
 
     /// Load the list of files to backup from a file
     /// Counterpart to serialize
-    pub fn deserialize<T: AsRef<Path>>(&self, file: T) -> Result<(),std::io::Error> {
-        let path = file.as_ref();
-        let file = std::fs::File::open(path)?;
-        let reader = std::io::BufReader::new(file);
-        for line in reader.lines() {
-            if line.is_err() {
-                println!("Malformed entry - {}", line.err().unwrap());
-                continue;
-            }
-            let line = line.unwrap();
-            // Note: on Unix, we add +1 to the offset to remove the leading '/' (the root)
-            // This is because the root element is already factored in
-            if line.starts_with("UPLOAD ") {
-                if cfg!(windows) {
-                    // offset 7 for "UPLOAD " (note the space)
-                    self.expand_for_path(&line[7..], Action::Upload);
-                } else {
-                    // offset 8 for "UPLOAD /" (note the space)
-                    self.expand_for_path(&line[8..], Action::Upload);
-                }
-            } else if line.starts_with("EXCLUDE ") {
-                if cfg!(windows) {
-                    // offset 8 for "EXCLUDE " (note the space)
-                    self.expand_for_path(&line[8..], Action::Exclude);
-                } else {
-                    // offset 9 for "EXCLUDE /"
-                    self.expand_for_path(&line[9..], Action::Exclude);
-                }
+    pub fn deserialize<T: AsRef<Path>>(&self, file: T, gitignore: bool) -> Result<(), String> {
+        let selection = Selection::read(file)?;
+        for (action, path) in selection.paths() {
+            if cfg!(windows) {
+                self.expand_for_path(path, action, gitignore);
+            } else if self.path.as_os_str().is_empty() {
+                // The dummy root holding the file systems, see get_roots
+                self.expand_for_root(path, action, gitignore);
             } else {
-                println!("Malformed entry - {}", line);
+                // The root element is '/' itself, so the path is relative to it
+                self.expand_for_path(path.strip_prefix('/').unwrap_or(path), action, gitignore);
             }
         }
         Ok(())
@@ -421,40 +560,57 @@ impl DirEntry {
 
 /// Alternate recursive part of 'get_files_for_upload'
 /// Used on non-indexed directories marked as upload
-/// This effectively means all files in all subdirectories should be added to the queue
-fn get_files_all<T: AsRef<Path>>(path: T, queue: &Arc<Mutex<Vec<PathBuf>>>) {
+/// This effectively means all files in all subdirectories should be added to the queue, along with the subdirectories
+/// Directories that can't be read are added to 'unreadable'
+fn get_files_all<T: AsRef<Path>>(path: T, queue: &Arc<Mutex<Vec<PathBuf>>>, patterns: &Patterns, filters: &Filters,
+                                 unreadable: &mut Vec<PathBuf>) {
     let path = path.as_ref();
     // Attempt to read the current entry
     // This may fail due to any number reasons, typically missing permissions
     let read = fs::read_dir(path);
     if read.is_err() {
         eprintln!("{:?}, {:?}", read.err().unwrap(), path);
+        unreadable.push(path.to_owned());
     } else {
         let mut buffer = vec![]; // Buffer files to add to minimize locking
+        let local = patterns.for_dir(path, filters.respect_gitignore());
+        let patterns = local.as_ref().unwrap_or(patterns);
+        if !filters.enter_dir(path) {
+            return;
+        }
+        buffer.push(path.to_owned());
         for entry in read.ok().unwrap() {
             // Skip bad entries, typically caused by permissions
             if entry.is_err() {
                 println!("IO Error: {:?}", entry.err().unwrap());
                 continue;
             }
-            // Get the entry and discard symlinks
-            // If we don't do this, we could have cyclic directories
+            // Get the entry and handle symlinks according to the policy, see links.rs
+            // Followed links can lead into cycles, which 'enter_dir' stops
             let entry = entry.unwrap();
-            if entry.file_type().unwrap().is_symlink() {
+            let is_link = matches!(entry.file_type(), Ok(t) if t.is_symlink());
+            if is_link && links::policy() == SymlinkPolicy::Skip {
                 continue;
             }
             // For files: add upload queue if we need to
             // For directories: determine if we should check recursively
-            let is_dir = entry.path().is_dir();
+            let is_dir = !(is_link && links::policy() == SymlinkPolicy::Store) && entry.path().is_dir();
+            if patterns.is_excluded(entry.path(), is_dir) || (is_dir && filters.skip_dir(entry.path())) {
+                continue;
+            }
             if !is_dir {
-                buffer.push(entry.path().to_owned());
+                if !filters.skip(entry.path()) {
+                    filters.check_name(entry.path());
+                    buffer.push(entry.path().to_owned());
+                }
             } else {
-                get_files_all(entry.path(), queue);
+                get_files_all(entry.path(), queue, patterns, filters, unreadable);
             }
         }
         // Append collected files to queue
         {
             queue.lock().unwrap().append(&mut buffer);
         }
+        filters.leave_dir();
     }
 }
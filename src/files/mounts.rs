@@ -0,0 +1,112 @@
+// Mounted file systems (Linux only)
+//
+// Read from /proc/self/mountinfo, so the file tree can show every real file system as its own root, like drives on Windows
+// Virtual file systems (/proc, /sys, tmpfs etc.) are never backed up. Real ones are still part of the directory they're
+// mounted in, unless GUIConfig::one_file_system is set, see files::get_roots and Filters::skip_dir
+// Overlays aren't virtual, containers and live systems have one as their root file system
+// On other platforms no mounts are listed
+
+use crate::files::manifest;
+
+/// File system types that don't hold user data
+const VIRTUAL_TYPES: &[&str] = &[
+    "proc", "sysfs", "devtmpfs", "devpts", "tmpfs", "ramfs", "cgroup", "cgroup2", "securityfs", "pstore", "debugfs",
+    "tracefs", "configfs", "fusectl", "mqueue", "hugetlbfs", "bpf", "autofs", "binfmt_misc", "efivarfs", "rpc_pipefs",
+    "nsfs", "selinuxfs", "squashfs", "fuse.gvfsd-fuse", "fuse.portal",
+];
+
+/// A mounted file system
+#[derive(Debug, Clone)]
+pub struct Mount {
+    // Without a trailing '/', except for the root
+    pub point: String,
+    pub fs_type: String,
+}
+
+impl Mount {
+    pub fn is_virtual(&self) -> bool {
+        VIRTUAL_TYPES.contains(&self.fs_type.as_str())
+    }
+
+    /// Shown in the file tree, e.g. '/home/ (ext4, 120.5 GB free)'
+    pub fn label(&self) -> String {
+        let dir = match self.point.as_str() {
+            "/" => "/".to_string(),
+            p => format!("{}/", p),
+        };
+        match free_space(&self.point) {
+            Some(free) => format!("{} ({}, {} free)", dir, self.fs_type, manifest::format_size(free)),
+            None => format!("{} ({})", dir, self.fs_type),
+        }
+    }
+}
+
+/// The mounted file systems, sorted by mount point
+/// A mount point that is mounted over only appears once, with the file system that is visible
+#[cfg(target_os = "linux")]
+pub fn list() -> Vec<Mount> {
+    let info = match std::fs::read_to_string("/proc/self/mountinfo") {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Failed to read the mount table - {:?}", e);
+            return vec![];
+        },
+    };
+    let mut mounts: Vec<Mount> = vec![];
+    for line in info.lines() {
+        // Fields: id, parent id, major:minor, root, mount point, options, optional fields..., '-', type, source, options
+        let fields: Vec<&str> = line.split(' ').collect();
+        let separator = match fields.iter().position(|f| *f == "-") {
+            Some(n) => n,
+            None => continue,
+        };
+        let (point, fs_type) = match (fields.get(4), fields.get(separator + 1)) {
+            (Some(p), Some(t)) => (unescape(p), t.to_string()),
+            _ => continue,
+        };
+        // Later lines are mounted over earlier ones
+        mounts.retain(|m| m.point != point);
+        mounts.push(Mount { point, fs_type });
+    }
+    mounts.sort_by(|a, b| a.point.cmp(&b.point));
+    mounts
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn list() -> Vec<Mount> {
+    vec![]
+}
+
+// Spaces, tabs, newlines and backslashes in mount points are written as octal escapes, e.g. '\040'
+#[cfg(target_os = "linux")]
+fn unescape(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i + 1] <= b'3' && bytes[i + 1..i + 4].iter().all(|b| (b'0'..=b'7').contains(b)) {
+            out.push((bytes[i + 1] - b'0') * 64 + (bytes[i + 2] - b'0') * 8 + (bytes[i + 3] - b'0'));
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// Space available to unprivileged users, in bytes
+#[cfg(target_os = "linux")]
+fn free_space(point: &str) -> Option<u64> {
+    let path = std::ffi::CString::new(point).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    match unsafe { libc::statvfs(path.as_ptr(), &mut stat) } {
+        0 => Some(stat.f_bavail as u64 * stat.f_frsize as u64),
+        _ => None,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn free_space(_point: &str) -> Option<u64> {
+    None
+}
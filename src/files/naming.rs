@@ -0,0 +1,380 @@
+// Names of files in the bucket
+//
+// Local files are stored under their absolute path, with '\' turned into '/' and, on Unix, without the leading '/'
+// B2 will not emulate folders for names starting with a slash, so /home/user/a.txt is stored as home/user/a.txt
+//
+// Every installation stores its files under its own remote prefix (GUIConfig::remote_prefix, see 'default_prefix'),
+// so several machines can back up into one bucket, e.g. laptop/home/user/a.txt
+// Configs from before prefixes existed have none, and keep storing files at the top of the bucket until it is set
+// The program's own objects (manifests, chunks, packs) are under the prefix as well, e.g. laptop/.backup_gui/pack_index.json
+//
+// Path mappings (GUIConfig::path_mappings) store a local directory under another name, e.g. /mnt/data2/photos as photos/
+// That keeps the names in the bucket stable when data moves to another disk, so nothing is uploaded again
+// Only the mapping has to be updated, see 'Naming::name_for_path' and its reverse 'Naming::path_for_name'
+// Mappings whose remote names overlap, or whose remote name is that of an unmapped local path (e.g. photos/ while /photos
+// exists), would store two directories under one name. They are ignored, and reported when the config is loaded
+//
+// The rest of the program works with names relative to the prefix: listings of the bucket are stripped with
+// 'Naming::strip_all', and 'Naming::remote' adds the prefix back whenever a file is uploaded, hidden or deleted by name
+// With an empty prefix, that leaves out the files of other computers that do have one, see 'Naming::other_prefixes'
+//
+// B2 names are at most MAX_NAME_BYTES long, characters it doesn't allow are escaped along with names that aren't UTF-8
+// (see escape.rs). Plain objects of files with longer names (think node_modules) are stored under a shortened name,
+// see 'Naming::object_name'. Chunked and packed files are only named in the manifest and pack index, which have no limit
+
+use std::path::{Path, PathBuf};
+
+use nanoserde::{DeJson, SerJson};
+use raze::api::B2FileInfo;
+
+use crate::files::{escape, manifest, selection, Action};
+use crate::ui::GUIConfig;
+
+/// Longest name B2 accepts, in bytes of UTF-8, including the remote prefix
+pub const MAX_NAME_BYTES: usize = 1024;
+// Length of the end of a shortened name: '~' and the SHA1 of the full name, see 'Naming::object_name'
+const SHORTENED_SUFFIX_LEN: usize = 41;
+
+/// Stores the local directory 'local' under 'remote' (relative to the remote prefix) instead of its own path
+#[derive(Debug, Clone, DeJson, SerJson)]
+pub struct PathMapping {
+    pub local: String,
+    pub remote: String,
+}
+
+/// Maps local paths to names relative to the remote prefix, and those to names in the bucket, and back
+#[derive(Debug, Clone, Default)]
+pub struct Naming {
+    // Empty, or the prefix including the trailing '/'
+    prefix: String,
+    // Path mappings as (local name, remote name), both including the trailing '/', longest local name first
+    mappings: Vec<(String, String)>,
+}
+
+impl Naming {
+    /// Leading and trailing slashes are ignored, an empty prefix stores files at the top of the bucket
+    pub fn new(prefix: &str) -> Self {
+        let prefix = prefix.trim().trim_matches('/');
+        match prefix.is_empty() {
+            true => Naming { prefix: String::new(), mappings: vec![] },
+            false => Naming { prefix: format!("{}/", prefix), mappings: vec![] },
+        }
+    }
+
+    pub fn from_config(cfg: &GUIConfig) -> Self {
+        let naming = match &cfg.remote_prefix {
+            Some(p) => Self::new(p),
+            None => Self::new(""),
+        };
+        naming.with_mappings(&cfg.path_mappings)
+    }
+
+    /// Adds path mappings, mappings with an empty side or conflicting names are ignored, see 'mapping_problems'
+    pub fn with_mappings(mut self, mappings: &[PathMapping]) -> Self {
+        self.mappings.append(&mut check_mappings(mappings).0);
+        self.mappings.sort_by_key(|m| std::cmp::Reverse(m.0.len()));
+        self
+    }
+
+    /// Name (relative to the remote prefix) a local file or directory is stored under
+    /// Directories are given with a trailing '/'
+    pub fn name_for_path<P: AsRef<Path>>(&self, path: P) -> String {
+        let name = path_name(path);
+        for (local, remote) in self.mappings.iter() {
+            // The mapped directory itself may be given without its trailing '/'
+            if name == local[..local.len() - 1] {
+                return remote.clone();
+            }
+            if let Some(rest) = name.strip_prefix(local.as_str()) {
+                return format!("{}{}", remote, rest);
+            }
+        }
+        name
+    }
+
+    /// Reverse of 'name_for_path', the path (without the leading '/' on Unix) a file was backed up from
+    pub fn path_for_name(&self, name: &str) -> String {
+        let mapping = self.mappings.iter()
+            .filter(|(_, remote)| name.starts_with(remote.as_str()))
+            .max_by_key(|(_, remote)| remote.len());
+        match mapping {
+            Some((local, remote)) => format!("{}{}", local, &name[remote.len()..]),
+            None => name.to_string(),
+        }
+    }
+
+    /// Remote names of the mapped directories under the given local name
+    fn mapped_under(&self, name: &str) -> Vec<String> {
+        self.mappings.iter()
+            .filter(|(local, _)| local.starts_with(name))
+            .map(|(_, remote)| remote.clone())
+            .collect()
+    }
+
+    /// The prefix including the trailing '/', or "" if there is none
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Name in the bucket of the given relative name
+    pub fn remote(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+
+    /// Whether the name (relative to the prefix) is too long for B2
+    pub fn is_too_long(&self, name: &str) -> bool {
+        self.prefix.len() + name.len() > MAX_NAME_BYTES
+    }
+
+    /// Name (relative to the prefix) of the object a file named 'name' is stored as in the plain format
+    /// That is the name itself, unless it is too long. Then as much of it as fits is kept, followed by '~' and the SHA1
+    /// of the full name, so it stays unique and sorts next to its directory. Restore gets the full name from the manifest
+    pub fn object_name(&self, name: &str) -> String {
+        if !self.is_too_long(name) {
+            return name.to_string();
+        }
+        let mut keep = MAX_NAME_BYTES.saturating_sub(self.prefix.len() + SHORTENED_SUFFIX_LEN);
+        while !name.is_char_boundary(keep) {
+            keep -= 1;
+        }
+        let mut hasher = sha1::Sha1::new();
+        hasher.update(name.as_bytes());
+        format!("{}~{}", &name[..keep], hasher.digest())
+    }
+
+    /// Relative name of a name in the bucket, None if it isn't under the prefix
+    pub fn local<'a>(&self, name: &'a str) -> Option<&'a str> {
+        name.strip_prefix(self.prefix.as_str())
+    }
+
+    /// Prefixes of other computers sharing the bucket, found by their own objects (e.g. laptop/.backup_gui/...),
+    /// each including the trailing '/'. Only with an empty prefix, where their files are mixed in with ours and have to be left out
+    pub fn other_prefixes<'a, I: IntoIterator<Item = &'a str>>(&self, names: I) -> Vec<String> {
+        if !self.prefix.is_empty() {
+            return vec![];
+        }
+        let mut prefixes: Vec<String> = names.into_iter()
+            .filter_map(|n| {
+                let end = n.find('/')? + 1;
+                match n[end..].starts_with(manifest::REPO_PREFIX) {
+                    true => Some(n[..end].to_string()),
+                    false => None,
+                }
+            })
+            .collect();
+        prefixes.sort_unstable();
+        prefixes.dedup();
+        prefixes
+    }
+
+    /// Keeps the files under the prefix, and strips it from their names
+    /// With an empty prefix, files under other computers' prefixes are left out, see 'other_prefixes'
+    pub fn strip_all(&self, files: Vec<B2FileInfo>) -> Vec<B2FileInfo> {
+        if self.prefix.is_empty() {
+            let others = self.other_prefixes(files.iter().map(|f| f.file_name.as_str()));
+            return files.into_iter()
+                .filter(|f| !others.iter().any(|p| f.file_name.starts_with(p.as_str())))
+                .collect();
+        }
+        files.into_iter()
+            .filter_map(|mut f| {
+                let name = self.local(&f.file_name)?.to_string();
+                f.file_name = name;
+                Some(f)
+            })
+            .collect()
+    }
+}
+
+// Name of a local path without mappings
+// Names that aren't UTF-8 are encoded, see files::escape
+fn path_name<P: AsRef<Path>>(path: P) -> String {
+    let name = escape::encode_path(path);
+    if cfg!(windows) {
+        name
+    } else {
+        name.strip_prefix('/').unwrap_or(&name).to_string()
+    }
+}
+
+/// Why path mappings are ignored, one message for each, see the top of this file
+pub fn mapping_problems(mappings: &[PathMapping]) -> Vec<String> {
+    check_mappings(mappings).1
+}
+
+// Path mappings as (local name, remote name), both including the trailing '/', and why the others are ignored
+fn check_mappings(mappings: &[PathMapping]) -> (Vec<(String, String)>, Vec<String>) {
+    let names: Vec<(String, String)> = mappings.iter()
+        .map(|m| {
+            let local = path_name(m.local.trim());
+            (local.trim_end_matches('/').to_string(), m.remote.trim().trim_matches('/').to_string())
+        })
+        .collect();
+    let mut accepted: Vec<(String, String)> = vec![];
+    let mut problems = vec![];
+    for (m, (local, remote)) in mappings.iter().zip(names.iter()) {
+        if local.is_empty() || remote.is_empty() {
+            problems.push(format!("Ignoring path mapping {:?} -> {:?}, both sides have to be set", m.local, m.remote));
+            continue;
+        }
+        let (local, remote) = (format!("{}/", local), format!("{}/", remote));
+        if let Some((_, other)) = accepted.iter().find(|(_, r)| r.starts_with(remote.as_str()) || remote.starts_with(r.as_str())) {
+            problems.push(format!("Ignoring path mapping {:?} -> {:?}, its remote name overlaps {:?}", m.local, m.remote, other));
+            continue;
+        }
+        // The local path whose own name is the remote name, unless it is mapped elsewhere itself
+        let natural = &remote[..remote.len() - 1];
+        let mapped = names.iter().any(|(l, _)| !l.is_empty() && (natural == l || natural.starts_with(&format!("{}/", l))));
+        if !mapped && natural_path(natural).exists() {
+            problems.push(format!("Ignoring path mapping {:?} -> {:?}, {:?} is stored under that name", m.local, m.remote,
+                                  natural_path(natural)));
+            continue;
+        }
+        accepted.push((local, remote));
+    }
+    (accepted, problems)
+}
+
+// Local path stored under the given name without mappings, the reverse of 'path_name'
+fn natural_path(name: &str) -> PathBuf {
+    if cfg!(windows) {
+        escape::decode_path(name)
+    } else {
+        escape::decode_path(&format!("/{}", name))
+    }
+}
+
+/// Default remote prefix, the hostname without any '/'
+pub fn default_prefix() -> String {
+    crate::files::hostname().replace('/', "_")
+}
+
+/// Relative names of the files selected for upload, i.e. everything under the UPLOAD rules of backuplist.dat,
+/// plus any extra prefixes. Directories mapped elsewhere count as selected if they are under a selected directory
+#[derive(Debug, Default)]
+pub struct Scope {
+    // Selected directories including the trailing '/', and extra prefixes
+    prefixes: Vec<String>,
+    // Selected single files
+    files: Vec<String>,
+}
+
+impl Scope {
+    pub fn new(naming: &Naming, rules: &[String], extra: &[String]) -> Self {
+        let mut scope = Scope::default();
+        for rule in rules {
+            let path = match selection::path_rule(rule) {
+                Some((Action::Upload, p)) => p,
+                _ => continue,
+            };
+            // Paths in backuplist.dat are already encoded
+            let path = escape::decode_path(path);
+            let name = naming.name_for_path(&path);
+            if name.is_empty() || name.ends_with('/') {
+                scope.prefixes.append(&mut naming.mapped_under(&path_name(&path)));
+                scope.prefixes.push(name);
+            } else {
+                scope.files.push(name);
+            }
+        }
+        scope.prefixes.extend(extra.iter().filter(|p| !p.is_empty()).cloned());
+        scope
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.prefixes.iter().any(|p| name.starts_with(p.as_str())) || self.files.iter().any(|f| f == name)
+    }
+
+    /// All prefixes and files, for display
+    pub fn prefixes(&self) -> Vec<String> {
+        self.prefixes.iter().chain(self.files.iter()).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_up_to_the_limit_are_kept() {
+        let naming = Naming::new("laptop");
+        let name = "a".repeat(MAX_NAME_BYTES - naming.prefix().len());
+        assert!(!naming.is_too_long(&name));
+        assert_eq!(naming.object_name(&name), name);
+        assert!(naming.is_too_long(&format!("{}b", name)));
+    }
+
+    #[test]
+    fn long_names_are_shortened_within_the_limit() {
+        let naming = Naming::new("laptop");
+        // Multi-byte characters, so the cut has to move to a character boundary
+        let name = format!("home/{}", "\u{e9}".repeat(MAX_NAME_BYTES));
+        let object = naming.object_name(&name);
+        assert!(naming.remote(&object).len() <= MAX_NAME_BYTES);
+        assert!(!naming.is_too_long(&object));
+        assert!(object.starts_with("home/\u{e9}"));
+        assert_eq!(naming.object_name(&name), object);
+    }
+
+    #[test]
+    fn shortened_names_dont_collide() {
+        let naming = Naming::new("");
+        let start = format!("node_modules/{}", "x".repeat(MAX_NAME_BYTES));
+        let a = naming.object_name(&format!("{}/a.js", start));
+        let b = naming.object_name(&format!("{}/b.js", start));
+        assert_ne!(a, b);
+        // Only the hash differs, the rest of the name is the same
+        assert_eq!(a[..a.len() - SHORTENED_SUFFIX_LEN], b[..b.len() - SHORTENED_SUFFIX_LEN]);
+        assert_eq!(a.len(), MAX_NAME_BYTES);
+    }
+
+    #[test]
+    fn other_computers_are_left_out_with_an_empty_prefix() {
+        let names = ["home/a.txt", "desktop/.backup_gui/manifests/1.json", "desktop/home/b.txt", ".backup_gui/pack_index.json",
+                     "laptop/.backup_gui/chunks/ab/abc", "home/.backup_gui"];
+        assert_eq!(Naming::new("").other_prefixes(names.iter().copied()), vec!["desktop/", "laptop/"]);
+        assert!(Naming::new("laptop").other_prefixes(names.iter().copied()).is_empty());
+    }
+
+    fn mapping(local: &str, remote: &str) -> PathMapping {
+        PathMapping { local: local.to_string(), remote: remote.to_string() }
+    }
+
+    #[test]
+    fn mappings_with_overlapping_remote_names_are_ignored() {
+        let mappings = [mapping("/mnt/a/photos", "backup_gui_test/photos"), mapping("/mnt/b/photos", "backup_gui_test/photos/"),
+                        mapping("/mnt/b/2024", "/backup_gui_test/photos/2024"), mapping("/mnt/c", "backup_gui_test/photos2")];
+        assert_eq!(mapping_problems(&mappings).len(), 2);
+        let naming = Naming::new("").with_mappings(&mappings);
+        assert_eq!(naming.name_for_path("/mnt/a/photos/x.jpg"), "backup_gui_test/photos/x.jpg");
+        assert_eq!(naming.name_for_path("/mnt/b/2024/x.jpg"), "mnt/b/2024/x.jpg");
+        assert_eq!(naming.name_for_path("/mnt/c/x.jpg"), "backup_gui_test/photos2/x.jpg");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mappings_onto_the_name_of_an_unmapped_directory_are_ignored() {
+        let dir = std::env::temp_dir().join(format!("backup_gui_naming_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let name = path_name(&dir);
+
+        let onto = [mapping("/mnt/data2/photos", &name)];
+        assert_eq!(mapping_problems(&onto).len(), 1);
+        assert_eq!(Naming::new("").with_mappings(&onto).name_for_path("/mnt/data2/photos/x.jpg"), "mnt/data2/photos/x.jpg");
+        // Fine once that directory is stored under another name itself
+        let swapped = [mapping("/mnt/data2/photos", &name), mapping(&dir.to_string_lossy(), "old_photos")];
+        assert!(mapping_problems(&swapped).is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn forbidden_characters_are_escaped() {
+        let naming = Naming::new("laptop");
+        let name = naming.name_for_path("/home/user/a\\b\nc.txt");
+        assert_eq!(name, "home/user/%%a%5Cb%0Ac.txt");
+        assert!(!naming.remote(&name).chars().any(|c| c < ' ' || c == '\u{7f}' || c == '\\'));
+        assert_eq!(escape::decode_path(&format!("/{}", naming.path_for_name(&name))), Path::new("/home/user/a\\b\nc.txt"));
+    }
+}
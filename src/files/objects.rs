@@ -0,0 +1,96 @@
+// Storing the program's own objects in the bucket, i.e. chunks, packs, manifests and indexes
+// Unlike backed up files these are held in memory, as they are either small or of bounded size
+
+use std::io::{Cursor, Read};
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+use nanoserde::{DeJson, SerJson};
+use raze::api::{B2Auth, B2FileInfo, Sha1Variant, UploadAuth};
+use reqwest::blocking::Client;
+
+use crate::files::compression;
+use crate::files::tracked_reader::TrackedReader;
+
+/// Uploads 'data' as 'name', retrying up to 5 times
+/// If 'compress' is set the data is compressed first, unless that doesn't make it smaller
+/// 'bandwidth' limits the upload speed (0 = unlimited), 'progress' receives the number of bytes sent
+#[allow(clippy::too_many_arguments)]
+pub fn upload(client: &Client, upauth: &UploadAuth, name: &str, data: Vec<u8>, content_type: &str,
+              compress: bool, bandwidth: usize, progress: Option<Sender<usize>>) -> Result<B2FileInfo, String> {
+    let compressed = if compress {
+        match compression::compress_bytes(&data) {
+            Ok(c) if c.len() < data.len() => Some(c),
+            _ => None,
+        }
+    } else {
+        None
+    };
+    let (data, content_type, file_info) = match compressed {
+        Some(c) => (c, "application/zstd", vec![(compression::INFO_KEY, compression::ZSTD.to_string())]),
+        None => (data, content_type, vec![]),
+    };
+
+    let mut result = Err("Not uploaded".to_string());
+    for attempt in 0..5 {
+        if attempt > 0 {
+            std::thread::sleep(Duration::from_millis(5000));
+        }
+        let params = crate::b2::FileParameters {
+            file_path: name,
+            file_size: data.len() as u64,
+            content_type: Some(content_type),
+            content_sha1: Sha1Variant::HexAtEnd,
+            last_modified_millis: 0,
+            file_info: file_info.clone(),
+        };
+        let reader: Box<dyn Read + Send> = match &progress {
+            Some(tx) => Box::new(TrackedReader::wrap(Cursor::new(data.clone()), tx.clone())),
+            None => Box::new(Cursor::new(data.clone())),
+        };
+        let reader = raze::util::ReadHashAtEnd::wrap(reader);
+        // If bandwidth == 0, do not throttle
+        let res = if bandwidth > 0 {
+            crate::b2::b2_upload_file(client, upauth, raze::util::ReadThrottled::wrap(reader, bandwidth), params)
+        } else {
+            crate::b2::b2_upload_file(client, upauth, reader, params)
+        };
+        match res {
+            Ok(f) => return Ok(f),
+            Err(e) => {
+                println!("Failed to upload {:?} - {:?}", name, e);
+                result = Err(format!("{:?}", e));
+            }
+        }
+    }
+    result
+}
+
+/// Downloads the file with the given id into memory, decompressing it if needed
+pub fn download(client: &Client, auth: &B2Auth, file_id: &str) -> Result<Vec<u8>, String> {
+    let download = crate::b2::b2_download_file_by_id(client, auth, file_id).map_err(|e| format!("{:?}", e))?;
+    let mut reader = compression::decoder(download.reader, download.file_info.get(compression::INFO_KEY)).map_err(|e| format!("{:?}", e))?;
+    let mut data = vec![];
+    reader.read_to_end(&mut data).map_err(|e| format!("{:?}", e))?;
+    Ok(data)
+}
+
+/// Serializes 'value' as JSON and uploads it as 'name'
+pub fn upload_json<T: SerJson>(client: &Client, auth: &B2Auth, bucket_id: &str, name: &str, value: &T) -> Result<B2FileInfo, String> {
+    let upauth = raze::api::b2_get_upload_url(client, auth, bucket_id).map_err(|e| format!("{:?}", e))?;
+    upload(client, &upauth, name, value.serialize_json().into_bytes(), "application/json", false, 0, None)
+}
+
+/// Downloads the file with the given id and parses it as JSON
+pub fn download_json<T: DeJson>(client: &Client, auth: &B2Auth, file_id: &str) -> Result<T, String> {
+    let data = download(client, auth, file_id)?;
+    let json = String::from_utf8(data).map_err(|e| format!("{:?}", e))?;
+    DeJson::deserialize_json(&json).map_err(|e| format!("Malformed JSON - {:?}", e))
+}
+
+/// Finds a file by name in a list of remote files, and returns its id
+pub fn find_id<'a>(remote: &'a [B2FileInfo], name: &str) -> Option<&'a str> {
+    remote.iter()
+        .find(|f| f.file_name == name)
+        .and_then(|f| f.file_id.as_deref())
+}
@@ -0,0 +1,294 @@
+// Packing of small files into larger objects
+//
+// Every uploaded file is a request and an object in the bucket, which makes trees of many tiny files slow to back up
+// When enabled, files smaller than a threshold are instead appended to a pack, which is uploaded once it reaches PACK_SIZE
+// Packs are named after the SHA1 of their content, and stored under PACK_PREFIX
+//
+// The pack index (INDEX_NAME) records the pack and offset of every packed file
+// It is rewritten at the end of each upload run and only lists the files packed in that run,
+// so a packed file always takes precedence over a plain object with the same name
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::mpsc::Sender;
+
+use nanoserde::{DeJson, SerJson};
+use raze::api::{B2Auth, B2FileInfo, UploadAuth};
+use reqwest::blocking::Client;
+
+use crate::files::{manifest, objects};
+use crate::files::manifest::ManifestEntry;
+use crate::files::naming::Naming;
+use crate::files::posix::PosixMeta;
+
+/// Prefix of packs in the bucket
+pub const PACK_PREFIX: &str = ".backup_gui/packs/";
+/// Name of the pack index in the bucket
+pub const INDEX_NAME: &str = ".backup_gui/pack_index.json";
+/// Current version of the index format, bumped on incompatible changes
+pub const VERSION: u32 = 1;
+
+// Packs are uploaded once they reach this size
+const PACK_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, DeJson, SerJson)]
+pub struct PackIndex {
+    pub version: u32,
+    pub files: Vec<PackEntry>,
+}
+
+/// A single packed file
+/// 'name' is the name the file would have in B2 if it wasn't packed
+/// Its content is 'size' bytes at 'offset' in the pack with hash 'pack'
+#[derive(Debug, Clone, DeJson, SerJson)]
+pub struct PackEntry {
+    pub name: String,
+    pub size: u64,
+    pub modified: u64,
+    pub pack: String,
+    pub offset: u64,
+    // See files::posix
+    #[nserde(default_with = "Option::default")]
+    pub posix: Option<PosixMeta>,
+}
+
+impl From<PackEntry> for ManifestEntry {
+    fn from(p: PackEntry) -> Self {
+        ManifestEntry {
+            name: p.name,
+            size: p.size,
+            modified: p.modified,
+            sha1: None,
+            file_id: None,
+            pack: Some(p.pack),
+            offset: p.offset,
+            chunks: vec![],
+            link: None,
+            posix: p.posix,
+            dir: false,
+            hardlink: None,
+            sparse: None,
+        }
+    }
+}
+
+/// Name of the pack with the given hash in the bucket
+pub fn pack_name(hash: &str) -> String {
+    format!("{}{}.pack", PACK_PREFIX, hash)
+}
+
+/// Downloads the pack index, returns None if nothing has been packed yet
+pub fn latest_index(client: &Client, auth: &B2Auth, remote: &[B2FileInfo]) -> Result<Option<PackIndex>, String> {
+    let file_id = match objects::find_id(remote, INDEX_NAME) {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+    let index: PackIndex = objects::download_json(client, auth, file_id)?;
+    if index.version > VERSION {
+        return Err(format!("Pack index version {} is newer than supported ({})", index.version, VERSION));
+    }
+    Ok(Some(index))
+}
+
+// A pack that is being filled
+// The entries don't have their 'pack' set yet, as the hash isn't known until the pack is full
+#[derive(Default)]
+struct PackBuilder {
+    data: Vec<u8>,
+    files: Vec<PackEntry>,
+}
+
+/// State shared between the upload threads for packing small files
+pub struct PackedUpload {
+    // Files smaller than this (in bytes) are packed, 0 = packing disabled
+    threshold: u64,
+    compress: bool,
+    bandwidth: usize,
+    // Whether an index already exists, if so it has to be rewritten even if nothing is packed anymore
+    had_index: bool,
+    // Files in the previous index, by name
+    previous: HashMap<String, PackEntry>,
+    // Files in packs uploaded in this run (or reused from the previous index)
+    entries: Mutex<Vec<PackEntry>>,
+    current: Mutex<PackBuilder>,
+    naming: Naming,
+}
+
+impl PackedUpload {
+    pub fn new(previous: Option<PackIndex>, threshold: u64, compress: bool, bandwidth: usize, naming: Naming) -> Self {
+        let had_index = previous.is_some();
+        let previous = match previous {
+            Some(i) => i.files.into_iter().map(|f| (f.name.clone(), f)).collect(),
+            None => HashMap::new(),
+        };
+        PackedUpload {
+            threshold,
+            compress,
+            bandwidth,
+            had_index,
+            previous,
+            entries: Mutex::new(vec![]),
+            current: Mutex::new(PackBuilder::default()),
+            naming,
+        }
+    }
+
+    /// Whether a file of the given size should be packed
+    pub fn should_pack(&self, size: u64) -> bool {
+        size < self.threshold
+    }
+
+    /// If the file is unchanged since it was packed, keeps it in the index and returns true
+    /// Its metadata is updated to 'posix', which can change without the content changing
+    pub fn reuse_unchanged(&self, name: &str, size: u64, modified: u64, posix: &Option<PosixMeta>) -> bool {
+        match self.previous.get(name) {
+            Some(e) if e.size == size && e.modified == modified => {
+                self.entries.lock().unwrap().push(PackEntry { posix: posix.clone(), ..e.clone() });
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Keeps the previous entry of a file that isn't packed in this run, returns whether it had one
+    pub fn keep_previous(&self, name: &str) -> bool {
+        match self.previous.get(name) {
+            Some(e) => {
+                self.entries.lock().unwrap().push(e.clone());
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Adds a file to the current pack, and uploads the pack if it is full
+    /// 'progress' receives the size of the file once it has been read
+    /// If the file can't be read, its previous version (if any) is kept
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_file(&self, client: &Client, upauth: &UploadAuth, path: &Path, name: &str, modified: u64, posix: Option<PosixMeta>,
+                    progress: Sender<usize>) -> Result<(), String> {
+        let data = match std::fs::read(path) {
+            Ok(d) => d,
+            Err(e) => {
+                if let Some(prev) = self.previous.get(name) {
+                    self.entries.lock().unwrap().push(prev.clone());
+                }
+                return Err(format!("{:?}", e));
+            }
+        };
+        let _ = progress.send(data.len());
+
+        let full = {
+            let mut current = self.current.lock().unwrap();
+            let offset = current.data.len() as u64;
+            current.files.push(PackEntry {
+                name: name.to_string(),
+                size: data.len() as u64,
+                modified,
+                pack: String::new(),
+                offset,
+                posix,
+            });
+            current.data.extend_from_slice(&data);
+            if current.data.len() >= PACK_SIZE {
+                Some(std::mem::take(&mut *current))
+            } else {
+                None
+            }
+        };
+        if let Some(pack) = full {
+            self.upload_pack(client, upauth, pack);
+        }
+        Ok(())
+    }
+
+    // Uploads a pack and adds its files to the index
+    // If it fails, the files keep their previous entries (if any)
+    fn upload_pack(&self, client: &Client, upauth: &UploadAuth, pack: PackBuilder) {
+        let mut hasher = sha1::Sha1::new();
+        hasher.update(&pack.data);
+        let hash = hasher.digest().to_string();
+        let name = pack_name(&hash);
+        println!("Uploading pack {:?} with {} files", name, pack.files.len());
+
+        match objects::upload(client, upauth, &self.naming.remote(&name), pack.data, "application/octet-stream", self.compress, self.bandwidth, None) {
+            Ok(_) => {
+                let mut entries = self.entries.lock().unwrap();
+                for mut f in pack.files {
+                    f.pack = hash.clone();
+                    entries.push(f);
+                }
+            },
+            Err(e) => {
+                println!("Failed to upload pack {:?} - {}", name, e);
+                let mut entries = self.entries.lock().unwrap();
+                for f in pack.files {
+                    if let Some(e) = self.previous.get(&f.name) {
+                        entries.push(e.clone());
+                    }
+                }
+            },
+        }
+    }
+
+    /// Uploads the last (partially filled) pack and the new index
+    /// Returns the packed files
+    pub fn finish(self, client: &Client, auth: &B2Auth, bucket_id: &str) -> Result<Vec<PackEntry>, String> {
+        let pack = std::mem::take(&mut *self.current.lock().unwrap());
+        if !pack.files.is_empty() {
+            let upauth = raze::api::b2_get_upload_url(client, auth, bucket_id).map_err(|e| format!("{:?}", e))?;
+            self.upload_pack(client, &upauth, pack);
+        }
+
+        let mut files = self.entries.into_inner().unwrap();
+        if files.is_empty() && !self.had_index {
+            return Ok(files);
+        }
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        println!("Uploading pack index with {} files", files.len());
+        let index = PackIndex { version: VERSION, files };
+        objects::upload_json(client, auth, bucket_id, &self.naming.remote(INDEX_NAME), &index)?;
+        Ok(index.files)
+    }
+}
+
+/// Returns the names of packs that aren't referenced by the index, as well as plain objects that have since been packed
+pub fn garbage(index: &PackIndex, remote: &[B2FileInfo]) -> Vec<String> {
+    let referenced: HashSet<String> = index.files.iter().map(|f| pack_name(&f.pack)).collect();
+    let packed: HashSet<&str> = index.files.iter().map(|f| f.name.as_str()).collect();
+
+    let mut garbage = vec![];
+    for file in remote {
+        let name = &file.file_name;
+        let unused_pack = name.starts_with(PACK_PREFIX) && !referenced.contains(name);
+        let packed_since = !name.starts_with(manifest::REPO_PREFIX) && packed.contains(name.as_str());
+        if unused_pack || packed_since {
+            garbage.push(name.clone());
+        }
+    }
+    garbage
+}
+
+/// Removes the named files from the index and uploads it
+/// Returns the new version of the index (None if nothing was removed),
+/// and the names of the packs that are no longer referenced as a result
+pub fn remove_files(client: &Client, auth: &B2Auth, bucket_id: &str, naming: &Naming, index: &PackIndex, names: &HashSet<&str>) -> Result<(Option<B2FileInfo>, Vec<String>), String> {
+    let (removed, files): (Vec<PackEntry>, Vec<PackEntry>) = index.files.iter().cloned()
+        .partition(|f| names.contains(f.name.as_str()));
+    if removed.is_empty() {
+        return Ok((None, vec![]));
+    }
+    println!("Removing {} deleted files from the pack index", removed.len());
+    let index = PackIndex { version: VERSION, files };
+    let uploaded = objects::upload_json(client, auth, bucket_id, &naming.remote(INDEX_NAME), &index)?;
+
+    let referenced: HashSet<&str> = index.files.iter().map(|f| f.pack.as_str()).collect();
+    let mut unused: Vec<String> = removed.iter()
+        .filter(|f| !referenced.contains(f.pack.as_str()))
+        .map(|f| pack_name(&f.pack))
+        .collect();
+    unused.sort();
+    unused.dedup();
+    Ok((Some(uploaded), unused))
+}
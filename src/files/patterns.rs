@@ -0,0 +1,428 @@
+// Pattern rules, which exclude files and directories by name anywhere in the selection
+//
+// Besides the UPLOAD/EXCLUDE lines for single paths, backuplist.dat can hold pattern rules:
+//     GLOB <pattern>   e.g. *.tmp, **/node_modules/, **/.cache/**
+//     REGEX <regex>    matched against the full path
+// Matching entries are skipped while collecting the files to upload (see DirEntry::get_files),
+// a matching directory is skipped with everything in it, even paths selected inside it
+//
+// Globs work like in .gitignore: '*' matches within a path component, '**' across components,
+// '?' matches a single character and [abc] a set of characters
+// A glob without a '/' (other than a trailing one) matches the name of an entry in any directory,
+// other globs match the full path from the root like in .gitignore, so the leading '/' is optional (on Windows they start
+// with the drive, e.g. C:/). Start them with '**/' to match at any depth, e.g. **/build/out
+// A glob ending in '/' only matches directories
+// Paths use '/' as the separator, and directories end in '/' when matched against a regex
+//
+// A directory can also hold a .backupignore file, written like a .gitignore, that applies to everything below it
+// Patterns in it are relative to that directory, '#' starts a comment and '!' re-includes what an earlier line excluded
+// .gitignore files are read the same way if GUIConfig::respect_gitignore is set, which callers pass in (see Filters)
+// Among the ignore files the last matching rule wins, the rules of deeper directories are checked after those of their parents
+// The rules in backuplist.dat always win, a '!' line can't include again what they exclude
+
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::files::selection::Selection;
+
+/// Prefixes of pattern rule lines
+pub const GLOB: &str = "GLOB ";
+pub const REGEX: &str = "REGEX ";
+
+/// Name of the per-directory ignore file
+pub const IGNORE_FILE: &str = ".backupignore";
+
+/// A single pattern rule
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    // The line in backuplist.dat
+    rule: String,
+    regex: Regex,
+    dir_only: bool,
+    // A '!' line of an ignore file, matching entries are included again
+    negated: bool,
+}
+
+impl Pattern {
+    /// Parses a rule line, returns None if it isn't a pattern rule
+    pub fn parse(line: &str) -> Option<Result<Pattern, String>> {
+        line.strip_prefix(GLOB).map(Pattern::glob)
+            .or_else(|| line.strip_prefix(REGEX).map(Pattern::regex))
+    }
+
+    pub fn glob(glob: &str) -> Result<Pattern, String> {
+        if glob.is_empty() {
+            return Err("Empty pattern".to_string());
+        }
+        let dir_only = glob.ends_with('/') && glob.len() > 1;
+        let trimmed = if dir_only { &glob[..glob.len() - 1] } else { glob };
+        let anchored = trimmed.contains('/') || trimmed.chars().nth(1) == Some(':');
+        let mut regex = match (anchored, trimmed.starts_with('/')) {
+            (true, true) => "^".to_string(),
+            (true, false) => "^/?".to_string(),
+            (false, _) => "(^|/)".to_string(),
+        };
+        regex.push_str(&glob_to_regex(trimmed)?);
+        regex.push('$');
+        let regex = Regex::new(&regex).map_err(|e| format!("Invalid pattern {:?} - {}", glob, e))?;
+        Ok(Pattern { rule: format!("{}{}", GLOB, glob), regex, dir_only, negated: false })
+    }
+
+    pub fn regex(regex: &str) -> Result<Pattern, String> {
+        if regex.is_empty() {
+            return Err("Empty pattern".to_string());
+        }
+        let compiled = Regex::new(regex).map_err(|e| format!("Invalid regex {:?} - {}", regex, e))?;
+        Ok(Pattern { rule: format!("{}{}", REGEX, regex), regex: compiled, dir_only: false, negated: false })
+    }
+
+    /// Parses a line of the ignore file in 'dir', returns None for blank lines and comments
+    pub fn ignore_line(dir: &str, line: &str) -> Option<Result<Pattern, String>> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, glob) = match line.strip_prefix('!') {
+            Some(g) => (true, g),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let dir_only = glob.ends_with('/');
+        let trimmed = glob.trim_end_matches('/');
+        if trimmed.is_empty() {
+            return None;
+        }
+        // Like in .gitignore, a '/' at the start or in the middle ties the pattern to the directory
+        let anchored = trimmed.contains('/');
+        let mut regex = format!("^{}/", regex::escape(dir.replace("\\", "/").trim_end_matches('/')));
+        if !anchored {
+            regex.push_str("(.*/)?");
+        }
+        let result = glob_to_regex(trimmed.trim_start_matches('/'))
+            .and_then(|g| Regex::new(&format!("{}{}$", regex, g)).map_err(|e| e.to_string()))
+            .map(|regex| Pattern { rule: format!("{}{}", GLOB, line), regex, dir_only, negated })
+            .map_err(|e| format!("Invalid pattern {:?} in {} - {}", line, dir, e));
+        Some(result)
+    }
+
+    /// The rule line, as stored in backuplist.dat
+    pub fn rule(&self) -> &str {
+        &self.rule
+    }
+
+    // 'path' uses '/' as the separator, without a trailing '/'
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.rule.starts_with(REGEX) {
+            return match is_dir {
+                true => self.regex.is_match(&format!("{}/", path)),
+                false => self.regex.is_match(path),
+            };
+        }
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        // Directories are also tried with a trailing '/', so e.g. **/.cache/** excludes .cache itself
+        self.regex.is_match(path) || (is_dir && self.regex.is_match(&format!("{}/", path)))
+    }
+}
+
+// Translates the glob syntax to a regex, everything else is matched literally
+fn glob_to_regex(glob: &str) -> Result<String, String> {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut regex = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                i += 1;
+                if chars.get(i + 1) == Some(&'/') {
+                    // '**/' matches zero or more directories
+                    regex.push_str("(.*/)?");
+                    i += 1;
+                } else {
+                    regex.push_str(".*");
+                }
+            },
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                let end = match chars[i + 1..].iter().position(|c| *c == ']') {
+                    Some(n) => i + 1 + n,
+                    None => return Err(format!("Unclosed '[' in {:?}", glob)),
+                };
+                regex.push('[');
+                for (j, c) in chars[i + 1..end].iter().enumerate() {
+                    match c {
+                        '!' if j == 0 => regex.push('^'),
+                        '\\' | '[' | '&' | '~' => regex.push_str(&regex::escape(&c.to_string())),
+                        _ => regex.push(*c),
+                    }
+                }
+                regex.push(']');
+                i = end;
+            },
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+    Ok(regex)
+}
+
+/// The pattern rules in effect
+#[derive(Debug, Clone, Default)]
+pub struct Patterns {
+    // Rules of backuplist.dat
+    patterns: Vec<Pattern>,
+    // Rules of the ignore files, outermost directory first
+    ignore: Vec<Pattern>,
+}
+
+impl Patterns {
+    /// Picks the pattern rules out of the lines of backuplist.dat, invalid ones are skipped
+    pub fn from_rules(rules: &[String]) -> Self {
+        let mut patterns = vec![];
+        for line in rules {
+            match Pattern::parse(line) {
+                Some(Ok(p)) => patterns.push(p),
+                Some(Err(e)) => println!("Skipping pattern rule - {}", e),
+                None => (),
+            }
+        }
+        Patterns { patterns, ignore: vec![] }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Self {
+        Self::from_rules(&crate::files::manifest::read_rules(path))
+    }
+
+    /// Whether the file or directory at 'path' is excluded by a pattern
+    pub fn is_excluded<P: AsRef<Path>>(&self, path: P, is_dir: bool) -> bool {
+        if self.patterns.is_empty() && self.ignore.is_empty() {
+            return false;
+        }
+        let path = path.as_ref().to_string_lossy().replace("\\", "/");
+        let path = match path.len() > 1 {
+            true => path.trim_end_matches('/'),
+            false => &path,
+        };
+        if self.patterns.iter().any(|p| p.matches(path, is_dir)) {
+            return true;
+        }
+        match self.ignore.iter().rev().find(|p| p.matches(path, is_dir)) {
+            Some(p) => !p.negated,
+            None => false,
+        }
+    }
+
+    /// Adds the rules of the ignore files in 'dir', returns None if it has none
+    /// Used when descending into a directory, the rules then apply to everything below it
+    /// .gitignore files are only read if 'gitignore' is set
+    pub fn for_dir<P: AsRef<Path>>(&self, dir: P, gitignore: bool) -> Option<Patterns> {
+        let dir = dir.as_ref();
+        // The Windows root element has an empty path, see files::get_roots
+        if dir.as_os_str().is_empty() {
+            return None;
+        }
+        let mut names = vec![IGNORE_FILE];
+        if gitignore {
+            names.push(".gitignore");
+        }
+        let dir_name = dir.to_string_lossy();
+        let mut added = vec![];
+        for name in names {
+            let content = match std::fs::read_to_string(dir.join(name)) {
+                Ok(c) => c,
+                Err(_e) => continue,
+            };
+            for line in content.lines() {
+                match Pattern::ignore_line(&dir_name, line) {
+                    Some(Ok(p)) => added.push(p),
+                    Some(Err(e)) => println!("Skipping ignore rule - {}", e),
+                    None => (),
+                }
+            }
+        }
+        if added.is_empty() {
+            return None;
+        }
+        let mut patterns = self.clone();
+        patterns.ignore.append(&mut added);
+        Some(patterns)
+    }
+
+    /// The rules of the ignore files in 'dir' and all directories above it
+    pub fn inherited<P: AsRef<Path>>(dir: P, gitignore: bool) -> Patterns {
+        let mut dirs: Vec<&Path> = dir.as_ref().ancestors().collect();
+        dirs.reverse();
+        let mut patterns = Patterns::default();
+        for d in dirs {
+            if let Some(p) = patterns.for_dir(d, gitignore) {
+                patterns = p;
+            }
+        }
+        patterns
+    }
+}
+
+/// Whether a line of backuplist.dat is a pattern rule
+pub fn is_rule(line: &str) -> bool {
+    line.starts_with(GLOB) || line.starts_with(REGEX)
+}
+
+/// Reads the pattern rule lines from the given file
+pub fn read<P: AsRef<Path>>(path: P) -> Vec<String> {
+    crate::files::manifest::read_rules(path).into_iter().filter(|l| is_rule(l)).collect()
+}
+
+/// Replaces the pattern rules in the given file, keeping the other rules and comments
+pub fn write<P: AsRef<Path>>(path: P, rules: &[String]) -> Result<(), String> {
+    let mut selection = Selection::load(&path)?;
+    selection.rules.retain(|l| !is_rule(l));
+    selection.rules.extend(rules.iter().cloned());
+    selection.write(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn excluded(rules: &[&str], path: &str, is_dir: bool) -> bool {
+        let rules: Vec<String> = rules.iter().map(|r| r.to_string()).collect();
+        Patterns::from_rules(&rules).is_excluded(path, is_dir)
+    }
+
+    #[test]
+    fn star_stays_within_a_component() {
+        assert_eq!(glob_to_regex("*.tmp").unwrap(), "[^/]*\\.tmp");
+        assert!(excluded(&["GLOB *.tmp"], "/home/user/a.tmp", false));
+        assert!(excluded(&["GLOB *.tmp"], "/home/user/.tmp", false));
+        assert!(!excluded(&["GLOB *.tmp"], "/home/user/a.tmp.txt", false));
+        assert!(excluded(&["GLOB /home/*/a.txt"], "/home/user/a.txt", false));
+        assert!(!excluded(&["GLOB /home/*/a.txt"], "/home/user/docs/a.txt", false));
+    }
+
+    #[test]
+    fn double_star_crosses_components() {
+        assert!(excluded(&["GLOB **/node_modules/"], "/home/user/app/node_modules", true));
+        assert!(!excluded(&["GLOB **/node_modules/"], "/home/user/app/node_modules", false));
+        assert!(excluded(&["GLOB /home/**/a.txt"], "/home/a.txt", false));
+        assert!(excluded(&["GLOB /home/**/a.txt"], "/home/user/docs/a.txt", false));
+        // The directory itself is excluded too, not just what is in it
+        assert!(excluded(&["GLOB **/.cache/**"], "/home/user/.cache", true));
+        assert!(excluded(&["GLOB **/.cache/**"], "/home/user/.cache/thumbs/a.png", false));
+        assert!(!excluded(&["GLOB **/.cache/**"], "/home/user/.cache2/a.png", false));
+    }
+
+    #[test]
+    fn question_mark_and_classes_match_one_character() {
+        assert!(excluded(&["GLOB ?.txt"], "/d/a.txt", false));
+        assert!(!excluded(&["GLOB ?.txt"], "/d/ab.txt", false));
+        assert!(!excluded(&["GLOB d?a.txt"], "/d/a.txt", false));
+        assert!(excluded(&["GLOB [ab].txt"], "/d/b.txt", false));
+        assert!(!excluded(&["GLOB [ab].txt"], "/d/c.txt", false));
+        assert!(excluded(&["GLOB [!ab].txt"], "/d/c.txt", false));
+        assert!(!excluded(&["GLOB [!ab].txt"], "/d/a.txt", false));
+        assert!(excluded(&["GLOB [0-9].log"], "/d/7.log", false));
+        assert!(Pattern::glob("[ab.txt").is_err());
+    }
+
+    #[test]
+    fn other_characters_are_literal() {
+        assert!(excluded(&["GLOB a+b(1).txt"], "/d/a+b(1).txt", false));
+        assert!(!excluded(&["GLOB a.txt"], "/d/abtxt", false));
+        assert!(!excluded(&["GLOB a$"], "/d/a", false));
+        assert!(excluded(&["GLOB a$"], "/d/a$", false));
+    }
+
+    #[test]
+    fn globs_with_a_slash_are_anchored_at_the_root() {
+        assert!(excluded(&["GLOB build/out"], "/build/out", true));
+        assert!(!excluded(&["GLOB build/out"], "/src/build/out", true));
+        assert!(excluded(&["GLOB **/build/out"], "/src/build/out", true));
+        assert!(excluded(&["GLOB /tmp/*.log"], "/tmp/a.log", false));
+        assert!(!excluded(&["GLOB /tmp/*.log"], "/var/tmp/a.log", false));
+        // Without a '/', the name matches in any directory
+        assert!(excluded(&["GLOB out"], "/src/build/out", true));
+        assert!(excluded(&["GLOB out/"], "/src/build/out", true));
+        assert!(!excluded(&["GLOB out/"], "/src/build/out", false));
+    }
+
+    // Ignore files in a temporary directory, given as (relative directory, file name, content)
+    fn ignore_files(test: &str, files: &[(&str, &str, &str)]) -> std::path::PathBuf {
+        let root = std::env::temp_dir().join(format!("backup_gui_patterns_{}_{}", test, std::process::id()));
+        for (dir, name, content) in files {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+            std::fs::write(root.join(dir).join(name), content).unwrap();
+        }
+        root
+    }
+
+    // Whether 'path' (relative to 'root') is excluded, reading the ignore files the way the upload does
+    fn ignored(patterns: &Patterns, root: &Path, path: &str, is_dir: bool, gitignore: bool) -> bool {
+        let path = root.join(path);
+        let mut dirs: Vec<&Path> = path.parent().unwrap().ancestors().collect();
+        dirs.reverse();
+        let mut patterns = patterns.clone();
+        for d in dirs {
+            if let Some(p) = patterns.for_dir(d, gitignore) {
+                patterns = p;
+            }
+        }
+        patterns.is_excluded(&path, is_dir)
+    }
+
+    #[test]
+    fn ignore_files_apply_to_everything_below_them() {
+        let root = ignore_files("inherit", &[("", IGNORE_FILE, "*.log\nbuild/\n/top.txt\n"), ("a/b", "x.txt", "")]);
+        let none = Patterns::default();
+        assert!(ignored(&none, &root, "a/b/debug.log", false, false));
+        assert!(ignored(&none, &root, "a/build", true, false));
+        assert!(!ignored(&none, &root, "a/build", false, false));
+        // A leading '/' ties the pattern to the directory of the ignore file
+        assert!(ignored(&none, &root, "top.txt", false, false));
+        assert!(!ignored(&none, &root, "a/top.txt", false, false));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn the_last_matching_line_wins() {
+        let root = ignore_files("negate", &[("", IGNORE_FILE, "*.log\n!keep.log\n"), ("a", IGNORE_FILE, "# Comment\nkeep.log\n"),
+                                            ("b", IGNORE_FILE, "!*.log\n*.log\n")]);
+        let none = Patterns::default();
+        assert!(ignored(&none, &root, "debug.log", false, false));
+        assert!(!ignored(&none, &root, "keep.log", false, false));
+        // Deeper ignore files are checked after their parents
+        assert!(ignored(&none, &root, "a/keep.log", false, false));
+        assert!(ignored(&none, &root, "a/c/debug.log", false, false));
+        assert!(ignored(&none, &root, "b/keep.log", false, false));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn negations_dont_override_the_selection_rules() {
+        let root = ignore_files("central", &[("", IGNORE_FILE, "!*.tmp\n!cache/\n")]);
+        let rules = Patterns::from_rules(&["GLOB *.tmp".to_string(), "GLOB **/cache/".to_string()]);
+        assert!(ignored(&rules, &root, "a.tmp", false, false));
+        assert!(ignored(&rules, &root, "cache", true, false));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn gitignore_files_are_only_read_when_asked() {
+        let root = ignore_files("gitignore", &[("", ".gitignore", "target/\n")]);
+        let none = Patterns::default();
+        assert!(!ignored(&none, &root, "target", true, false));
+        assert!(ignored(&none, &root, "target", true, true));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn regexes_match_the_full_path() {
+        assert!(excluded(&["REGEX \\.o$"], "/src/main.o", false));
+        assert!(!excluded(&["REGEX \\.o$"], "/src/main.obj", false));
+        // Directories end in '/'
+        assert!(excluded(&["REGEX /target/$"], "/src/target", true));
+        assert!(!excluded(&["REGEX /target/$"], "/src/target", false));
+        assert!(Pattern::regex("(").is_err());
+    }
+}
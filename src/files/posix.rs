@@ -0,0 +1,368 @@
+// POSIX metadata: permissions, ownership, timestamps and extended attributes (Unix only)
+//
+// Read when a file is uploaded and applied when it is restored, so a restored tree is owned and accessible like the original
+// Plain objects carry it as file info (X-Bz-Info-posix_* headers), every format also records it in the manifest
+// (see ManifestEntry::posix), and packed files in the pack index
+// Extended attributes that don't fit in a header are uploaded as a sidecar object under SIDECAR_PREFIX,
+// named after their hash, and the header holds its file id instead
+//
+// Like tar, ownership is restored by name if the user or group exists, by number otherwise, and only when restoring as root
+// On other platforms nothing is read, and restored files only get their modification time back
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use nanoserde::{DeJson, SerJson};
+use raze::api::{B2Auth, B2FileInfo, UploadAuth};
+use reqwest::blocking::Client;
+
+use crate::files::naming::Naming;
+use crate::files::objects;
+
+/// Prefix of extended attribute sidecars in the bucket
+pub const SIDECAR_PREFIX: &str = ".backup_gui/xattrs/";
+
+// B2 allows 7000 bytes of file info headers, after percent-encoding, which can triple the size of the JSON
+const XATTR_HEADER_LIMIT: usize = 1500;
+
+/// Metadata of a single file
+/// Times are seconds since the Unix epoch plus nanoseconds
+#[derive(Debug, Clone, Default, PartialEq, DeJson, SerJson)]
+pub struct PosixMeta {
+    // Including the file type bits, as returned by stat
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    // Empty if the id has no name
+    #[nserde(default)]
+    pub user: String,
+    #[nserde(default)]
+    pub group: String,
+    pub atime: i64,
+    pub atime_nsec: u32,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    #[nserde(default)]
+    pub xattrs: Vec<Xattr>,
+}
+
+/// An extended attribute, the value is hex encoded as it is binary
+#[derive(Debug, Clone, PartialEq, DeJson, SerJson)]
+pub struct Xattr {
+    pub name: String,
+    pub value: String,
+}
+
+/// Name of the sidecar holding the given extended attributes (as JSON)
+pub fn sidecar_name(json: &str) -> String {
+    let mut hasher = sha1::Sha1::new();
+    hasher.update(json.as_bytes());
+    format!("{}{}.json", SIDECAR_PREFIX, hasher.digest())
+}
+
+impl PosixMeta {
+    /// Whether the metadata differs from 'other', other than in the access time, which reading the file changes
+    pub fn changed(&self, other: &PosixMeta) -> bool {
+        self.mode != other.mode || self.uid != other.uid || self.gid != other.gid
+            || self.user != other.user || self.group != other.group
+            || self.mtime != other.mtime || self.mtime_nsec != other.mtime_nsec || self.xattrs != other.xattrs
+    }
+
+    /// File info for a plain object holding the file
+    /// If the extended attributes are too large for a header, they are uploaded as a sidecar first,
+    /// unless one with the same content is already in 'remote'. If that fails, they are left out
+    pub fn file_info(&self, client: &Client, upauth: &UploadAuth, naming: &Naming, remote: &[B2FileInfo]) -> Vec<(&'static str, String)> {
+        let mut info = vec![
+            ("posix_mode", format!("{:o}", self.mode)),
+            ("posix_owner", format!("{}:{}", self.uid, self.gid)),
+            ("posix_names", format!("{}:{}", self.user, self.group)),
+            ("posix_atime", format!("{}.{:09}", self.atime, self.atime_nsec)),
+            ("posix_mtime", format!("{}.{:09}", self.mtime, self.mtime_nsec)),
+        ];
+        if self.xattrs.is_empty() {
+            return info;
+        }
+        let json = self.xattrs.serialize_json();
+        if json.len() <= XATTR_HEADER_LIMIT {
+            info.push(("posix_xattrs", json));
+            return info;
+        }
+        let name = sidecar_name(&json);
+        let stored = objects::find_id(remote, &name).map(|id| id.to_string());
+        let uploaded = stored.map(Ok).unwrap_or_else(|| {
+            objects::upload(client, upauth, &naming.remote(&name), json.into_bytes(), "application/json", false, 0, None)
+                .and_then(|f| f.file_id.ok_or_else(|| "No file id".to_string()))
+        });
+        match uploaded {
+            Ok(id) => info.push(("posix_xattrs_id", id)),
+            Err(e) => println!("Failed to upload extended attributes {:?} - {}, they are not backed up", name, e),
+        }
+        info
+    }
+
+    /// Reads the metadata from the file info of a plain object, None if it has none
+    /// Sidecars are downloaded as needed
+    pub fn from_file_info(info: &HashMap<String, String>, client: &Client, auth: &B2Auth) -> Option<Self> {
+        let mode = u32::from_str_radix(info.get("posix_mode")?, 8).ok()?;
+        let (uid, gid) = split_pair(info.get("posix_owner")?)?;
+        let (user, group) = info.get("posix_names").and_then(|n| split_pair::<String>(n)).unwrap_or_default();
+        let (atime, atime_nsec) = info.get("posix_atime").and_then(|t| parse_time(t)).unwrap_or_default();
+        let (mtime, mtime_nsec) = parse_time(info.get("posix_mtime")?)?;
+        let xattrs = match (info.get("posix_xattrs"), info.get("posix_xattrs_id")) {
+            (Some(json), _) => DeJson::deserialize_json(json).ok(),
+            (None, Some(id)) => objects::download_json(client, auth, id)
+                .map_err(|e| println!("Failed to download extended attributes - {}", e))
+                .ok(),
+            (None, None) => None,
+        };
+        Some(PosixMeta { mode, uid, gid, user, group, atime, atime_nsec, mtime, mtime_nsec, xattrs: xattrs.unwrap_or_default() })
+    }
+}
+
+// "a:b", e.g. an owner "1000:1000"
+fn split_pair<T: std::str::FromStr>(s: &str) -> Option<(T, T)> {
+    let mut parts = s.splitn(2, ':');
+    let a = parts.next()?.parse().ok()?;
+    let b = parts.next()?.parse().ok()?;
+    Some((a, b))
+}
+
+// "seconds.nanoseconds"
+fn parse_time(s: &str) -> Option<(i64, u32)> {
+    let mut parts = s.splitn(2, '.');
+    let secs = parts.next()?.parse().ok()?;
+    let nsec = parts.next().map_or(Some(0), |n| n.parse().ok())?;
+    Some((secs, nsec))
+}
+
+/// Reads the metadata of the file at 'path', None if it can't be read or the platform has none
+#[cfg(unix)]
+pub fn read<P: AsRef<Path>>(path: P) -> Option<PosixMeta> {
+    use std::os::unix::fs::MetadataExt;
+    let path = path.as_ref();
+    let m = std::fs::metadata(path).ok()?;
+    Some(PosixMeta {
+        mode: m.mode(),
+        uid: m.uid(),
+        gid: m.gid(),
+        user: sys::user_name(m.uid()).unwrap_or_default(),
+        group: sys::group_name(m.gid()).unwrap_or_default(),
+        atime: m.atime(),
+        atime_nsec: m.atime_nsec() as u32,
+        mtime: m.mtime(),
+        mtime_nsec: m.mtime_nsec() as u32,
+        xattrs: sys::xattrs(path),
+    })
+}
+
+#[cfg(not(unix))]
+pub fn read<P: AsRef<Path>>(_path: P) -> Option<PosixMeta> {
+    None
+}
+
+/// Applies the metadata to the file at 'path', which has just been restored
+/// Attributes, owner, times and mode are set in that order: changing the owner can clear the setuid bits,
+/// and the mode can make the file read-only
+/// Returns what couldn't be applied, if anything
+#[cfg(unix)]
+pub fn apply(meta: &PosixMeta, path: &Path) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let mut failed = vec![];
+    for x in meta.xattrs.iter() {
+        if let Err(e) = sys::set_xattr(path, &x.name, &from_hex(&x.value)) {
+            failed.push(format!("attribute {} ({:?})", x.name, e));
+        }
+    }
+    if sys::is_root() {
+        let uid = sys::user_id(&meta.user).unwrap_or(meta.uid);
+        let gid = sys::group_id(&meta.group).unwrap_or(meta.gid);
+        if let Err(e) = std::os::unix::fs::chown(path, Some(uid), Some(gid)) {
+            failed.push(format!("owner ({:?})", e));
+        }
+    }
+    let time = |secs: i64, nsec: u32| match secs {
+        s if s >= 0 => UNIX_EPOCH + Duration::new(s as u64, nsec),
+        s => UNIX_EPOCH - Duration::from_secs(s.unsigned_abs()) + Duration::from_nanos(nsec as u64),
+    };
+    let times = std::fs::FileTimes::new()
+        .set_accessed(time(meta.atime, meta.atime_nsec))
+        .set_modified(time(meta.mtime, meta.mtime_nsec));
+    if let Err(e) = std::fs::File::open(path).and_then(|f| f.set_times(times)) {
+        failed.push(format!("times ({:?})", e));
+    }
+    if let Err(e) = std::fs::set_permissions(path, std::fs::Permissions::from_mode(meta.mode & 0o7777)) {
+        failed.push(format!("mode ({:?})", e));
+    }
+    match failed.is_empty() {
+        true => Ok(()),
+        false => Err(format!("Failed to restore {}", failed.join(", "))),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn apply(_meta: &PosixMeta, _path: &Path) -> Result<(), String> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn from_hex(s: &str) -> Vec<u8> {
+    (0..s.len() / 2).filter_map(|i| u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()).collect()
+}
+
+// User and group names and extended attributes, through libc
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::ffi::{CStr, CString};
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    use super::Xattr;
+
+    // Large enough for the entries of getpwuid_r and friends
+    const BUF_SIZE: usize = 16 * 1024;
+
+    pub fn is_root() -> bool {
+        unsafe { libc::geteuid() == 0 }
+    }
+
+    pub fn user_name(uid: u32) -> Option<String> {
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut buf = vec![0 as libc::c_char; BUF_SIZE];
+        let mut result = std::ptr::null_mut();
+        unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+        match result.is_null() {
+            true => None,
+            false => Some(unsafe { CStr::from_ptr(pwd.pw_name) }.to_string_lossy().into_owned()),
+        }
+    }
+
+    pub fn group_name(gid: u32) -> Option<String> {
+        let mut grp: libc::group = unsafe { std::mem::zeroed() };
+        let mut buf = vec![0 as libc::c_char; BUF_SIZE];
+        let mut result = std::ptr::null_mut();
+        unsafe { libc::getgrgid_r(gid, &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) };
+        match result.is_null() {
+            true => None,
+            false => Some(unsafe { CStr::from_ptr(grp.gr_name) }.to_string_lossy().into_owned()),
+        }
+    }
+
+    pub fn user_id(name: &str) -> Option<u32> {
+        let name = CString::new(name).ok().filter(|n| !n.as_bytes().is_empty())?;
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut buf = vec![0 as libc::c_char; BUF_SIZE];
+        let mut result = std::ptr::null_mut();
+        unsafe { libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+        match result.is_null() {
+            true => None,
+            false => Some(pwd.pw_uid),
+        }
+    }
+
+    pub fn group_id(name: &str) -> Option<u32> {
+        let name = CString::new(name).ok().filter(|n| !n.as_bytes().is_empty())?;
+        let mut grp: libc::group = unsafe { std::mem::zeroed() };
+        let mut buf = vec![0 as libc::c_char; BUF_SIZE];
+        let mut result = std::ptr::null_mut();
+        unsafe { libc::getgrnam_r(name.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) };
+        match result.is_null() {
+            true => None,
+            false => Some(grp.gr_gid),
+        }
+    }
+
+    /// Extended attributes of the file, attributes that can't be read are left out
+    pub fn xattrs(path: &Path) -> Vec<Xattr> {
+        let path = match CString::new(path.as_os_str().as_bytes()) {
+            Ok(p) => p,
+            Err(_e) => return vec![],
+        };
+        // The size is asked first, the list is a sequence of NUL terminated names
+        let size = unsafe { libc::llistxattr(path.as_ptr(), std::ptr::null_mut(), 0) };
+        if size <= 0 {
+            return vec![];
+        }
+        let mut names = vec![0u8; size as usize];
+        let size = unsafe { libc::llistxattr(path.as_ptr(), names.as_mut_ptr() as *mut libc::c_char, names.len()) };
+        if size <= 0 {
+            return vec![];
+        }
+        names.truncate(size as usize);
+
+        let mut xattrs = vec![];
+        for name in names.split(|b| *b == 0).filter(|n| !n.is_empty()) {
+            let cname = match CString::new(name) {
+                Ok(n) => n,
+                Err(_e) => continue,
+            };
+            let size = unsafe { libc::lgetxattr(path.as_ptr(), cname.as_ptr(), std::ptr::null_mut(), 0) };
+            if size < 0 {
+                continue;
+            }
+            let mut value = vec![0u8; size as usize];
+            let size = unsafe { libc::lgetxattr(path.as_ptr(), cname.as_ptr(), value.as_mut_ptr() as *mut libc::c_void, value.len()) };
+            if size < 0 {
+                continue;
+            }
+            value.truncate(size as usize);
+            xattrs.push(Xattr { name: String::from_utf8_lossy(name).into_owned(), value: to_hex(&value) });
+        }
+        xattrs
+    }
+
+    pub fn set_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        let path = CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let result = unsafe {
+            libc::lsetxattr(path.as_ptr(), name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0)
+        };
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    fn to_hex(data: &[u8]) -> String {
+        data.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+// Without libc, names and extended attributes aren't available
+#[cfg(all(unix, not(target_os = "linux")))]
+mod sys {
+    use std::io;
+    use std::path::Path;
+
+    use super::Xattr;
+
+    pub fn is_root() -> bool {
+        false
+    }
+
+    pub fn user_name(_uid: u32) -> Option<String> {
+        None
+    }
+
+    pub fn group_name(_gid: u32) -> Option<String> {
+        None
+    }
+
+    pub fn user_id(_name: &str) -> Option<u32> {
+        None
+    }
+
+    pub fn group_id(_name: &str) -> Option<u32> {
+        None
+    }
+
+    pub fn xattrs(_path: &Path) -> Vec<Xattr> {
+        vec![]
+    }
+
+    pub fn set_xattr(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,329 @@
+// The selection file, backuplist.dat, which lists what is backed up
+//
+// Format (version 2):
+//     # backup_gui selection v2     the header, always the first line
+//     # Anything else               comments, kept when the file is rewritten (at the top, after the header)
+//     UPLOAD /home/user/            a selected file or directory, directories end in '/'
+//     EXCLUDE /home/user/tmp/       a file or directory left out of a selected directory
+//     GLOB **/node_modules/         pattern rules, see patterns.rs
+//     REGEX \.o$
+// Paths are absolute with '/' as the separator (C:/... on Windows), and names are encoded like in B2 (see escape.rs),
+// so a path can't hold a newline. Empty lines and Windows line endings are ignored
+//
+// Files without the header are version 1, from before the format had one. Their lines are the same, so they are
+// read as they are, and rewritten with the header by 'migrate'. A copy of the old file is kept next to it
+// Files written by a newer version are refused rather than guessed at, nothing is written over them either
+//
+// Selections can be exported and imported (see cli.rs), to share them between computers or keep them in git
+// Exported paths under the home directory start with '~/' instead, which import expands to the home directory there,
+// so one file can be handed out to everyone
+
+use std::path::{Path, PathBuf};
+
+use crate::files::{escape, patterns, Action};
+
+/// Current version of the format
+pub const VERSION: u32 = 2;
+// First line of the file, followed by the version
+const HEADER: &str = "# backup_gui selection v";
+
+/// Prefixes of the lines selecting or excluding a single path
+pub const UPLOAD: &str = "UPLOAD ";
+pub const EXCLUDE: &str = "EXCLUDE ";
+
+/// The contents of a selection file
+#[derive(Debug, Clone)]
+pub struct Selection {
+    // Version the file was written with, 1 if it had no header
+    pub version: u32,
+    // Including the leading '#', without the header
+    pub comments: Vec<String>,
+    // UPLOAD, EXCLUDE and pattern rule lines, in order
+    pub rules: Vec<String>,
+}
+
+impl Default for Selection {
+    fn default() -> Self {
+        Selection { version: VERSION, comments: vec![], rules: vec![] }
+    }
+}
+
+impl Selection {
+    /// Parses the text of a selection file
+    /// Fails on a newer version, lines that aren't rules or comments are returned as problems and left out
+    pub fn parse(text: &str) -> Result<(Self, Vec<String>), String> {
+        let mut selection = Selection { version: 1, ..Default::default() };
+        let mut problems = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if i == 0 {
+                if let Some(v) = line.strip_prefix(HEADER) {
+                    selection.version = v.trim().parse().map_err(|_e| format!("Malformed header {:?}", line))?;
+                    if selection.version > VERSION {
+                        return Err(format!("Selection version {} is newer than supported ({})", selection.version, VERSION));
+                    }
+                    continue;
+                }
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            if line.starts_with('#') {
+                selection.comments.push(line.to_string());
+                continue;
+            }
+            match path_rule(line) {
+                Some((_, "")) => problems.push(format!("Line {}: no path given - {}", i + 1, line)),
+                Some(_) => selection.rules.push(line.to_string()),
+                None if patterns::is_rule(line) => selection.rules.push(line.to_string()),
+                None => problems.push(format!("Line {}: not a rule - {}", i + 1, line)),
+            }
+        }
+        Ok((selection, problems))
+    }
+
+    /// Reads a selection file, problems are printed and the lines skipped
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{:?}", e))?;
+        let (selection, problems) = Self::parse(&text).map_err(|e| format!("{:?} - {}", path, e))?;
+        for p in problems {
+            println!("Malformed entry in {:?} - {}", path, p);
+        }
+        Ok(selection)
+    }
+
+    /// Like 'read', but a missing file is an empty selection
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        match path.as_ref().exists() {
+            true => Self::read(path),
+            false => Ok(Self::default()),
+        }
+    }
+
+    /// The file contents in the current version
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{}{}\n", HEADER, VERSION);
+        for line in self.comments.iter().chain(self.rules.iter()) {
+            text.push_str(line);
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Writes the selection in the current version
+    /// It is written to a temporary file first, so a failed write doesn't lose the previous selection
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let temp = with_suffix(path, ".tmp");
+        std::fs::write(&temp, self.to_text())
+            .and_then(|_| std::fs::rename(&temp, path))
+            .map_err(|e| format!("Failed to write {:?} - {:?}", path, e))
+    }
+
+    /// Paths of the UPLOAD and EXCLUDE rules
+    pub fn paths(&self) -> impl Iterator<Item = (Action, &str)> {
+        self.rules.iter().filter_map(|r| path_rule(r))
+    }
+}
+
+/// Splits an UPLOAD or EXCLUDE line into its action and path, None for other lines
+pub fn path_rule(line: &str) -> Option<(Action, &str)> {
+    line.strip_prefix(UPLOAD).map(|p| (Action::Upload, p))
+        .or_else(|| line.strip_prefix(EXCLUDE).map(|p| (Action::Exclude, p)))
+}
+
+/// Rewrites a selection file of an older version in the current one, keeping a copy of it
+/// Returns whether the file was migrated
+pub fn migrate<P: AsRef<Path>>(path: P) -> Result<bool, String> {
+    let path = path.as_ref();
+    if !path.exists() {
+        return Ok(false);
+    }
+    let selection = Selection::read(path)?;
+    if selection.version == VERSION {
+        return Ok(false);
+    }
+    let copy = with_suffix(path, &format!(".v{}", selection.version));
+    std::fs::copy(path, &copy).map_err(|e| format!("Failed to copy {:?} - {:?}", path, e))?;
+    selection.write(path)?;
+    println!("Updated {:?} to version {}, the old file is kept as {:?}", path, VERSION, copy);
+    Ok(true)
+}
+
+/// Writes the selection in 'from' to 'to', with paths under the home directory relative to it
+/// Returns the number of rules written
+pub fn export<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<usize, String> {
+    let mut selection = Selection::read(from)?;
+    if let Some(home) = home_dir() {
+        for rule in selection.rules.iter_mut() {
+            let (action, path) = match path_rule(rule) {
+                Some(r) => r,
+                None => continue,
+            };
+            if let Some(rest) = path.strip_prefix(home.as_str()).and_then(|r| r.strip_prefix('/')) {
+                *rule = format!("{}~/{}", prefix(action), rest);
+            }
+        }
+    }
+    selection.write(to)?;
+    Ok(selection.rules.len())
+}
+
+/// Replaces the selection in 'to' with the one in 'from', or adds its rules and comments with 'merge'
+/// The file is checked first, nothing is changed if any line is malformed. '~/' is expanded to the home directory
+/// A copy of the previous selection is kept. Returns warnings, e.g. about paths that don't exist here
+pub fn import<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q, merge: bool) -> Result<Vec<String>, String> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    let text = std::fs::read_to_string(from).map_err(|e| format!("Failed to read {:?} - {:?}", from, e))?;
+    let (mut imported, mut problems) = Selection::parse(&text)?;
+    for rule in imported.rules.iter() {
+        if let Some(Err(e)) = patterns::Pattern::parse(rule) {
+            problems.push(e);
+        }
+    }
+    if !problems.is_empty() {
+        return Err(format!("Nothing was imported, {:?} has errors:\n{}", from, problems.join("\n")));
+    }
+
+    let home = home_dir();
+    let mut warnings = vec![];
+    for rule in imported.rules.iter_mut() {
+        let (action, path) = match path_rule(rule) {
+            Some(r) => r,
+            None => continue,
+        };
+        let path = match (path.strip_prefix("~/"), &home) {
+            (Some(rest), Some(home)) => format!("{}/{}", home, rest),
+            (Some(_), None) => return Err("Paths start with '~/', but there is no home directory to expand it to".to_string()),
+            (None, _) => path.to_string(),
+        };
+        if !escape::decode_path(&path).exists() {
+            warnings.push(format!("{} doesn't exist on this computer", path));
+        }
+        *rule = format!("{}{}", prefix(action), path);
+    }
+
+    let mut selection = match merge {
+        true => Selection::load(to)?,
+        false => Selection::default(),
+    };
+    for line in imported.comments {
+        if !selection.comments.contains(&line) {
+            selection.comments.push(line);
+        }
+    }
+    for line in imported.rules {
+        if !selection.rules.contains(&line) {
+            selection.rules.push(line);
+        }
+    }
+    if to.exists() {
+        let copy = with_suffix(to, ".bak");
+        std::fs::copy(to, &copy).map_err(|e| format!("Failed to copy {:?} - {:?}", to, e))?;
+        println!("The previous selection is kept as {:?}", copy);
+    }
+    selection.write(to)?;
+    Ok(warnings)
+}
+
+// Start of a line with the given action
+fn prefix(action: Action) -> &'static str {
+    match action {
+        Action::Upload => UPLOAD,
+        Action::Exclude => EXCLUDE,
+    }
+}
+
+// The home directory as written in a selection file, without the trailing '/'
+fn home_dir() -> Option<String> {
+    let var = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+    let home = std::env::var_os(var)?;
+    let home = escape::encode_path(PathBuf::from(home));
+    match home.trim_end_matches('/') {
+        "" => None,
+        h => Some(h.to_string()),
+    }
+}
+
+// 'path' with 'suffix' added to its file name
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An empty directory for a test
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("backup_gui_selection_{}_{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn parse_and_to_text_round_trip() {
+        let text = "# backup_gui selection v2\n# Work laptop\nUPLOAD /home/user/\nEXCLUDE /home/user/tmp/\nGLOB **/node_modules/\nREGEX \\.o$\n";
+        let (selection, problems) = Selection::parse(text).unwrap();
+        assert!(problems.is_empty());
+        assert_eq!(selection.version, VERSION);
+        assert_eq!(selection.comments, vec!["# Work laptop"]);
+        assert_eq!(selection.rules.len(), 4);
+        assert_eq!(selection.paths().collect::<Vec<_>>(), vec![(Action::Upload, "/home/user/"), (Action::Exclude, "/home/user/tmp/")]);
+        assert_eq!(selection.to_text(), text);
+    }
+
+    #[test]
+    fn malformed_lines_and_newer_versions() {
+        let (selection, problems) = Selection::parse("UPLOAD /a/\r\n\nUPLOAD \nDELETE /b/\n").unwrap();
+        assert_eq!(selection.version, 1);
+        assert_eq!(selection.rules, vec!["UPLOAD /a/"]);
+        assert_eq!(problems.len(), 2);
+        assert!(Selection::parse(&format!("{}{}\n", HEADER, VERSION + 1)).is_err());
+    }
+
+    #[test]
+    fn migrates_version_1() {
+        let dir = temp_dir("migrate");
+        let path = dir.join("backuplist.dat");
+        std::fs::write(&path, "UPLOAD /home/user/\nEXCLUDE /home/user/tmp/\n").unwrap();
+
+        assert!(migrate(&path).unwrap());
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text, format!("{}{}\nUPLOAD /home/user/\nEXCLUDE /home/user/tmp/\n", HEADER, VERSION));
+        assert_eq!(std::fs::read_to_string(dir.join("backuplist.dat.v1")).unwrap(), "UPLOAD /home/user/\nEXCLUDE /home/user/tmp/\n");
+        // Already in the current version
+        assert!(!migrate(&path).unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn import_merge_skips_duplicates() {
+        let dir = temp_dir("merge");
+        let root = escape::encode_path(&dir);
+        let to = dir.join("backuplist.dat");
+        let from = dir.join("shared.dat");
+        std::fs::write(&to, format!("{}{}\n# Mine\nUPLOAD {}/a/\nGLOB *.tmp\n", HEADER, VERSION, root)).unwrap();
+        std::fs::write(&from, format!("# Mine\n# Shared\nUPLOAD {}/a/\nUPLOAD {}/b/\nGLOB *.tmp\nREGEX \\.o$\n", root, root)).unwrap();
+        std::fs::create_dir_all(dir.join("a")).unwrap();
+
+        let warnings = import(&from, &to, true).unwrap();
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        let merged = Selection::read(&to).unwrap();
+        assert_eq!(merged.comments, vec!["# Mine", "# Shared"]);
+        assert_eq!(merged.rules, vec![
+            format!("UPLOAD {}/a/", root),
+            "GLOB *.tmp".to_string(),
+            format!("UPLOAD {}/b/", root),
+            "REGEX \\.o$".to_string(),
+        ]);
+        assert!(dir.join("backuplist.dat.bak").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
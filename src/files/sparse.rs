@@ -0,0 +1,212 @@
+// Sparse files (Linux only)
+//
+// Holes in a sparse file (e.g. a VM disk image) read as zeros but take no space, reading them would upload gigabytes of zeros
+// The data extents are found with SEEK_DATA/SEEK_HOLE, and only those are read, back to back (see 'DataReader')
+// The extents are recorded with the file: in the manifest (ManifestEntry::sparse) and, for plain objects,
+// in the INFO_KEY file info. Restore writes the data back at the extents and sets the size, leaving the holes (see 'HoleWriter')
+//
+// Small holes are not worth the bookkeeping, and the number of extents is limited so they fit in a header
+// Hashes (ManifestEntry::sha1) and chunks of a sparse file are of its data only
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use nanoserde::{DeJson, SerJson};
+
+/// File info key holding the size and extents of a sparse file, e.g. "1073741824:0+4096,1048576+8192"
+pub const INFO_KEY: &str = "b2gui-sparse";
+
+// Holes smaller than this are read like data
+const MIN_HOLE: u64 = 64 * 1024;
+// If there are more extents, the smallest holes are read like data as well
+const MAX_EXTENTS: usize = 64;
+
+/// A region of data in a sparse file
+#[derive(Debug, Clone, Copy, PartialEq, DeJson, SerJson)]
+pub struct Extent {
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Total length of the extents, i.e. the amount of data that is stored
+pub fn data_len(extents: &[Extent]) -> u64 {
+    extents.iter().map(|e| e.len).sum()
+}
+
+/// The data extents of the file at 'path', None if it has no holes worth skipping
+pub fn data_extents(path: &Path, size: u64) -> Option<Vec<Extent>> {
+    let extents = merge(sys::extents(path, size)?);
+    match data_len(&extents) + MIN_HOLE <= size {
+        true => Some(extents),
+        false => None,
+    }
+}
+
+// Fills holes smaller than MIN_HOLE, and the smallest ones beyond MAX_EXTENTS
+fn merge(extents: Vec<Extent>) -> Vec<Extent> {
+    let mut holes: Vec<(u64, usize)> = extents.windows(2)
+        .enumerate()
+        .map(|(i, w)| (w[1].offset - (w[0].offset + w[0].len), i))
+        .filter(|(len, _)| *len >= MIN_HOLE)
+        .collect();
+    holes.sort_by(|a, b| b.cmp(a));
+    holes.truncate(MAX_EXTENTS - 1);
+    // Indexes of the extents after which a hole is kept
+    let kept: std::collections::HashSet<usize> = holes.into_iter().map(|(_, i)| i).collect();
+
+    let mut merged: Vec<Extent> = vec![];
+    for (i, e) in extents.into_iter().enumerate() {
+        match merged.last_mut() {
+            Some(last) if !kept.contains(&(i - 1)) => last.len = e.offset + e.len - last.offset,
+            _ => merged.push(e),
+        }
+    }
+    merged
+}
+
+/// Writes the file info value for the extents, see INFO_KEY
+pub fn encode(size: u64, extents: &[Extent]) -> String {
+    let extents: Vec<String> = extents.iter().map(|e| format!("{}+{}", e.offset, e.len)).collect();
+    format!("{}:{}", size, extents.join(","))
+}
+
+/// Reads a file info value written by 'encode', returns the size and extents
+pub fn decode(value: &str) -> Option<(u64, Vec<Extent>)> {
+    let mut parts = value.splitn(2, ':');
+    let size = parts.next()?.parse().ok()?;
+    let mut extents = vec![];
+    for e in parts.next()?.split(',').filter(|e| !e.is_empty()) {
+        let mut nums = e.splitn(2, '+');
+        let offset = nums.next()?.parse().ok()?;
+        let len = nums.next()?.parse().ok()?;
+        extents.push(Extent { offset, len });
+    }
+    Some((size, extents))
+}
+
+/// Reads only the data extents of a file, one after the other
+pub struct DataReader {
+    file: File,
+    extents: Vec<Extent>,
+    // Extent being read, and how much of it is left
+    current: usize,
+    remaining: u64,
+}
+
+impl DataReader {
+    pub fn new(file: File, extents: Vec<Extent>) -> Self {
+        DataReader { file, extents, current: 0, remaining: 0 }
+    }
+}
+
+impl Read for DataReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            let extent = match self.extents.get(self.current) {
+                Some(e) => *e,
+                None => return Ok(0),
+            };
+            self.current += 1;
+            self.file.seek(SeekFrom::Start(extent.offset))?;
+            self.remaining = extent.len;
+        }
+        let max = (buf.len() as u64).min(self.remaining) as usize;
+        let read = self.file.read(&mut buf[..max])?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File shrank while being read"));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Writes data read by a 'DataReader' back at its extents
+/// With no extents, everything is written sequentially, so dense files can be written through it as well
+/// The holes at the end aren't written, call 'File::set_len' with the size of the file afterwards
+pub struct HoleWriter<'a> {
+    file: &'a mut File,
+    extents: &'a [Extent],
+    current: usize,
+    remaining: u64,
+}
+
+impl<'a> HoleWriter<'a> {
+    pub fn new(file: &'a mut File, extents: &'a [Extent]) -> Self {
+        HoleWriter { file, extents, current: 0, remaining: 0 }
+    }
+}
+
+impl<'a> Write for HoleWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.extents.is_empty() {
+            return self.file.write(buf);
+        }
+        while self.remaining == 0 {
+            let extent = match self.extents.get(self.current) {
+                Some(e) => *e,
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData, "More data than the extents hold")),
+            };
+            self.current += 1;
+            self.file.seek(SeekFrom::Start(extent.offset))?;
+            self.remaining = extent.len;
+        }
+        let max = (buf.len() as u64).min(self.remaining) as usize;
+        let written = self.file.write(&buf[..max])?;
+        self.remaining -= written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    use super::Extent;
+
+    // Walks the file with SEEK_DATA and SEEK_HOLE, None if the file system doesn't support them or there are no holes
+    pub fn extents(path: &Path, size: u64) -> Option<Vec<Extent>> {
+        let file = std::fs::File::open(path).ok()?;
+        // Files that take as many blocks as their size can't have holes
+        if file.metadata().ok()?.blocks() * 512 >= size {
+            return None;
+        }
+        let fd = file.as_raw_fd();
+        let mut extents = vec![];
+        let mut offset = 0i64;
+        while (offset as u64) < size {
+            let start = unsafe { libc::lseek(fd, offset, libc::SEEK_DATA) };
+            if start < 0 {
+                // ENXIO: only a hole is left
+                match std::io::Error::last_os_error().raw_os_error() {
+                    Some(libc::ENXIO) => break,
+                    _ => return None,
+                }
+            }
+            let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+            if end < 0 {
+                return None;
+            }
+            extents.push(Extent { offset: start as u64, len: (end - start) as u64 });
+            offset = end;
+        }
+        Some(extents)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::path::Path;
+
+    use super::Extent;
+
+    pub fn extents(_path: &Path, _size: u64) -> Option<Vec<Extent>> {
+        None
+    }
+}
@@ -392,13 +392,16 @@ impl GuiProgram {
             transform,
             sc_desc: sc_desc.clone(),
             state_manager: ui::StateManager {
-                fileroot: files::get_roots().unwrap(),
+                fileroot: files::get_roots(cfg.respect_gitignore).unwrap(),
                 config: cfg,
                 strings,
                 text_handler: Mutex::new(text::TextHandler::init(&device, sc_desc.format)),
                 scroll: 0.0,
                 state: start_state,
                 upload_state: Default::default(),
+                purge: Default::default(),
+                diff: Default::default(),
+                rules: Default::default(),
                 status_message: None,
                 status_channel_rx: rx,
                 status_channel_tx: tx,
@@ -463,11 +466,22 @@ impl GuiProgram {
                     }
                 }
             },
+            winit::event::WindowEvent::ReceivedCharacter(c) => {
+                if let UIState::Rules = self.state_manager.state {
+                    ui::rules::handle_char(self, c);
+                }
+            },
             winit::event::WindowEvent::MouseWheel {
                 delta: winit::event::MouseScrollDelta::LineDelta(_, y),
                 ..
             } => {
-                let max = filetree::compute_max_scroll(self);
+                let max = match self.state_manager.state {
+                    UIState::Advanced => ui::advanced::compute_max_scroll(self),
+                    UIState::Diff => ui::diff::compute_max_scroll(self),
+                    UIState::Rules => ui::rules::compute_max_scroll(self),
+                    UIState::Purge => ui::purge::compute_max_scroll(self),
+                    _ => filetree::compute_max_scroll(self),
+                };
                 self.state_manager.scroll(y, max);
             },
             winit::event::WindowEvent::MouseInput {device_id: _, state, button, modifiers: _} => {
@@ -482,7 +496,11 @@ impl GuiProgram {
                         UIState::FileTree => ui::filetree::handle_click(self, but),
                         UIState::Main => ui::mainmenu::handle_click(self),
                         UIState::Options => ui::options::handle_click(self),
+                        UIState::Advanced => ui::advanced::handle_click(self),
                         UIState::Consent => ui::consent::handle_click(self),
+                        UIState::Diff => ui::diff::handle_click(self, but),
+                        UIState::Rules => ui::rules::handle_click(self),
+                        UIState::Purge => ui::purge::handle_click(self),
                         _ => None,
                     };
                     if let Some(state) = state {
@@ -517,7 +535,10 @@ impl GuiProgram {
             UIState::Upload => crate::ui::upload::render(self, frame, device),
             UIState::Purge => crate::ui::purge::render(self, frame, device),
             UIState::Options => crate::ui::options::render(self, frame, device),
+            UIState::Advanced => crate::ui::advanced::render(self, frame, device),
             UIState::Consent => crate::ui::consent::render(self, frame, device),
+            UIState::Diff => crate::ui::diff::render(self, frame, device),
+            UIState::Rules => crate::ui::rules::render(self, frame, device),
         }
 
     }
@@ -525,6 +546,7 @@ impl GuiProgram {
     // Saves the config when exiting
     pub fn save_config(&mut self) {
         self.state_manager.strings.destring(&mut self.state_manager.config);
+        crate::files::apply_config(&self.state_manager.config);
         let json = SerJson::serialize_json(&self.state_manager.config);
         std::fs::write("config.cfg",json).unwrap();
     }
@@ -6,7 +6,24 @@ use gui::GuiProgram;
 mod text;
 mod ui;
 mod files;
+mod b2;
+mod cli;
+mod restore;
+mod purge;
+mod retention;
+mod migrate;
 
 fn main() {
+    // Selections from before backuplist.dat had a version are rewritten in the current format, see files::selection
+    if let Err(e) = files::selection::migrate("backuplist.dat") {
+        println!("Failed to update backuplist.dat - {}", e);
+    }
+
+    // Any arguments means we're running a command instead of the GUI, see cli.rs
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args));
+    }
+
     framework::run("Backup GUI");
 }
\ No newline at end of file
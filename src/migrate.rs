@@ -0,0 +1,134 @@
+// Moving backups under our remote prefix, see files::naming
+//
+// Files backed up before remote prefixes existed (or under another prefix) are copied on the server with b2_copy_file,
+// so the next upload finds them instead of uploading everything again
+// Like purge, this is done in two steps: 'plan' lists what would be copied, 'execute' copies it
+// The originals are left alone, they can be hidden once the next upload has completed
+//
+// From the top of the bucket (an empty prefix), only files under the selected paths and the program's own objects are
+// copied, as other computers may back up into the same bucket
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use raze::api::B2Auth;
+use reqwest::blocking::Client;
+
+use crate::files::manifest;
+use crate::files::naming::{Naming, Scope};
+use crate::ui::GUIConfig;
+
+/// A file that would be copied
+#[derive(Debug, Clone)]
+pub struct Copy {
+    pub source_id: String,
+    // Names in the bucket
+    pub from: String,
+    pub to: String,
+    pub size: u64,
+}
+
+/// What migrating would copy, along with the session needed to carry it out
+pub struct MigrationPlan {
+    // Sorted by name
+    pub copies: Vec<Copy>,
+    // Number of files that already exist under our prefix with the same content
+    pub existing: usize,
+    client: Client,
+    auth: B2Auth,
+}
+
+impl MigrationPlan {
+    /// Total size of the files that would be copied
+    pub fn size(&self) -> u64 {
+        self.copies.iter().map(|c| c.size).sum()
+    }
+
+    pub fn print(&self) {
+        for c in self.copies.iter() {
+            println!("{} -> {}  {}", c.from, c.to, manifest::format_size(c.size));
+        }
+        println!("{} files would be copied ({}), {} are already there", self.copies.len(),
+                 manifest::format_size(self.size()), self.existing);
+    }
+}
+
+/// Works out which files under the remote prefix 'from' would be copied under ours, without changing anything
+pub fn plan(cfg: &GUIConfig, from: &str) -> Result<MigrationPlan, String> {
+    let source = Naming::new(from);
+    let target = Naming::from_config(cfg);
+    if source.prefix() == target.prefix() {
+        return Err(format!("Files are already stored under {:?}", target.prefix()));
+    }
+    // Files are copied under the same name, so the source's names (without path mappings) are what's selected
+    let scope = Scope::new(&source, &manifest::read_rules("backuplist.dat"), &[]);
+
+    let client = reqwest::blocking::Client::builder().timeout(Duration::from_secs_f32(30.0)).build().unwrap();
+    let keystring = format!("{}:{}", cfg.app_key_id, cfg.app_key);
+    let auth = match raze::api::b2_authorize_account(&client, keystring) {
+        Ok(a) => a,
+        Err(_e) => return Err("Authentication Failed".to_string()),
+    };
+    let files = match raze::util::list_all_files(&client, &auth, &cfg.bucket_id, 1000) {
+        Ok(f) => f,
+        Err(e) => {
+            println!("Failed to get remote files - {:?}", e);
+            return Err("Failed talking to B2 - Check your Bucket ID".to_string())
+        },
+    };
+    println!("Collected remote files");
+
+    // Files already under our prefix, by relative name
+    let existing_files: HashMap<&str, (u64, Option<&String>)> = files.iter()
+        .filter_map(|f| target.local(&f.file_name).map(|n| (n, (f.content_length, f.content_sha1.as_ref()))))
+        .collect();
+
+    // Other computers' files are under the source prefix too, if it is empty
+    let others = source.other_prefixes(files.iter().map(|f| f.file_name.as_str()));
+
+    let mut copies = vec![];
+    let mut existing = 0;
+    for f in files.iter() {
+        let name = match source.local(&f.file_name) {
+            Some(n) if !others.iter().any(|p| n.starts_with(p.as_str())) => n,
+            _ => continue,
+        };
+        // Our own files may be under the source prefix too, e.g. when it is empty
+        if target.local(&f.file_name).is_some() {
+            continue;
+        }
+        if source.prefix().is_empty() && !scope.contains(name) && !name.starts_with(manifest::REPO_PREFIX) {
+            continue;
+        }
+        if existing_files.get(name) == Some(&(f.content_length, f.content_sha1.as_ref())) {
+            existing += 1;
+            continue;
+        }
+        if let Some(id) = &f.file_id {
+            copies.push(Copy { source_id: id.clone(), from: f.file_name.clone(), to: target.remote(name), size: f.content_length });
+        }
+    }
+    copies.sort_by(|a, b| a.from.cmp(&b.from));
+    println!("Planned copying {} files", copies.len());
+
+    Ok(MigrationPlan { copies, existing, client, auth })
+}
+
+/// Copies the files listed in a plan
+/// Returns the number of files that failed to be copied
+pub fn execute(plan: MigrationPlan) -> usize {
+    let client = &plan.client;
+    let auth = &plan.auth;
+    let failed = crate::purge::run_parallel(plan.copies, |c| {
+        println!("Copying {:?} to {:?}", c.from, c.to);
+        for _i in 0..5 {
+            match crate::b2::b2_copy_file(client, auth, &c.source_id, &c.to) {
+                Ok(_) => return true,
+                Err(e) => println!("Err {:?}, retrying {:?}", e, c.from),
+            }
+        }
+        false
+    });
+    println!("Done migrating");
+    failed
+}
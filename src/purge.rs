@@ -0,0 +1,525 @@
+// Purging gets rid of files in the bucket that are no longer needed, by hiding them
+// What happens to hidden files afterwards depends on the lifecycle rules of the bucket
+//
+// A purge is done in two steps, so nothing is hidden without being reviewed first:
+// 'plan' compares the bucket with the local files and lists what would be purged, without changing anything
+// 'execute' then purges the entries of the plan that are still selected
+//
+// A plan can come with warnings, e.g. when a selected directory is missing because its drive isn't mounted
+// Its files would look deleted, so the purge needs an explicit override in that case, see 'PurgePlan::warnings'
+//
+// Files are only purged once they've been missing locally for the grace period (GUIConfig::purge_grace_days)
+// When they were first seen missing is recorded locally, see files::missing
+// In the chunked format the same goes for chunks no manifest references
+//
+// Purges are logged to a local journal, the last one can be undone with 'undo_last', see files::journal
+// Unless the pack index was uploaded again since: the new index no longer lists the packed files the purge removed
+//
+// Only files under our remote prefix (see files::naming), and in there only those under the selected paths
+// (the UPLOAD rules of backuplist.dat) and GUIConfig::purge_prefixes are considered,
+// so other machines or manually uploaded files sharing the bucket are left alone
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use raze::api::B2Auth;
+use reqwest::blocking::Client;
+use scoped_pool::Pool;
+
+use crate::files::{chunked, escape, manifest, mounts, packed, selection, Action};
+use crate::files::filters::Filters;
+use crate::files::journal::{self, Journal, JournalEntry};
+use crate::files::missing::{self, MissingFiles};
+use crate::files::naming::{Naming, Scope};
+use crate::files::packed::PackIndex;
+use crate::files::patterns::Patterns;
+use crate::retention::Policy;
+use crate::ui::{GUIConfig, RepositoryMode};
+
+/// How an entry is purged
+/// Object: the object is hidden
+/// Packed: the file is removed from the pack index, packs that are no longer used are hidden afterwards
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ItemKind {
+    Object,
+    Packed,
+}
+
+/// A single entry of a purge plan
+#[derive(Debug, Clone)]
+pub struct PurgeItem {
+    pub name: String,
+    pub size: u64,
+    pub kind: ItemKind,
+    // Only selected entries are purged, all are selected initially
+    pub selected: bool,
+}
+
+impl PurgeItem {
+    /// Directory the entry is in, including the trailing '/', or "" for the top level
+    pub fn dir(&self) -> &str {
+        match self.name.rfind('/') {
+            Some(i) => &self.name[..i+1],
+            None => "",
+        }
+    }
+}
+
+/// What a purge would do, along with the session needed to carry it out
+pub struct PurgePlan {
+    // Sorted by name, so entries in the same directory are next to each other
+    pub items: Vec<PurgeItem>,
+    // Number of files in the bucket when the plan was made
+    pub remote_count: usize,
+    // Number of missing files (or unused chunks) that are kept, as they haven't been missing for the grace period yet
+    pub waiting: usize,
+    pub grace_days: u32,
+    // Prefixes purge was limited to, empty in the chunked format
+    pub scope: Vec<String>,
+    // Selected paths that are missing, empty or couldn't be read
+    problems: Vec<String>,
+    // Limits on the number of files to purge, see GUIConfig
+    max_percent: u32,
+    max_count: u32,
+    client: Client,
+    auth: B2Auth,
+    bucket_id: String,
+    naming: Naming,
+    // Pack index at the time of planning, only set if there are packed files to remove
+    index: Option<PackIndex>,
+}
+
+impl PurgePlan {
+    /// Number of selected entries and their total size
+    pub fn selected(&self) -> (usize, u64) {
+        self.items.iter()
+            .filter(|i| i.selected)
+            .fold((0, 0), |(n, size), i| (n + 1, size + i.size))
+    }
+
+    /// Number of selected entries that are backups (manifests) the retention rules no longer keep, see chunked::garbage
+    pub fn retired(&self) -> usize {
+        self.items.iter()
+            .filter(|i| i.selected && i.name.starts_with(manifest::MANIFEST_PREFIX))
+            .count()
+    }
+
+    /// Reasons the purge may hide files that still exist, empty if it looks safe
+    /// Limits are checked against the selected entries, so unticking entries can resolve them
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = self.problems.clone();
+        let (count, _size) = self.selected();
+        if self.max_count > 0 && count > self.max_count as usize {
+            warnings.push(format!("{} files would be purged, the limit is {}", count, self.max_count));
+        }
+        if self.max_percent > 0 && count * 100 > self.max_percent as usize * self.remote_count {
+            warnings.push(format!("{}% of the files in the bucket would be purged, the limit is {}%",
+                                  count * 100 / self.remote_count.max(1), self.max_percent));
+        }
+        warnings
+    }
+
+    /// Message for a plan without any entries
+    pub fn nothing_to_purge(&self) -> String {
+        match self.waiting {
+            0 => "Nothing to purge".to_string(),
+            n => format!("Nothing to purge yet, {} files have been missing for less than {} days", n, self.grace_days),
+        }
+    }
+
+    /// Prints the plan grouped by directory, e.g. for a dry run
+    pub fn print(&self) {
+        for prefix in self.scope.iter() {
+            println!("Considering files under {:?}", prefix);
+        }
+        let mut dir = None;
+        for item in self.items.iter() {
+            if dir != Some(item.dir()) {
+                dir = Some(item.dir());
+                println!("{}", if item.dir().is_empty() { "/" } else { item.dir() });
+            }
+            let packed = match item.kind {
+                ItemKind::Object => "",
+                ItemKind::Packed => "  (packed)",
+            };
+            println!("  {}  {}{}", &item.name[item.dir().len()..], manifest::format_size(item.size), packed);
+        }
+        let (count, size) = self.selected();
+        println!("{} of {} files in the bucket would be purged ({})", count, self.remote_count, manifest::format_size(size));
+        if self.retired() > 0 {
+            println!("{} of them are old backups the retention rules no longer keep", self.retired());
+        }
+        if self.waiting > 0 {
+            println!("{} missing files are kept until they've been missing for {} days", self.waiting, self.grace_days);
+        }
+        for w in self.warnings() {
+            println!("Warning: {}", w);
+        }
+    }
+}
+
+/// Works out what a purge would do, without changing anything in the bucket
+pub fn plan(cfg: &GUIConfig) -> Result<PurgePlan, String> {
+    let naming = Naming::from_config(cfg);
+    // In the chunked format, the latest manifest already describes the local files
+    // A missing selected path means its files are missing from the latest manifest as well, so it's checked in both formats
+    let rules = manifest::read_rules("backuplist.dat");
+    let mut problems = check_roots(&rules, cfg.one_file_system);
+    let mut scope = Scope::default();
+    let local_files = match cfg.repository_mode {
+        RepositoryMode::Files => match local_file_names(cfg, &naming) {
+            Some((files, unreadable)) => {
+                scope = Scope::new(&naming, &rules, &cfg.purge_prefixes);
+                for dir in unreadable {
+                    problems.push(format!("{:?} couldn't be read", dir));
+                }
+                Some(files)
+            },
+            None => return Err("Got no files for upload, make sure you've selected some".to_string()),
+        },
+        RepositoryMode::Chunked => None,
+    };
+
+    let client = reqwest::blocking::Client::builder().timeout(Duration::from_secs_f32(30.0)).build().unwrap();
+    let keystring = format!("{}:{}", cfg.app_key_id, cfg.app_key);
+    let auth = match raze::api::b2_authorize_account(&client, keystring) {
+        Ok(a) => a,
+        Err(_e) => return Err("Authentication Failed".to_string()),
+    };
+
+    // Get list of files stored under our prefix
+    let remote_files = match raze::util::list_all_files(&client, &auth, &cfg.bucket_id, 1000) {
+        Ok(f) => naming.strip_all(f),
+        Err(e) => {
+            println!("Failed to get remote files - {:?}", e);
+            return Err("Failed talking to B2 - Check your Bucket ID".to_string())
+        },
+    };
+    println!("Collected remote files");
+    let sizes: HashMap<&str, u64> = remote_files.iter().map(|f| (f.file_name.as_str(), f.content_length)).collect();
+
+    let mut index = None;
+    let mut items = vec![];
+    let waiting;
+    match local_files {
+        // Compare the two lists:
+        // Check each file in the cloud; if it isn't in the upload list, queue it for hiding
+        // Files the program stores for itself (manifests, chunks) are left alone
+        // Packed files that are no longer present locally are removed from the pack index,
+        // and packs no longer used as well as plain files that have since been packed are hidden
+        Some(local_files) => {
+            let pack_index = match packed::latest_index(&client, &auth, &remote_files) {
+                Ok(i) => i,
+                Err(e) => {
+                    println!("Failed to read the pack index - {}", e);
+                    return Err("Failed to read the pack index".to_string())
+                },
+            };
+            // Files outside the scope are treated like local files, so they're never purged
+            let is_local = |name: &str| !scope.contains(name) || local_files.binary_search_by(|l| l.as_str().cmp(name)).is_ok();
+
+            // Files that are no longer present locally, stored as plain objects or packed
+            let mut missing: Vec<String> = remote_files.iter()
+                .map(|f| &f.file_name)
+                .filter(|n| !n.starts_with(manifest::REPO_PREFIX) && !is_local(n))
+                .cloned()
+                .collect();
+            if let Some(i) = &pack_index {
+                missing.extend(i.files.iter().map(|f| &f.name).filter(|n| !is_local(n)).cloned());
+            }
+
+            // Only files that have been missing for the grace period are purged
+            let (w, expired) = grace_period(cfg, missing, problems.is_empty());
+            waiting = w;
+
+            let mut hide: Vec<String> = remote_files.iter()
+                .map(|f| &f.file_name)
+                .filter(|n| !n.starts_with(manifest::REPO_PREFIX) && !is_local(n) && expired(n))
+                .cloned()
+                .collect();
+            if let Some(pack_index) = pack_index {
+                hide.extend(packed::garbage(&pack_index, &remote_files).into_iter().filter(|n| expired(n)));
+                for f in pack_index.files.iter() {
+                    if !is_local(&f.name) && expired(&f.name) {
+                        items.push(PurgeItem { name: f.name.clone(), size: f.size, kind: ItemKind::Packed, selected: true });
+                    }
+                }
+                if !items.is_empty() {
+                    index = Some(pack_index);
+                }
+            }
+            hide.sort();
+            hide.dedup();
+            for name in hide {
+                let size = sizes.get(name.as_str()).copied().unwrap_or(0);
+                items.push(PurgeItem { name, size, kind: ItemKind::Object, selected: true });
+            }
+        },
+        // Garbage collect manifests the retention policy doesn't keep, and chunks no kept manifest references
+        // Like missing files, unused chunks are only hidden once they've been unused for the grace period
+        None => {
+            let now = match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+                Ok(d) => d.as_millis() as u64,
+                Err(_e) => 0,
+            };
+            let garbage = chunked::garbage(&client, &auth, &remote_files, &Policy::from_config(cfg), now).map_err(|e| {
+                println!("Failed to find unused chunks - {}", e);
+                e
+            })?;
+            let (w, expired) = grace_period(cfg, garbage.clone(), problems.is_empty());
+            waiting = w;
+            for name in garbage.into_iter().filter(|n| expired(n)) {
+                let size = sizes.get(name.as_str()).copied().unwrap_or(0);
+                items.push(PurgeItem { name, size, kind: ItemKind::Object, selected: true });
+            }
+        },
+    }
+    items.sort_by(|a, b| a.name.cmp(&b.name));
+    println!("Planned purge of {} files", items.len());
+
+    Ok(PurgePlan {
+        items,
+        remote_count: remote_files.len(),
+        waiting,
+        grace_days: cfg.purge_grace_days,
+        scope: scope.prefixes(),
+        problems,
+        max_percent: cfg.purge_max_percent,
+        max_count: cfg.purge_max_count,
+        client,
+        auth,
+        bucket_id: cfg.bucket_id.clone(),
+        naming,
+        index,
+    })
+}
+
+/// Purges the selected entries of a plan
+/// Every change is logged to the purge journal, so it can be undone with 'undo_last'
+/// Returns the number of files that failed to be hidden
+pub fn execute(plan: PurgePlan) -> Result<usize, String> {
+    let client = &plan.client;
+    let auth = &plan.auth;
+    let bid = &plan.bucket_id;
+    let naming = &plan.naming;
+    let journal = Journal::start().map_err(|e| format!("Failed to open {} - {:?}", journal::JOURNAL_FILE, e))?;
+    let journal = &journal;
+
+    let mut hide_list: Vec<String> = plan.items.iter()
+        .filter(|i| i.selected && i.kind == ItemKind::Object)
+        .map(|i| i.name.clone())
+        .collect();
+    if let Some(index) = &plan.index {
+        let names: HashSet<&str> = plan.items.iter()
+            .filter(|i| i.selected && i.kind == ItemKind::Packed)
+            .map(|i| i.name.as_str())
+            .collect();
+        let (uploaded, mut unused) = packed::remove_files(client, auth, bid, naming, index, &names)?;
+        if let Some(id) = uploaded.and_then(|f| f.file_id) {
+            journal.record(&naming.remote(packed::INDEX_NAME), &id);
+        }
+        hide_list.append(&mut unused);
+    }
+    println!("Ready to hide {} files", hide_list.len());
+
+    let failed = run_parallel(hide_list, |file| {
+        println!("Hiding {:?}", file);
+        let name = naming.remote(file);
+        for _i in 0..5 {
+            match raze::api::b2_hide_file(client, auth, bid, &name) {
+                Ok(f) => { // Return on success = do not retry
+                    if let Some(id) = f.file_id {
+                        journal.record(&name, &id);
+                    }
+                    return true
+                },
+                Err(e) => println!("Err {:?}, retrying {:?}", e, file), // Continue on failure = retry
+            }
+        }
+        false
+    });
+
+    println!("Done purging");
+    Ok(failed)
+}
+
+/// Undoes the most recent purge in the journal, by deleting the file versions it created
+/// Entries that fail to be deleted are kept, so undoing again retries them
+/// Returns the number of entries undone
+pub fn undo_last(cfg: &GUIConfig) -> Result<usize, String> {
+    let entries = journal::read();
+    let last = match entries.last() {
+        Some(e) => e.purge,
+        None => return Err("There is no purge to undo".to_string()),
+    };
+    let (undo, mut keep): (Vec<JournalEntry>, Vec<JournalEntry>) = entries.into_iter().partition(|e| e.purge == last);
+
+    let client = reqwest::blocking::Client::builder().timeout(Duration::from_secs_f32(30.0)).build().unwrap();
+    let keystring = format!("{}:{}", cfg.app_key_id, cfg.app_key);
+    let auth = match raze::api::b2_authorize_account(&client, keystring) {
+        Ok(a) => a,
+        Err(_e) => return Err("Authentication Failed".to_string()),
+    };
+
+    // Deleting the index the purge wrote would leave the newer one in place, so the packed files wouldn't come back
+    let index_name = Naming::from_config(cfg).remote(packed::INDEX_NAME);
+    if let Some(e) = undo.iter().find(|e| e.name == index_name) {
+        let latest = raze::api::b2_list_file_names(&client, &auth, &cfg.bucket_id, &e.name, 1)
+            .map_err(|e| format!("Failed to look up the pack index - {:?}", e))?;
+        if latest.files.iter().any(|f| f.file_name == e.name && f.file_id.as_deref() != Some(e.file_id.as_str())) {
+            return Err(format!("The pack index has been uploaded again since the purge of {}, so it can't be undone. \
+                                Remove its entries from {} to undo older purges", manifest::format_timestamp(last), journal::JOURNAL_FILE));
+        }
+    }
+
+    println!("Undoing purge of {} with {} entries", manifest::format_timestamp(last), undo.len());
+    let count = undo.len();
+    let failed_entries = Mutex::new(vec![]);
+    let failed = run_parallel(undo, |entry| {
+        println!("Restoring {:?}", entry.name);
+        for _i in 0..5 {
+            match raze::api::b2_delete_file_version(&client, &auth, &entry.name, &entry.file_id) {
+                Ok(_) => return true,
+                // Already gone, e.g. removed by a lifecycle rule
+                Err(raze::Error::B2Error(e)) if e.code == "file_not_present" => return true,
+                Err(e) => println!("Err {:?}, retrying {:?}", e, entry.name),
+            }
+        }
+        failed_entries.lock().unwrap().push(entry.clone());
+        false
+    });
+
+    keep.append(&mut failed_entries.into_inner().unwrap());
+    if let Err(e) = journal::write(&keep) {
+        println!("Failed to update {} - {:?}", journal::JOURNAL_FILE, e);
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} files could not be restored, undo again to retry", failed, count));
+    }
+    Ok(count)
+}
+
+/// Runs 'f' on every item using a pool of threads
+/// 'f' returns whether it succeeded, the number of failures is returned
+pub fn run_parallel<T: Send, F: Fn(&T) -> bool + Sync>(items: Vec<T>, f: F) -> usize {
+    let queue = Mutex::new(items);
+    let failed = Mutex::new(0);
+
+    let pool = Pool::new(16);
+    pool.scoped(|scope| {
+        for _i in 0..pool.workers() {
+            let (queue, failed, f) = (&queue, &failed, &f);
+            scope.execute(move || {
+                loop {
+                    let item = match queue.lock().unwrap().pop() {
+                        Some(i) => i,
+                        None => break, // Nothing left to do
+                    };
+                    if !f(&item) {
+                        *failed.lock().unwrap() += 1;
+                    }
+                }
+            });
+        }
+    });
+
+    failed.into_inner().unwrap()
+}
+
+// Records when each of 'missing' was first seen missing, and returns how many of them are still within the grace period,
+// along with a check of whether a file may be purged, i.e. it isn't missing or has been missing for the grace period
+// While a selected path is missing ('save' is false), the record isn't saved, so an unplugged drive doesn't start the clock
+fn grace_period(cfg: &GUIConfig, mut missing: Vec<String>, save: bool) -> (usize, impl Fn(&str) -> bool) {
+    missing.sort_unstable();
+    missing.dedup();
+    let now = match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Ok(d) => d.as_millis() as u64,
+        Err(_e) => 0,
+    };
+    let grace = cfg.purge_grace_days as u64 * 24 * 60 * 60 * 1000;
+    let mut record = MissingFiles::load();
+    record.update(&missing, now);
+    if save {
+        if let Err(e) = record.save() {
+            println!("Failed to save {} - {:?}", missing::MISSING_FILE, e);
+        }
+    }
+    let waiting = missing.iter().filter(|n| !record.expired(n, grace, now)).count();
+    let expired = move |name: &str| missing.binary_search_by(|m| m.as_str().cmp(name)).is_err() || record.expired(name, grace, now);
+    (waiting, expired)
+}
+
+// Checks that every path selected for upload exists, and that selected directories aren't empty
+// A missing or empty directory is typically a drive that isn't mounted
+// With 'one_file_system', file systems mounted inside a selected directory aren't backed up unless they're selected too
+fn check_roots(rules: &[String], one_file_system: bool) -> Vec<String> {
+    // Paths are encoded like names in B2, see files::selection
+    let selected: Vec<PathBuf> = rules.iter()
+        .filter_map(|r| match selection::path_rule(r) {
+            Some((Action::Upload, p)) => Some(escape::decode_path(p)),
+            _ => None,
+        })
+        .collect();
+    let mut problems = vec![];
+    if one_file_system {
+        for m in mounts::list().iter().filter(|m| m.point != "/" && !m.is_virtual()) {
+            let point = Path::new(&m.point);
+            if selected.iter().any(|p| p.as_path() == point) {
+                continue;
+            }
+            if let Some(parent) = selected.iter().find(|p| point.starts_with(p)) {
+                problems.push(format!("{:?} is mounted inside {:?}, but only selected file systems are backed up", point, parent));
+            }
+        }
+    }
+    for path in selected.iter() {
+        let path = path.as_path();
+        if !path.exists() {
+            problems.push(format!("{:?} is selected but doesn't exist", path));
+        } else if path.is_dir() {
+            match std::fs::read_dir(path) {
+                Ok(mut r) => if r.next().is_none() {
+                    problems.push(format!("{:?} is selected but empty", path));
+                },
+                Err(e) => problems.push(format!("{:?} couldn't be read - {:?}", path, e)),
+            }
+        }
+    }
+    problems
+}
+
+// Returns the names (relative to the remote prefix) of all files that are selected for upload, sorted,
+// as well as the directories that couldn't be read
+// Returns None if backuplist.dat can't be read
+fn local_file_names(cfg: &GUIConfig, naming: &Naming) -> Option<(Vec<String>, Vec<PathBuf>)> {
+    // Get local files
+    // Make sure the filetree is exactly the stored list
+    let root = crate::files::get_roots(cfg.respect_gitignore).unwrap();
+    match root.deserialize("backuplist.dat", cfg.respect_gitignore) {
+        Ok(_) => {},
+        Err(e) => {
+            println!("Error reading backuplist.dat - Reason: {}", e);
+            return None
+        },
+    }
+    let q: Arc<Mutex<Vec<PathBuf>>> = Arc::new(Mutex::new(vec![]));
+    // No filters, so files they skip aren't purged, see files::filters
+    let unreadable = root.get_files_for_upload(&q, &Patterns::load("backuplist.dat"), &Filters::boundaries_only(cfg));
+
+    // Collect all files that are supposed to be uploaded, named like during the upload
+    // Files with names too long for B2 are stored under a shortened name, which is theirs as well, see files::naming
+    let lf = q.lock().unwrap();
+    let mut local_files = vec![];
+    for name in lf.iter().map(|p| naming.name_for_path(p)) {
+        let object_name = naming.object_name(&name);
+        if object_name != name {
+            local_files.push(object_name);
+        }
+        local_files.push(name);
+    }
+    local_files.sort();
+    println!("Collected local files");
+    Some((local_files, unreadable))
+}
@@ -0,0 +1,361 @@
+// Downloads backed up files from B2 to a local directory
+// Undoes what the upload does to a file, i.e. decompresses it and restores its modification time
+// In the chunked format, files are reassembled from their chunks as listed in the latest manifest
+// Packed files are cut out of their pack, see files::packed
+// Stored symbolic links are recreated as links, see files::links
+// Permissions, ownership, times and extended attributes are applied if they were stored, see files::posix
+// Directories are recreated from the latest manifest, including empty ones, as are hard links between files
+// Sparse files get their holes back, see files::sparse
+// Only files under a single remote prefix are restored, by default our own (see files::naming)
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use raze::api::{B2Auth, B2FileInfo};
+use reqwest::blocking::Client;
+use scoped_pool::Pool;
+
+use crate::files::{chunked, compression, escape, links, manifest, objects, packed, posix, sparse};
+use crate::files::manifest::ManifestEntry;
+use crate::files::naming::Naming;
+use crate::files::packed::PackEntry;
+use crate::files::posix::PosixMeta;
+use crate::files::sparse::HoleWriter;
+use crate::ui::{GUIConfig, RepositoryMode};
+
+/// Restores all files under 'naming' whose name starts with 'prefix' into 'destination'
+/// The name of the file (relative to the remote prefix) is used as the path relative to 'destination'
+pub fn restore(cfg: &GUIConfig, naming: &Naming, prefix: &str, destination: &Path) -> Result<(), String> {
+    let client = reqwest::blocking::Client::builder().timeout(None).build().unwrap();
+    let keystring = format!("{}:{}", cfg.app_key_id, cfg.app_key);
+    let auth = match raze::api::b2_authorize_account(&client, keystring) {
+        Ok(a) => a,
+        Err(_e) => return Err("Authentication failed".to_string()),
+    };
+
+    let files = match raze::util::list_all_files(&client, &auth, &cfg.bucket_id, 1000) {
+        Ok(f) => naming.strip_all(f),
+        Err(e) => return Err(format!("Failed to get remote files - {:?}", e)),
+    };
+
+    let failed = match cfg.repository_mode {
+        RepositoryMode::Files => {
+            // Packed files take precedence over plain files with the same name, see files::packed
+            let index = packed::latest_index(&client, &auth, &files)?;
+            let packed_files: Vec<PackEntry> = match index {
+                Some(i) => i.files.into_iter().filter(|f| f.name.starts_with(prefix)).collect(),
+                None => vec![],
+            };
+            let packed_names: HashSet<&str> = packed_files.iter().map(|f| f.name.as_str()).collect();
+            let mut packs: HashMap<String, Vec<PackEntry>> = HashMap::new();
+            for f in packed_files.iter() {
+                packs.entry(packed::pack_name(&f.pack)).or_default().push(f.clone());
+            }
+            let pack_ids: HashMap<&str, &str> = files.iter()
+                .filter(|f| packs.contains_key(&f.file_name))
+                .filter_map(|f| f.file_id.as_deref().map(|id| (f.file_name.as_str(), id)))
+                .collect();
+
+            // Directories and hard links are only recorded in the manifest, backups from before that was written have none
+            // It also holds the full names of files stored under a shortened name, see files::naming
+            let latest = manifest::latest(&client, &auth, &files)?.map_or(vec![], |m| m.files);
+            let full_names: HashMap<String, &str> = latest.iter()
+                .filter(|f| !f.dir && naming.is_too_long(&f.name))
+                .map(|f| (naming.object_name(&f.name), f.name.as_str()))
+                .collect();
+
+            let queue: Vec<B2FileInfo> = files.iter()
+                .filter(|f| !f.file_name.starts_with(manifest::REPO_PREFIX))
+                .map(|f| match full_names.get(&f.file_name) {
+                    Some(name) => B2FileInfo { file_name: name.to_string(), ..f.clone() },
+                    None => f.clone(),
+                })
+                .filter(|f| f.file_name.starts_with(prefix) && !packed_names.contains(f.file_name.as_str()))
+                .collect();
+            println!("Restoring {} files and {} packed files to {:?}", queue.len(), packed_files.len(), destination);
+            let failed = restore_all(queue, |f| &f.file_name, |f| restore_file(&client, &auth, f, naming, destination));
+            let packs: Vec<(String, Vec<PackEntry>)> = packs.into_iter().collect();
+            let failed = failed + restore_all(packs, |p| &p.0, |p| restore_pack(&client, &auth, &p.0, &p.1, &pack_ids, naming, destination));
+            let (dirs, hard_links): (Vec<ManifestEntry>, Vec<ManifestEntry>) = latest.iter()
+                .filter(|f| (f.dir || f.hardlink.is_some()) && f.name.starts_with(prefix))
+                .cloned()
+                .partition(|f| f.dir);
+            let failed = failed + restore_hard_links(&hard_links, naming, destination);
+            failed + restore_directories(&dirs, naming, destination)
+        },
+        RepositoryMode::Chunked => {
+            let latest = match manifest::latest(&client, &auth, &files)? {
+                Some(m) => m,
+                None => return Err("No manifest found, nothing to restore".to_string()),
+            };
+            // Chunks are downloaded by id, so map their names to ids
+            let chunk_ids: HashMap<String, String> = files.into_iter()
+                .filter(|f| f.file_name.starts_with(chunked::CHUNK_PREFIX))
+                .filter_map(|f| match f.file_id {
+                    Some(id) => Some((f.file_name, id)),
+                    None => None,
+                })
+                .collect();
+            let (dirs, queue): (Vec<ManifestEntry>, Vec<ManifestEntry>) = latest.files.into_iter()
+                .filter(|f| f.name.starts_with(prefix))
+                .partition(|f| f.dir);
+            let (hard_links, queue): (Vec<ManifestEntry>, Vec<ManifestEntry>) = queue.into_iter().partition(|f| f.hardlink.is_some());
+            println!("Restoring {} files to {:?}", queue.len(), destination);
+            let failed = restore_all(queue, |f| &f.name, |f| restore_chunked_file(&client, &auth, f, &chunk_ids, naming, destination));
+            let failed = failed + restore_hard_links(&hard_links, naming, destination);
+            failed + restore_directories(&dirs, naming, destination)
+        },
+    };
+
+    if failed > 0 {
+        Err(format!("Failed to restore {} files", failed))
+    } else {
+        println!("Done restoring");
+        Ok(())
+    }
+}
+
+// Restores every item in 'queue' with 'restore_one' on a pool of threads
+// 'name' gives the name of an item for logging
+// Returns the number of items that failed
+fn restore_all<T, N, F>(queue: Vec<T>, name: N, restore_one: F) -> usize
+    where T: Send, N: Fn(&T) -> &str + Sync, F: Fn(&T) -> Result<(), String> + Sync {
+    let queue = Arc::new(Mutex::new(queue));
+    let failed = Arc::new(Mutex::new(0usize));
+
+    let pool = Pool::new(8);
+    pool.scoped(|scope| {
+        for _i in 0..pool.workers() {
+            let q = queue.clone();
+            let failed = failed.clone();
+            let name = &name;
+            let restore_one = &restore_one;
+            scope.execute(move || {
+                loop {
+                    let item = match q.lock().unwrap().pop() {
+                        Some(i) => i,
+                        None => break,
+                    };
+                    println!("Restoring {:?}", name(&item));
+                    if let Err(e) = restore_one(&item) {
+                        println!("Failed to restore {:?} - {}", name(&item), e);
+                        *failed.lock().unwrap() += 1;
+                    }
+                }
+            });
+        }
+    });
+
+    let failed = *failed.lock().unwrap();
+    failed
+}
+
+// Downloads a single file, decompressing it if needed
+fn restore_file(client: &Client, auth: &B2Auth, file: &B2FileInfo, naming: &Naming, destination: &Path) -> Result<(), String> {
+    let file_id = match &file.file_id {
+        Some(id) => id,
+        None => return Err("File has no id".to_string()),
+    };
+    let target = local_path(destination, naming, &file.file_name)?;
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| format!("{:?}", e))?;
+    }
+
+    // A stored link holds its target, see files::links
+    if file.content_type.as_deref() == Some(links::CONTENT_TYPE) {
+        let data = objects::download(client, auth, file_id)?;
+        return links::create(&String::from_utf8_lossy(&data), &target).map_err(|e| format!("{:?}", e));
+    }
+
+    let download = crate::b2::b2_download_file_by_id(client, auth, file_id).map_err(|e| format!("{:?}", e))?;
+    let file_info = download.file_info;
+    let mut reader = compression::decoder(download.reader, file_info.get(compression::INFO_KEY)).map_err(|e| format!("{:?}", e))?;
+    let mut out = std::fs::File::create(&target).map_err(|e| format!("{:?}", e))?;
+    // Sparse files only hold their data, see files::sparse
+    let sparse = file_info.get(sparse::INFO_KEY).and_then(|v| sparse::decode(v));
+    let extents = sparse.as_ref().map_or(&[][..], |(_, e)| e.as_slice());
+    std::io::copy(&mut reader, &mut HoleWriter::new(&mut out, extents)).map_err(|e| format!("{:?}", e))?;
+    if let Some((size, _)) = sparse {
+        out.set_len(size).map_err(|e| format!("{:?}", e))?;
+    }
+
+    // Restore the modification time, so the next upload does not consider the file changed
+    // Along with the permissions etc. if they were stored, see files::posix
+    if let Some(meta) = PosixMeta::from_file_info(&file_info, client, auth) {
+        drop(out);
+        return posix::apply(&meta, &target);
+    }
+    if let Some(millis) = file_info.get("src_last_modified_millis").and_then(|m| m.parse::<u64>().ok()) {
+        let _ = out.set_modified(UNIX_EPOCH + Duration::from_millis(millis));
+    }
+    Ok(())
+}
+
+// Downloads a pack and restores the given files from it
+fn restore_pack(client: &Client, auth: &B2Auth, pack: &str, files: &[PackEntry], pack_ids: &HashMap<&str, &str>, naming: &Naming, destination: &Path) -> Result<(), String> {
+    let file_id = match pack_ids.get(pack) {
+        Some(id) => id,
+        None => return Err(format!("Missing pack {:?}", pack)),
+    };
+    let data = objects::download(client, auth, file_id)?;
+
+    let mut failed = 0;
+    for file in files {
+        let start = file.offset as usize;
+        let end = start + file.size as usize;
+        if end > data.len() {
+            println!("Failed to restore {:?} - Pack is too short", file.name);
+            failed += 1;
+            continue;
+        }
+        let target = match local_path(destination, naming, &file.name) {
+            Ok(t) => t,
+            Err(e) => {
+                println!("Failed to restore {:?} - {}", file.name, e);
+                failed += 1;
+                continue;
+            },
+        };
+        let res = target.parent().map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&target, &data[start..end]))
+            .and_then(|_| std::fs::File::options().write(true).open(&target))
+            .and_then(|f| f.set_modified(UNIX_EPOCH + Duration::from_millis(file.modified)));
+        if let Err(e) = res {
+            println!("Failed to restore {:?} - {:?}", file.name, e);
+            failed += 1;
+        } else if let Some(meta) = &file.posix {
+            if let Err(e) = posix::apply(meta, &target) {
+                println!("Failed to restore {:?} - {}", file.name, e);
+                failed += 1;
+            }
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        n => Err(format!("{} of {} files failed", n, files.len())),
+    }
+}
+
+// Reassembles a file from its chunks
+// Files the manifest lists as plain objects (i.e. from before switching to the chunked format) are downloaded as-is
+fn restore_chunked_file(client: &Client, auth: &B2Auth, file: &ManifestEntry, chunk_ids: &HashMap<String, String>, naming: &Naming, destination: &Path) -> Result<(), String> {
+    if file.pack.is_some() {
+        return Err("Packed files can only be restored with the 'Files' storage format".to_string());
+    }
+    let target = local_path(destination, naming, &file.name)?;
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| format!("{:?}", e))?;
+    }
+    if let Some(link) = &file.link {
+        return links::create(link, &target).map_err(|e| format!("{:?}", e));
+    }
+
+    let mut out = std::fs::File::create(&target).map_err(|e| format!("{:?}", e))?;
+    // Sparse files only hold their data, see files::sparse
+    // A plain object has its own extents, as the manifest only records them when the object is uploaded
+    let mut sparse = file.sparse.clone().map(|e| (file.size, e));
+    let mut plain = None;
+    if let Some(file_id) = &file.file_id {
+        let download = crate::b2::b2_download_file_by_id(client, auth, file_id).map_err(|e| format!("{:?}", e))?;
+        if let Some(s) = download.file_info.get(sparse::INFO_KEY).and_then(|v| sparse::decode(v)) {
+            sparse = Some(s);
+        }
+        plain = Some(compression::decoder(download.reader, download.file_info.get(compression::INFO_KEY)).map_err(|e| format!("{:?}", e))?);
+    }
+    let extents = sparse.as_ref().map_or(&[][..], |(_, e)| e.as_slice());
+    let mut writer = HoleWriter::new(&mut out, extents);
+    if let Some(mut reader) = plain {
+        std::io::copy(&mut reader, &mut writer).map_err(|e| format!("{:?}", e))?;
+    }
+    for hash in file.chunks.iter() {
+        let name = chunked::chunk_name(hash);
+        let file_id = match chunk_ids.get(&name) {
+            Some(id) => id,
+            None => return Err(format!("Missing chunk {:?}", name)),
+        };
+        let download = crate::b2::b2_download_file_by_id(client, auth, file_id).map_err(|e| format!("{:?}", e))?;
+        let mut reader = compression::decoder(download.reader, download.file_info.get(compression::INFO_KEY)).map_err(|e| format!("{:?}", e))?;
+        std::io::copy(&mut reader, &mut writer).map_err(|e| format!("{:?}", e))?;
+    }
+    if let Some((size, _)) = sparse {
+        out.set_len(size).map_err(|e| format!("{:?}", e))?;
+    }
+
+    // Restore the modification time, so the next upload does not consider the file changed
+    let _ = out.set_modified(UNIX_EPOCH + Duration::from_millis(file.modified));
+    match &file.posix {
+        Some(meta) => {
+            drop(out);
+            posix::apply(meta, &target)
+        },
+        None => Ok(()),
+    }
+}
+
+// Links the other names of hard linked files to the name that was restored, see files::hardlinks
+// Returns the number of links that failed
+fn restore_hard_links(hard_links: &[ManifestEntry], naming: &Naming, destination: &Path) -> usize {
+    let mut failed = 0;
+    for link in hard_links {
+        let first = link.hardlink.as_deref().unwrap_or_default();
+        let res = local_path(destination, naming, first).and_then(|first| {
+            let target = local_path(destination, naming, &link.name)?;
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent).map_err(|e| format!("{:?}", e))?;
+            }
+            if std::fs::symlink_metadata(&target).is_ok() {
+                std::fs::remove_file(&target).map_err(|e| format!("{:?}", e))?;
+            }
+            std::fs::hard_link(&first, &target).map_err(|e| format!("{:?}", e))
+        });
+        if let Err(e) = res {
+            println!("Failed to link {:?} to {:?} - {}", link.name, first, e);
+            failed += 1;
+        }
+    }
+    failed
+}
+
+// Creates the directories listed in a manifest, and restores their metadata
+// Runs after the files are restored, as adding files changes a directory's modification time and its mode could forbid it
+// Children are done before their parents for the same reason
+// Returns the number of directories that failed
+fn restore_directories(dirs: &[ManifestEntry], naming: &Naming, destination: &Path) -> usize {
+    println!("Restoring {} directories to {:?}", dirs.len(), destination);
+    let mut sorted: Vec<&ManifestEntry> = dirs.iter().collect();
+    sorted.sort_by(|a, b| b.name.cmp(&a.name));
+
+    let mut failed = 0;
+    for dir in sorted {
+        let res = local_path(destination, naming, &dir.name)
+            .and_then(|target| std::fs::create_dir_all(&target).map(|_| target).map_err(|e| format!("{:?}", e)))
+            .and_then(|target| match &dir.posix {
+                Some(meta) => posix::apply(meta, &target),
+                None => {
+                    let _ = std::fs::File::open(&target).and_then(|f| f.set_modified(UNIX_EPOCH + Duration::from_millis(dir.modified)));
+                    Ok(())
+                },
+            });
+        if let Err(e) = res {
+            println!("Failed to restore {:?} - {}", dir.name, e);
+            failed += 1;
+        }
+    }
+    failed
+}
+
+// Maps a name in B2 (relative to the remote prefix) to a path inside 'destination'
+// Path mappings are reversed, so files are restored under the path they were backed up from
+// Windows drive letters (C:/...) become a directory (C/...), and names that would escape 'destination' are rejected
+fn local_path(destination: &Path, naming: &Naming, name: &str) -> Result<PathBuf, String> {
+    let relative = escape::decode_path(&naming.path_for_name(name).replace(':', ""));
+    for component in relative.components() {
+        match component {
+            Component::Normal(_) => (),
+            _ => return Err(format!("Refusing to restore to {:?}", relative)),
+        }
+    }
+    Ok(destination.join(relative))
+}
@@ -0,0 +1,334 @@
+// Retention of old file versions, enforced by the client
+//
+// Bucket lifecycle rules can only keep versions for a number of days
+// Instead, a grandfather-father-son policy can be configured (see 'Policy'), which keeps
+//     the newest 'last' versions of every file,
+//     the newest version of each of the last 'daily' days,
+//     the newest version of each of the last 'weekly' weeks (starting on Monday),
+//     the newest version of each of the last 'monthly' calendar months,
+// and deletes every other version, using b2_list_file_versions and b2_delete_file_version
+// Like purge, this is done in two steps: 'plan' lists what would be deleted, 'execute' deletes it
+//
+// The current version of a file is always kept. Hide markers are only deleted along with the last version of a hidden file
+// Files the program stores for itself (manifests, chunks, packs) are left alone, as are files outside our remote prefix
+// and, with an empty prefix, those under other computers' prefixes
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use raze::api::B2Auth;
+use reqwest::blocking::Client;
+
+use crate::b2::FileVersion;
+use crate::files::manifest;
+use crate::files::naming::Naming;
+use crate::ui::GUIConfig;
+
+const DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// How many versions to keep, 0 disables a rule
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    pub last: u32,
+    pub daily: u32,
+    pub weekly: u32,
+    pub monthly: u32,
+}
+
+impl Policy {
+    pub fn from_config(cfg: &GUIConfig) -> Self {
+        Policy {
+            last: cfg.retention_last,
+            daily: cfg.retention_daily,
+            weekly: cfg.retention_weekly,
+            monthly: cfg.retention_monthly,
+        }
+    }
+
+    /// Whether any rule is set, if not nothing is deleted
+    pub fn is_enabled(&self) -> bool {
+        self.last > 0 || self.daily > 0 || self.weekly > 0 || self.monthly > 0
+    }
+
+    /// Decides which versions to keep, given their upload times (in milliseconds), newest first
+    pub fn keep(&self, times: &[u64], now: u64) -> Vec<bool> {
+        let mut keep = vec![false; times.len()];
+        for k in keep.iter_mut().take(self.last as usize) {
+            *k = true;
+        }
+        let day = |t: u64| (t / DAY) as i64;
+        // 1970-01-01 was a Thursday, offset by 3 days so weeks start on Monday
+        let week = |t: u64| (day(t) + 3).div_euclid(7);
+        let month = |t: u64| {
+            let (year, month, _day) = manifest::civil_from_days(day(t));
+            year * 12 + month - 1
+        };
+        keep_per_period(times, &mut keep, self.daily, now, day);
+        keep_per_period(times, &mut keep, self.weekly, now, week);
+        keep_per_period(times, &mut keep, self.monthly, now, month);
+        keep
+    }
+}
+
+// Keeps the newest version in each of the last 'periods' periods (counting the current one)
+// 'period' maps a time to the number of its period
+fn keep_per_period<F: Fn(u64) -> i64>(times: &[u64], keep: &mut [bool], periods: u32, now: u64, period: F) {
+    let current = period(now);
+    let mut seen = HashSet::new();
+    for (i, t) in times.iter().enumerate() {
+        let p = period(*t);
+        if current - p < periods as i64 && seen.insert(p) {
+            keep[i] = true;
+        }
+    }
+}
+
+/// A file version that would be deleted
+#[derive(Debug, Clone)]
+pub struct Deletion {
+    // Name in the bucket, including the remote prefix
+    pub name: String,
+    pub file_id: String,
+    // 'upload' or 'hide'
+    pub action: String,
+    pub uploaded: u64,
+    pub size: u64,
+}
+
+/// What enforcing the policy would delete, along with the session needed to carry it out
+pub struct RetentionPlan {
+    // Sorted by name, then newest first
+    pub deletions: Vec<Deletion>,
+    // Number of versions that are kept
+    pub kept: usize,
+    client: Client,
+    auth: B2Auth,
+}
+
+impl RetentionPlan {
+    /// Total size of the versions that would be deleted
+    pub fn size(&self) -> u64 {
+        self.deletions.iter().map(|d| d.size).sum()
+    }
+
+    /// Prints the versions that would be deleted, grouped by file
+    pub fn print(&self) {
+        let mut name = None;
+        for d in self.deletions.iter() {
+            if name != Some(&d.name) {
+                name = Some(&d.name);
+                println!("{}", d.name);
+            }
+            match d.action.as_str() {
+                "hide" => println!("  {}  hide marker", manifest::format_timestamp(d.uploaded)),
+                _ => println!("  {}  {}", manifest::format_timestamp(d.uploaded), manifest::format_size(d.size)),
+            }
+        }
+        println!("{} versions would be deleted ({}), {} are kept", self.deletions.len(),
+                 manifest::format_size(self.size()), self.kept);
+    }
+}
+
+/// Works out which versions the retention policy would delete, without changing anything in the bucket
+pub fn plan(cfg: &GUIConfig) -> Result<RetentionPlan, String> {
+    let policy = Policy::from_config(cfg);
+    let naming = Naming::from_config(cfg);
+    if !policy.is_enabled() {
+        return Err("No retention rules are set, see the advanced options".to_string());
+    }
+
+    let client = reqwest::blocking::Client::builder().timeout(Duration::from_secs_f32(30.0)).build().unwrap();
+    let keystring = format!("{}:{}", cfg.app_key_id, cfg.app_key);
+    let auth = match raze::api::b2_authorize_account(&client, keystring) {
+        Ok(a) => a,
+        Err(_e) => return Err("Authentication Failed".to_string()),
+    };
+    let versions = match crate::b2::list_all_file_versions(&client, &auth, &cfg.bucket_id, 1000) {
+        Ok(v) => v,
+        Err(e) => {
+            println!("Failed to list file versions - {:?}", e);
+            return Err("Failed talking to B2 - Check your Bucket ID".to_string())
+        },
+    };
+    println!("Collected {} file versions", versions.len());
+
+    let now = match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Ok(d) => d.as_millis() as u64,
+        Err(_e) => 0,
+    };
+    let mut deletions = vec![];
+    let mut kept = 0;
+    // With an empty prefix, every name is under it, including those of other computers with a prefix of their own
+    let others = naming.other_prefixes(versions.iter().map(|v| v.file_name.as_str()));
+    // Versions are sorted by name, so each file's versions are next to each other, newest first
+    let mut start = 0;
+    while start < versions.len() {
+        let name = &versions[start].file_name;
+        let count = versions[start..].iter().take_while(|v| v.file_name == *name).count();
+        let file = &versions[start..start + count];
+        start += count;
+        match naming.local(name) {
+            // Another computer's, it enforces its own policy
+            Some(local) if others.iter().any(|p| local.starts_with(p.as_str())) => continue,
+            Some(local) if !local.starts_with(manifest::REPO_PREFIX) => (),
+            Some(_) => {
+                kept += file.len();
+                continue;
+            },
+            // Another computer's, it enforces its own policy
+            None => continue,
+        }
+        let (deleted, k) = file_deletions(&policy, file, now);
+        deletions.extend(deleted);
+        kept += k;
+    }
+    println!("Planned deletion of {} versions", deletions.len());
+
+    Ok(RetentionPlan { deletions, kept, client, auth })
+}
+
+// Applies the policy to the versions of a single file (newest first)
+// Returns the versions to delete, and the number kept
+fn file_deletions(policy: &Policy, file: &[FileVersion], now: u64) -> (Vec<Deletion>, usize) {
+    let uploads: Vec<&FileVersion> = file.iter().filter(|v| v.action == "upload").collect();
+    let times: Vec<u64> = uploads.iter().map(|v| v.upload_timestamp).collect();
+    let mut keep = policy.keep(&times, now);
+    // The current version of a visible file is always kept
+    let visible = file.first().map(|v| v.action == "upload").unwrap_or(false);
+    if visible {
+        keep[0] = true;
+    }
+
+    let mut delete: Vec<&FileVersion> = uploads.iter().zip(keep.iter()).filter(|(_, k)| !**k).map(|(v, _)| *v).collect();
+    // Once no version of a hidden file is left, its hide markers go as well
+    if !keep.contains(&true) {
+        delete.extend(file.iter().filter(|v| v.action == "hide"));
+    }
+    let deletions: Vec<Deletion> = delete.iter()
+        .filter_map(|v| v.file_id.as_ref().map(|id| Deletion {
+            name: v.file_name.clone(),
+            file_id: id.clone(),
+            action: v.action.clone(),
+            uploaded: v.upload_timestamp,
+            size: v.content_length,
+        }))
+        .collect();
+    let kept = file.len() - deletions.len();
+    (deletions, kept)
+}
+
+/// Deletes the versions listed in a plan
+/// Returns the number of versions that failed to be deleted
+pub fn execute(plan: RetentionPlan) -> usize {
+    let client = &plan.client;
+    let auth = &plan.auth;
+    let failed = crate::purge::run_parallel(plan.deletions, |d| {
+        println!("Deleting {:?} from {}", d.name, manifest::format_timestamp(d.uploaded));
+        for _i in 0..5 {
+            match raze::api::b2_delete_file_version(client, auth, &d.name, &d.file_id) {
+                Ok(_) => return true,
+                // Already gone, e.g. removed by a lifecycle rule
+                Err(raze::Error::B2Error(e)) if e.code == "file_not_present" => return true,
+                Err(e) => println!("Err {:?}, retrying {:?}", e, d.name),
+            }
+        }
+        false
+    });
+    println!("Done enforcing retention");
+    failed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // Noon on Friday 2024-10-04
+    const NOW: u64 = 20000 * DAY + DAY / 2;
+
+    fn policy(last: u32, daily: u32, weekly: u32, monthly: u32) -> Policy {
+        Policy { last, daily, weekly, monthly }
+    }
+
+    fn days_ago(days: &[u64]) -> Vec<u64> {
+        days.iter().map(|d| NOW - d * DAY).collect()
+    }
+
+    fn version(action: &str, uploaded: u64, id: &str) -> FileVersion {
+        FileVersion {
+            account_id: String::new(),
+            action: action.to_string(),
+            bucket_id: String::new(),
+            content_length: 10,
+            content_sha1: None,
+            content_type: None,
+            file_id: Some(id.to_string()),
+            file_info: HashMap::new(),
+            file_name: "home/a.txt".to_string(),
+            upload_timestamp: uploaded,
+        }
+    }
+
+    fn deleted_ids(deletions: &[Deletion]) -> Vec<&str> {
+        deletions.iter().map(|d| d.file_id.as_str()).collect()
+    }
+
+    #[test]
+    fn keeps_nothing_without_rules() {
+        let p = policy(0, 0, 0, 0);
+        assert!(!p.is_enabled());
+        assert_eq!(p.keep(&days_ago(&[0, 1, 2]), NOW), vec![false, false, false]);
+    }
+
+    #[test]
+    fn keeps_the_last_versions() {
+        assert_eq!(policy(2, 0, 0, 0).keep(&days_ago(&[0, 0, 5, 9]), NOW), vec![true, true, false, false]);
+    }
+
+    #[test]
+    fn keeps_the_newest_version_of_each_day() {
+        assert_eq!(policy(0, 3, 0, 0).keep(&days_ago(&[0, 0, 1, 2, 3]), NOW), vec![true, false, true, true, false]);
+    }
+
+    #[test]
+    fn weeks_start_on_monday() {
+        // Friday, Monday of the same week, the Sunday before, and the Sunday of the week before that
+        assert_eq!(policy(0, 0, 2, 0).keep(&days_ago(&[0, 4, 5, 12]), NOW), vec![true, false, true, false]);
+    }
+
+    #[test]
+    fn keeps_the_newest_version_of_each_month() {
+        // October, September twice, August
+        assert_eq!(policy(0, 0, 0, 2).keep(&days_ago(&[0, 20, 30, 60]), NOW), vec![true, true, false, false]);
+    }
+
+    #[test]
+    fn rules_add_up() {
+        assert_eq!(policy(1, 2, 0, 0).keep(&days_ago(&[0, 0, 0, 1, 1]), NOW), vec![true, false, false, true, false]);
+    }
+
+    #[test]
+    fn current_version_is_always_kept() {
+        let file = vec![version("upload", NOW - 100 * DAY, "3"), version("upload", NOW - 200 * DAY, "2"), version("upload", NOW - 300 * DAY, "1")];
+        let (deletions, kept) = file_deletions(&policy(0, 1, 0, 0), &file, NOW);
+        assert_eq!(deleted_ids(&deletions), vec!["2", "1"]);
+        assert_eq!(kept, 1);
+    }
+
+    #[test]
+    fn hidden_files_keep_their_hide_marker_while_a_version_is_kept() {
+        let file = vec![version("hide", NOW - DAY, "h"), version("upload", NOW - 2 * DAY, "2"), version("upload", NOW - 3 * DAY, "1")];
+        let (deletions, kept) = file_deletions(&policy(1, 0, 0, 0), &file, NOW);
+        assert_eq!(deleted_ids(&deletions), vec!["1"]);
+        assert_eq!(kept, 2);
+    }
+
+    #[test]
+    fn hidden_files_go_with_their_last_version() {
+        let file = vec![version("hide", NOW - 50 * DAY, "h"), version("upload", NOW - 60 * DAY, "1")];
+        let (deletions, kept) = file_deletions(&policy(0, 7, 0, 0), &file, NOW);
+        assert_eq!(deleted_ids(&deletions), vec!["1", "h"]);
+        assert_eq!(kept, 0);
+        assert_eq!(deletions[1].action, "hide");
+    }
+}
@@ -0,0 +1,242 @@
+use wgpu::BufferUsage;
+use zerocopy::AsBytes;
+
+use crate::gui::{GuiProgram, Vertex};
+use crate::ui::{GUIConfig, RepositoryMode, SymlinkPolicy, UIState};
+use crate::ui::align::Anchor;
+
+// Advanced options page, reached from the options menu
+// Holds settings most users never need to touch, laid out as a scrollable list of rows
+
+/// A single row on the page
+/// Choice: label, current value and what to do when clicked (typically cycling through the values)
+/// Field: label and the index of the field in GUIConfigStrings, edited by typing like in the options menu
+enum Row {
+    Choice(&'static str, fn(&GUIConfig) -> &'static str, fn(&mut GUIConfig)),
+    Field(&'static str, usize),
+}
+
+const ROWS: &[Row] = &[
+    Row::Choice("Compress files (zstd)", |c| yes_no(c.compression), |c| c.compression = !c.compression),
+    Row::Choice("Storage format", |c| match c.repository_mode {
+        RepositoryMode::Files => "Files",
+        RepositoryMode::Chunked => "Chunked (dedup)",
+    }, |c| c.repository_mode = match c.repository_mode {
+        RepositoryMode::Files => RepositoryMode::Chunked,
+        RepositoryMode::Chunked => RepositoryMode::Files,
+    }),
+    Row::Field("Pack files below (KB)", 7),
+    Row::Field("Purge limit (% of files)", 8),
+    Row::Field("Purge limit (files)", 9),
+    Row::Field("Purge after missing (days)", 10),
+    Row::Field("Keep last versions", 11),
+    Row::Field("Keep daily versions (days)", 12),
+    Row::Field("Keep weekly versions (weeks)", 13),
+    Row::Field("Keep monthly versions (months)", 14),
+    Row::Field("Remote prefix", 15),
+    Row::Field("Skip files over (MB)", 16),
+    Row::Field("Skip files older than (days)", 17),
+    Row::Field("Skip types (e.g. iso, video)", 18),
+    Row::Choice("Respect .gitignore files", |c| yes_no(c.respect_gitignore), |c| c.respect_gitignore = !c.respect_gitignore),
+    Row::Choice("Skip cache dirs (CACHEDIR.TAG)", |c| yes_no(c.skip_cachedir), |c| c.skip_cachedir = !c.skip_cachedir),
+    Row::Choice("Skip nodump files (Linux)", |c| yes_no(c.skip_nodump), |c| c.skip_nodump = !c.skip_nodump),
+    Row::Choice("Stay on one file system", |c| yes_no(c.one_file_system), |c| c.one_file_system = !c.one_file_system),
+    Row::Choice("Symbolic links", |c| match c.symlinks {
+        SymlinkPolicy::Skip => "Skip",
+        SymlinkPolicy::Store => "Store as links",
+        SymlinkPolicy::Follow => "Follow",
+    }, |c| c.symlinks = match c.symlinks {
+        SymlinkPolicy::Skip => SymlinkPolicy::Store,
+        SymlinkPolicy::Store => SymlinkPolicy::Follow,
+        SymlinkPolicy::Follow => SymlinkPolicy::Skip,
+    }),
+];
+
+const ROW_HEIGHT: f32 = 40.0;
+const ROW_WIDTH: f32 = 300.0;
+// Where the first row starts, leaves room for the header
+const TOP: f32 = 100.0;
+
+fn yes_no(b: bool) -> &'static str {
+    match b {
+        true => "Yes",
+        false => "No",
+    }
+}
+
+// Y coordinate of the top of row 'i'
+fn row_y(gui: &GuiProgram, i: usize) -> f32 {
+    TOP + gui.state_manager.scroll + ROW_HEIGHT * i as f32
+}
+
+pub fn render(
+    gui: &mut GuiProgram,
+    frame: &wgpu::SwapChainOutput,
+    device: &wgpu::Device,
+) -> Vec<wgpu::CommandBuffer> {
+
+    ///// Polygons
+    let mut vertices = vec![];
+    for (i, row) in ROWS.iter().enumerate() {
+        let y = row_y(gui, i);
+        let col_left = match i % 2 {
+            0 => [0.2,0.2,0.2,1.0],
+            _ => [0.3,0.3,0.3,1.0],
+        };
+        let active = match row {
+            Row::Field(_, n) => gui.state_manager.strings.active_field == *n,
+            Row::Choice(..) => false,
+        };
+        let col_right = if active {
+            [0.5,0.5,0.5,1.0]
+        } else {
+            match i % 2 {
+                0 => [0.3,0.3,0.3,1.0],
+                _ => [0.2,0.2,0.2,1.0],
+            }
+        };
+        vertices.append(&mut Vertex::rect(gui.align.win_width/2.0 - ROW_WIDTH, y, ROW_WIDTH, ROW_HEIGHT, col_left));
+        vertices.append(&mut Vertex::rect(gui.align.win_width/2.0, y, ROW_WIDTH, ROW_HEIGHT, col_right));
+    }
+    // Header background, covers rows scrolled above the list
+    vertices.append(&mut Vertex::rect(0.0, 0.0, gui.align.win_width, TOP, [1.0,1.0,1.0,1.0]));
+
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+
+    {
+        let buffer = device.create_buffer_with_data(vertices.as_bytes(), BufferUsage::VERTEX);
+
+        let rpass_color_attachment = {
+            wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &frame.view,
+                resolve_target: None,
+                load_op: wgpu::LoadOp::Clear,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::WHITE,
+            }
+        };
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[rpass_color_attachment],
+            depth_stencil_attachment: None,
+        });
+
+        rpass.set_pipeline(&gui.pipeline);
+        rpass.set_bind_group(0, &gui.uniforms, &[]);
+        rpass.set_vertex_buffer(0, &buffer, 0, 0);
+
+        rpass.draw(0..vertices.len() as u32, 0..1);
+    }
+
+    let cb1 = encoder.finish();
+
+    ///// Text
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("Text") });
+
+    {
+        let _ = encoder.begin_render_pass(
+            &wgpu::RenderPassDescriptor {
+                color_attachments: &[
+                    wgpu::RenderPassColorAttachmentDescriptor {
+                        attachment: &frame.view,
+                        resolve_target: None,
+                        load_op: wgpu::LoadOp::Load,
+                        store_op: wgpu::StoreOp::Store,
+                        clear_color: wgpu::Color::WHITE,
+                    },
+                ],
+                depth_stencil_attachment: None,
+            },
+        );
+    }
+
+    let mut th = gui.state_manager.text_handler.lock().unwrap();
+    th.draw_centered("Advanced options", gui.align.win_width/2.0, TOP/2.0,
+                     64.0, f32::INFINITY, [0.05,0.05,0.05,1.0]);
+
+    for (i, row) in ROWS.iter().enumerate() {
+        let y = row_y(gui, i) + ROW_HEIGHT/2.0;
+        // Skip rows hidden behind the header or below the window
+        if y < TOP || y > gui.align.win_height {
+            continue;
+        }
+        let (label, value) = match row {
+            Row::Choice(label, value, _) => (*label, value(&gui.state_manager.config)),
+            Row::Field(label, n) => (*label, gui.state_manager.strings.field(*n)),
+        };
+        th.draw_centered(label, gui.align.win_width/2.0 - ROW_WIDTH/2.0, y,
+                         24.0, ROW_WIDTH - 4.0, [0.95,0.95,0.95,1.0]);
+        th.draw_centered(value, gui.align.win_width/2.0 + ROW_WIDTH/2.0, y,
+                         24.0, ROW_WIDTH - 4.0, [0.95,0.95,0.95,1.0]);
+    }
+
+    th.flush(device, &mut encoder, frame, (gui.sc_desc.width,gui.sc_desc.height));
+
+    let cb2 = encoder.finish();
+
+    ///// Images
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    let vertices = gui.align.image(Anchor::TopRight, 0.0, 0.0, 64.0, 32.0, 0.0, Some([0.0,651.0,128.0,64.0]));
+    let buffer = device.create_buffer_with_data(vertices.as_bytes(), BufferUsage::VERTEX);
+
+    let rpass_color_attachment =  {
+        wgpu::RenderPassColorAttachmentDescriptor {
+            attachment: &frame.view,
+            resolve_target: None,
+            load_op: wgpu::LoadOp::Load,
+            store_op: wgpu::StoreOp::Store,
+            clear_color: wgpu::Color::WHITE,
+        }
+    };
+
+    {
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[rpass_color_attachment],
+            depth_stencil_attachment: None,
+        });
+
+        rpass.set_pipeline(&gui.tex_pipeline);
+        rpass.set_bind_group(0, &gui.uniforms, &[]);
+        rpass.set_bind_group(1, &gui.texture_bind_group, &[]);
+        rpass.set_vertex_buffer(0, &buffer, 0, 0);
+
+        rpass.draw(0..vertices.len() as u32, 0..1);
+    }
+
+    let cb3 = encoder.finish();
+
+    vec![cb1,cb2,cb3]
+}
+
+pub fn handle_click(gui: &mut GuiProgram) -> Option<UIState> {
+    if gui.align.was_area_clicked(Anchor::TopRight, gui.state_manager.cx, gui.state_manager.cy, 0.0, 0.0, 64.0, 32.0) {
+        gui.save_config();
+        gui.state_manager.scroll = 0.0;
+        return Some(UIState::Options)
+    }
+
+    // Rows hidden behind the header can't be clicked
+    gui.state_manager.strings.active_field = 0;
+    if gui.state_manager.cy >= TOP {
+        for (i, row) in ROWS.iter().enumerate() {
+            if gui.align.was_area_clicked(Anchor::TopLeft, gui.state_manager.cx, gui.state_manager.cy,
+                                          gui.align.win_width/2.0, row_y(gui, i), ROW_WIDTH, ROW_HEIGHT) {
+                match row {
+                    Row::Choice(_, _, click) => click(&mut gui.state_manager.config),
+                    Row::Field(_, n) => gui.state_manager.strings.active_field = *n,
+                }
+                break;
+            }
+        }
+    }
+    gui.state_manager.strings.destring(&mut gui.state_manager.config);
+    None
+}
+
+// Returns the maximum amount that we can scroll down
+pub fn compute_max_scroll(gui: &GuiProgram) -> f32 {
+    (TOP + ROW_HEIGHT * ROWS.len() as f32 - gui.align.win_height).max(0.0)
+}
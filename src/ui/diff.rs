@@ -0,0 +1,239 @@
+use wgpu::BufferUsage;
+use zerocopy::AsBytes;
+
+use crate::files::diff::{self, ChangeKind};
+use crate::files::manifest::{self, Manifest};
+use crate::gui::{GuiProgram, Vertex};
+use crate::ui::UIState;
+use crate::ui::align::Anchor;
+
+// Snapshot diff page, reached from the options menu
+// Compares two of the local snapshots and lists the changed files grouped by directory
+// The snapshots are picked by clicking the 'From' and 'To' boxes, left click for an older one, right click for a newer one
+
+const LINE_HEIGHT: f32 = 24.0;
+const BOX_WIDTH: f32 = 300.0;
+const BOX_HEIGHT: f32 = 32.0;
+const BOX_Y: f32 = 70.0;
+// Where the list starts, leaves room for the header
+const TOP: f32 = 150.0;
+
+const DIR_COLOR: [f32; 4] = [0.05,0.05,0.05,1.0];
+const ADDED_COLOR: [f32; 4] = [0.1,0.5,0.1,1.0];
+const MODIFIED_COLOR: [f32; 4] = [0.6,0.4,0.0,1.0];
+const REMOVED_COLOR: [f32; 4] = [0.7,0.1,0.1,1.0];
+
+/// Which snapshots are being compared, and the result
+#[derive(Default)]
+pub struct DiffState {
+    // Timestamps of the local snapshots, oldest first
+    timestamps: Vec<u64>,
+    from: usize,
+    to: usize,
+    // Text, indentation and color of each line in the list
+    lines: Vec<(String, f32, [f32; 4])>,
+    summary: String,
+}
+
+impl DiffState {
+    /// Loads the list of snapshots and compares the two latest ones
+    pub fn open(&mut self) {
+        self.timestamps = manifest::local_snapshot_list();
+        self.to = self.timestamps.len().saturating_sub(1);
+        self.from = self.to.saturating_sub(1);
+        self.compute();
+    }
+
+    fn compute(&mut self) {
+        self.lines.clear();
+        if self.timestamps.len() < 2 {
+            self.summary = "At least two snapshots are needed, one is recorded after each completed upload".to_string();
+            return;
+        }
+        let old = Manifest::from_file(manifest::local_path(self.timestamps[self.from]));
+        let new = Manifest::from_file(manifest::local_path(self.timestamps[self.to]));
+        let (old, new) = match (old, new) {
+            (Ok(o), Ok(n)) => (o, n),
+            (Err(e), _) | (_, Err(e)) => {
+                self.summary = e;
+                return;
+            },
+        };
+
+        let dirs = diff::diff(&old, &new);
+        for d in dirs.iter() {
+            let dir = if d.dir.is_empty() { "/" } else { &d.dir };
+            self.lines.push((format!("{} ({})", dir, diff::format_delta(d.delta())), 0.0, DIR_COLOR));
+            for c in d.changes.iter() {
+                let (text, color) = match c.kind {
+                    ChangeKind::Added => (format!("+ {}  {}", c.file_name(), manifest::format_size(c.new_size)), ADDED_COLOR),
+                    ChangeKind::Modified => (format!("~ {}  {} -> {}", c.file_name(), manifest::format_size(c.old_size),
+                                                     manifest::format_size(c.new_size)), MODIFIED_COLOR),
+                    ChangeKind::Removed => (format!("- {}  {}", c.file_name(), manifest::format_size(c.old_size)), REMOVED_COLOR),
+                };
+                self.lines.push((text, 24.0, color));
+            }
+        }
+        self.summary = diff::summary(&dirs);
+    }
+
+    fn label(&self, prefix: &str, i: usize) -> String {
+        match self.timestamps.get(i) {
+            Some(t) => format!("{} {}", prefix, manifest::format_timestamp(*t)),
+            None => format!("{} -", prefix),
+        }
+    }
+}
+
+pub fn render(
+    gui: &mut GuiProgram,
+    frame: &wgpu::SwapChainOutput,
+    device: &wgpu::Device,
+) -> Vec<wgpu::CommandBuffer> {
+
+    ///// Polygons
+    let mut vertices = vec![];
+    vertices.append(&mut Vertex::rect(gui.align.win_width/2.0 - BOX_WIDTH - 10.0, BOX_Y, BOX_WIDTH, BOX_HEIGHT, [0.2,0.2,0.2,1.0]));
+    vertices.append(&mut Vertex::rect(gui.align.win_width/2.0 + 10.0, BOX_Y, BOX_WIDTH, BOX_HEIGHT, [0.2,0.2,0.2,1.0]));
+
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+
+    {
+        let buffer = device.create_buffer_with_data(vertices.as_bytes(), BufferUsage::VERTEX);
+
+        let rpass_color_attachment = {
+            wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &frame.view,
+                resolve_target: None,
+                load_op: wgpu::LoadOp::Clear,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::WHITE,
+            }
+        };
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[rpass_color_attachment],
+            depth_stencil_attachment: None,
+        });
+
+        rpass.set_pipeline(&gui.pipeline);
+        rpass.set_bind_group(0, &gui.uniforms, &[]);
+        rpass.set_vertex_buffer(0, &buffer, 0, 0);
+
+        rpass.draw(0..vertices.len() as u32, 0..1);
+    }
+
+    let cb1 = encoder.finish();
+
+    ///// Text
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("Text") });
+
+    {
+        let _ = encoder.begin_render_pass(
+            &wgpu::RenderPassDescriptor {
+                color_attachments: &[
+                    wgpu::RenderPassColorAttachmentDescriptor {
+                        attachment: &frame.view,
+                        resolve_target: None,
+                        load_op: wgpu::LoadOp::Load,
+                        store_op: wgpu::StoreOp::Store,
+                        clear_color: wgpu::Color::WHITE,
+                    },
+                ],
+                depth_stencil_attachment: None,
+            },
+        );
+    }
+
+    let state = &gui.state_manager.diff;
+    let mut th = gui.state_manager.text_handler.lock().unwrap();
+    th.draw_centered("Snapshot diff", gui.align.win_width/2.0, 32.0,
+                     48.0, f32::INFINITY, [0.05,0.05,0.05,1.0]);
+    th.draw_centered(&state.label("From", state.from), gui.align.win_width/2.0 - BOX_WIDTH/2.0 - 10.0, BOX_Y + BOX_HEIGHT/2.0,
+                     20.0, BOX_WIDTH - 4.0, [0.95,0.95,0.95,1.0]);
+    th.draw_centered(&state.label("To", state.to), gui.align.win_width/2.0 + BOX_WIDTH/2.0 + 10.0, BOX_Y + BOX_HEIGHT/2.0,
+                     20.0, BOX_WIDTH - 4.0, [0.95,0.95,0.95,1.0]);
+    th.draw_centered(&state.summary, gui.align.win_width/2.0, BOX_Y + BOX_HEIGHT + 24.0,
+                     24.0, gui.align.win_width - 20.0, [0.05,0.05,0.05,1.0]);
+
+    for (i, (text, indent, color)) in state.lines.iter().enumerate() {
+        let y = TOP + gui.state_manager.scroll + LINE_HEIGHT * i as f32;
+        // Skip lines scrolled into the header or below the window
+        if y < TOP || y > gui.align.win_height {
+            continue;
+        }
+        th.draw(text, 10.0 + indent, y, 20.0, gui.align.win_width - 20.0 - indent, *color);
+    }
+
+    th.flush(device, &mut encoder, frame, (gui.sc_desc.width,gui.sc_desc.height));
+
+    let cb2 = encoder.finish();
+
+    ///// Images
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    let vertices = gui.align.image(Anchor::TopRight, 0.0, 0.0, 64.0, 32.0, 0.0, Some([0.0,651.0,128.0,64.0]));
+    let buffer = device.create_buffer_with_data(vertices.as_bytes(), BufferUsage::VERTEX);
+
+    let rpass_color_attachment =  {
+        wgpu::RenderPassColorAttachmentDescriptor {
+            attachment: &frame.view,
+            resolve_target: None,
+            load_op: wgpu::LoadOp::Load,
+            store_op: wgpu::StoreOp::Store,
+            clear_color: wgpu::Color::WHITE,
+        }
+    };
+
+    {
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[rpass_color_attachment],
+            depth_stencil_attachment: None,
+        });
+
+        rpass.set_pipeline(&gui.tex_pipeline);
+        rpass.set_bind_group(0, &gui.uniforms, &[]);
+        rpass.set_bind_group(1, &gui.texture_bind_group, &[]);
+        rpass.set_vertex_buffer(0, &buffer, 0, 0);
+
+        rpass.draw(0..vertices.len() as u32, 0..1);
+    }
+
+    let cb3 = encoder.finish();
+
+    vec![cb1,cb2,cb3]
+}
+
+// Left click picks an older snapshot, right click a newer one
+// 'From' always stays older than 'To'
+pub fn handle_click(gui: &mut GuiProgram, button: u8) -> Option<UIState> {
+    if gui.align.was_area_clicked(Anchor::TopRight, gui.state_manager.cx, gui.state_manager.cy, 0.0, 0.0, 64.0, 32.0) {
+        gui.state_manager.scroll = 0.0;
+        return Some(UIState::Options)
+    }
+
+    let from_clicked = gui.align.was_area_clicked(Anchor::TopLeft, gui.state_manager.cx, gui.state_manager.cy,
+                                                  gui.align.win_width/2.0 - BOX_WIDTH - 10.0, BOX_Y, BOX_WIDTH, BOX_HEIGHT);
+    let to_clicked = gui.align.was_area_clicked(Anchor::TopLeft, gui.state_manager.cx, gui.state_manager.cy,
+                                                gui.align.win_width/2.0 + 10.0, BOX_Y, BOX_WIDTH, BOX_HEIGHT);
+    let state = &mut gui.state_manager.diff;
+    let (from, to) = match (from_clicked, to_clicked, button) {
+        (true, _, 1) if state.from > 0 => (state.from - 1, state.to),
+        (true, _, 2) if state.from + 1 < state.to => (state.from + 1, state.to),
+        (_, true, 1) if state.to > state.from + 1 => (state.from, state.to - 1),
+        (_, true, 2) if state.to + 1 < state.timestamps.len() => (state.from, state.to + 1),
+        _ => return None,
+    };
+    state.from = from;
+    state.to = to;
+    state.compute();
+    gui.state_manager.scroll = 0.0;
+    None
+}
+
+// Returns the maximum amount that we can scroll down
+pub fn compute_max_scroll(gui: &GuiProgram) -> f32 {
+    (TOP + LINE_HEIGHT * gui.state_manager.diff.lines.len() as f32 - gui.align.win_height).max(0.0)
+}
@@ -169,8 +169,13 @@ fn render_subtree(gui: &crate::GuiProgram, root: &DirEntry, mut y: f32, mut inde
 fn render_subtree_text(gui: &crate::GuiProgram, root: &DirEntry, mut y: f32, mut indent: f32) -> f32 {
     // Draw gui if within visible area
     if y >= 32.0 && y <= gui.align.win_height {
+        // Entries excluded by an ignore file are greyed out
+        let color = match root.ignored {
+            true => [0.6,0.6,0.6,1.0],
+            false => [1.0,1.0,1.0,1.0],
+        };
         gui.state_manager.text_handler.lock().unwrap().draw(&root.name, indent+2.0, y,
-                                                            gui.state_manager.config.font_size, gui.align.win_width-indent-2.0, [1.0,1.0,1.0,1.0]);
+                                                            gui.state_manager.config.font_size, gui.align.win_width-indent-2.0, color);
     } else if y > gui.align.win_height {
         // We will never return to the visible area, stop drawing
         return y;
@@ -192,7 +197,9 @@ fn render_subtree_text(gui: &crate::GuiProgram, root: &DirEntry, mut y: f32, mut
 pub fn handle_click(gui: &GuiProgram, button: u8) -> Option<UIState> {
     if gui.align.was_area_clicked(Anchor::TopRight, gui.state_manager.cx, gui.state_manager.cy, 0.0, 0.0, 64.0, 32.0) {
         println!("Return to Main -- Saving tree");
-        gui.state_manager.fileroot.serialize("backuplist.dat");
+        if let Err(e) = gui.state_manager.fileroot.serialize("backuplist.dat") {
+            println!("Failed to save the selection - {}", e);
+        }
         Some(UIState::Main)
     } else if gui.state_manager.cy >= 32.0 { // Only check for y>32 to exclude the top bar
         // Check if we clicked on an item in the tree
@@ -229,7 +236,7 @@ fn handle_click_rec(gui: &GuiProgram, entry: &DirEntry, x: f32, mut y: f32, butt
             } else {
                 // This refreshes the dir and expands it
                 if !entry.indexed.load(Ordering::Relaxed) {
-                    entry.expand();
+                    entry.expand(gui.state_manager.config.respect_gitignore);
                 }
                 entry.expanded.swap(true, Ordering::Relaxed);
             }
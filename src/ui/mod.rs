@@ -11,6 +11,8 @@ use nanoserde::{DeJson, SerJson};
 /// 4. (De)serialize code
 
 use crate::files::{DirEntry};
+use crate::files::filters::Filters;
+use crate::files::naming::PathMapping;
 use crate::gui::Vertex;
 use crate::text::TextHandler;
 use std::sync::mpsc::{Receiver,Sender};
@@ -21,7 +23,10 @@ pub mod align;
 pub mod upload;
 pub mod purge;
 pub mod options;
+pub mod advanced;
 pub mod consent;
+pub mod diff;
+pub mod rules;
 
 /// Keeps track of the UI state
 pub struct StateManager {
@@ -44,6 +49,15 @@ pub struct StateManager {
     // State info specifically for keeping track of upload progress
     pub upload_state: UploadState,
 
+    // Purge plan being reviewed
+    pub purge: purge::PurgeState,
+
+    // Snapshots being compared on the diff page
+    pub diff: diff::DiffState,
+
+    // Pattern rules being edited on the rules page
+    pub rules: rules::RulesState,
+
     // If this is a Some, a message box is shown in the main menu with the contained string
     // This is used to convey info/errors from other states
     pub status_message: Option<String>,
@@ -62,6 +76,8 @@ pub struct UploadState {
     // Queue of files to be uploaded, shared between threads
     // One thread populates this, a number of threads consumes from it
     pub queue: Arc<Mutex<Vec<PathBuf>>>,
+    // Filters of the current upload, they count what they skipped
+    pub filters: Arc<Filters>,
 }
 
 impl Default for UploadState {
@@ -81,6 +97,7 @@ impl Default for UploadState {
         UploadState {
             instances: Arc::new(Mutex::new(instances)),
             queue: Arc::new(Mutex::new(vec![])),
+            filters: Arc::new(Filters::default()),
         }
     }
 }
@@ -107,8 +124,12 @@ pub struct UploadInstance {
 ///     Contains buttons to go to different states + options menu
 /// FileTree: File tree browser, for selecting what files to upload/exclude
 /// Upload: Displays upload progress + some settings to limit bandwidth usage while uploading
-/// Purge: Switched to after upload, gets rid of files in the cloud that are no longer on the drive (B2 hide)
+/// Purge: Gets rid of files in the cloud that are no longer on the drive (B2 hide)
+///     Lists what would be purged for review first, nothing is hidden until confirmed
 /// Options: Configure the program or start purge
+/// Advanced: Less commonly changed settings, reached from the options menu
+/// Diff: Compares two snapshots (completed upload runs), reached from the options menu
+/// Rules: Edits the glob/regex patterns that exclude files from the selection, reached from the options menu
 #[allow(dead_code)]
 pub enum UIState {
     Consent,
@@ -116,11 +137,14 @@ pub enum UIState {
     FileTree,
     Upload,
     Options,
+    Advanced,
     Purge,
+    Diff,
+    Rules,
 }
 
 /// Contains the settings for the UI, i.e. colors, size and other persistent data
-#[derive(Debug,DeJson,SerJson)]
+#[derive(Debug,Clone,DeJson,SerJson)]
 pub struct GUIConfig {
     // Size of the font (in pixels) in the file tree
     // Note that the size of entries are based on this
@@ -139,6 +163,96 @@ pub struct GUIConfig {
     pub hide_file_names: bool,
     // Whether or not the user has marked that they understand the consequences of using the program
     pub consented: bool,
+    // Whether or not to compress files with zstd before uploading them
+    #[nserde(default)]
+    pub compression: bool,
+    // How files are stored in the bucket
+    #[nserde(default)]
+    pub repository_mode: RepositoryMode,
+    // Files smaller than this (in KB) are packed together, 0 = disabled
+    #[nserde(default)]
+    pub pack_threshold: u32,
+    // Purge asks for an override before hiding more than this percentage of the files in the bucket, 0 = no limit
+    #[nserde(default = "10")]
+    pub purge_max_percent: u32,
+    // Same, but as a number of files, 0 = no limit
+    #[nserde(default = "1000")]
+    pub purge_max_count: u32,
+    // Purge only hides files that have been missing locally for this many days, 0 = immediately
+    #[nserde(default = "7")]
+    pub purge_grace_days: u32,
+    // Purge only considers files under the selected paths, and under these prefixes (names as in B2, e.g. "archive/")
+    // Only editable in config.cfg
+    #[nserde(default)]
+    pub purge_prefixes: Vec<String>,
+    // Files are stored under this prefix in the bucket, so several computers can share one, see files::naming
+    // Set to the hostname when missing, an empty prefix stores files at the top of the bucket
+    #[nserde(default_with = "Option::default")]
+    pub remote_prefix: Option<String>,
+    // Local directories stored under another name, e.g. {"local":"/mnt/data2/photos","remote":"photos"}
+    // Only editable in config.cfg
+    #[nserde(default)]
+    pub path_mappings: Vec<PathMapping>,
+    // Whether .gitignore files are applied like .backupignore files, see files::patterns
+    #[nserde(default)]
+    pub respect_gitignore: bool,
+    // Selection filters, see files::filters, 0 disables a limit
+    // Files larger than this (in MB) are skipped
+    #[nserde(default)]
+    pub filter_max_size: u32,
+    // Files not modified in this many days are skipped
+    #[nserde(default)]
+    pub filter_max_age: u32,
+    // Extensions and classes of files that are skipped, e.g. "iso" or "video"
+    #[nserde(default)]
+    pub filter_types: Vec<String>,
+    // Skip directories containing a CACHEDIR.TAG
+    // On for new configs, off for configs saved before it existed, so their backups don't shrink unannounced
+    #[nserde(default)]
+    pub skip_cachedir: bool,
+    // Skip files with the nodump attribute (Linux only), on for new configs like 'skip_cachedir'
+    #[nserde(default)]
+    pub skip_nodump: bool,
+    // Don't descend into mount points or directories on another device, see files::mounts
+    #[nserde(default)]
+    pub one_file_system: bool,
+    // What to do with symbolic links, see files::links
+    #[nserde(default)]
+    pub symlinks: SymlinkPolicy,
+    // Retention policy for old versions, see retention.rs, 0 disables a rule
+    // Keep the newest N versions of every file
+    #[nserde(default)]
+    pub retention_last: u32,
+    // Keep the newest version of each of the last N days, weeks and months
+    #[nserde(default)]
+    pub retention_daily: u32,
+    #[nserde(default)]
+    pub retention_weekly: u32,
+    #[nserde(default)]
+    pub retention_monthly: u32,
+}
+
+/// How backed up files are stored in the bucket
+/// Files: every file is uploaded as-is under its own path, a changed file is uploaded again in full
+/// Chunked: files are split into chunks that are stored once by hash, plus a manifest per upload
+///     See files::chunked for details
+#[derive(Debug,Clone,Copy,PartialEq,Default,DeJson,SerJson)]
+pub enum RepositoryMode {
+    #[default]
+    Files,
+    Chunked,
+}
+
+/// What to do with symbolic links, see files::links
+/// Skip: leave them out
+/// Store: back up the link itself, it is restored as a link
+/// Follow: back up what they point to, descending into linked directories
+#[derive(Debug,Clone,Copy,PartialEq,Default,DeJson,SerJson)]
+pub enum SymlinkPolicy {
+    #[default]
+    Skip,
+    Store,
+    Follow,
 }
 
 /// Used by the options menu to hold user input
@@ -151,6 +265,18 @@ pub struct GUIConfigStrings {
     pub app_key: String,
     pub bucket_id: String,
     pub bandwidth_limit: String,
+    pub pack_threshold: String,
+    pub purge_max_percent: String,
+    pub purge_max_count: String,
+    pub purge_grace_days: String,
+    pub retention_last: String,
+    pub retention_daily: String,
+    pub retention_weekly: String,
+    pub retention_monthly: String,
+    pub remote_prefix: String,
+    pub filter_max_size: String,
+    pub filter_max_age: String,
+    pub filter_types: String,
 }
 
 impl GUIConfigStrings {
@@ -163,6 +289,73 @@ impl GUIConfigStrings {
             app_key: cfg.app_key.to_string(),
             bucket_id: cfg.bucket_id.to_string(),
             bandwidth_limit: (cfg.bandwidth_limit/1000).to_string(), // Divide by 1000 to get KB/s from B/s
+            pack_threshold: cfg.pack_threshold.to_string(),
+            purge_max_percent: cfg.purge_max_percent.to_string(),
+            purge_max_count: cfg.purge_max_count.to_string(),
+            purge_grace_days: cfg.purge_grace_days.to_string(),
+            retention_last: cfg.retention_last.to_string(),
+            retention_daily: cfg.retention_daily.to_string(),
+            retention_weekly: cfg.retention_weekly.to_string(),
+            retention_monthly: cfg.retention_monthly.to_string(),
+            remote_prefix: cfg.remote_prefix.clone().unwrap_or_default(),
+            filter_max_size: cfg.filter_max_size.to_string(),
+            filter_max_age: cfg.filter_max_age.to_string(),
+            filter_types: cfg.filter_types.join(", "),
+        }
+    }
+
+    // Returns the input for the field with the given index, see 'active_field'
+    pub fn field(&self, index: usize) -> &str {
+        match index {
+            1 => &self.font_size,
+            2 => &self.scroll_factor,
+            3 => &self.app_key_id,
+            4 => &self.app_key,
+            5 => &self.bucket_id,
+            6 => &self.bandwidth_limit,
+            7 => &self.pack_threshold,
+            8 => &self.purge_max_percent,
+            9 => &self.purge_max_count,
+            10 => &self.purge_grace_days,
+            11 => &self.retention_last,
+            12 => &self.retention_daily,
+            13 => &self.retention_weekly,
+            14 => &self.retention_monthly,
+            15 => &self.remote_prefix,
+            16 => &self.filter_max_size,
+            17 => &self.filter_max_age,
+            18 => &self.filter_types,
+            _ => "",
+        }
+    }
+
+    // Whether the field with the given index holds a list, the only fields that take commas and spaces
+    pub fn is_list(index: usize) -> bool {
+        index == 18
+    }
+
+    // Mutable version of 'field', returns None if no field has the given index
+    pub fn field_mut(&mut self, index: usize) -> Option<&mut String> {
+        match index {
+            1 => Some(&mut self.font_size),
+            2 => Some(&mut self.scroll_factor),
+            3 => Some(&mut self.app_key_id),
+            4 => Some(&mut self.app_key),
+            5 => Some(&mut self.bucket_id),
+            6 => Some(&mut self.bandwidth_limit),
+            7 => Some(&mut self.pack_threshold),
+            8 => Some(&mut self.purge_max_percent),
+            9 => Some(&mut self.purge_max_count),
+            10 => Some(&mut self.purge_grace_days),
+            11 => Some(&mut self.retention_last),
+            12 => Some(&mut self.retention_daily),
+            13 => Some(&mut self.retention_weekly),
+            14 => Some(&mut self.retention_monthly),
+            15 => Some(&mut self.remote_prefix),
+            16 => Some(&mut self.filter_max_size),
+            17 => Some(&mut self.filter_max_age),
+            18 => Some(&mut self.filter_types),
+            _ => None,
         }
     }
 
@@ -190,9 +383,61 @@ impl GUIConfigStrings {
         if let Ok(n) = fs {cfg.bandwidth_limit = n.min(1000000)*1000;} // Multiply by 1000 to get B/s from KB/s
 
         self.bandwidth_limit = (cfg.bandwidth_limit/1000).to_string();
+
+        // Packs are 16 MB, so packing files larger than a few MB makes no sense
+        let s = self.pack_threshold.trim();
+        let fs = u32::from_str(s);
+        if let Ok(n) = fs {cfg.pack_threshold = n.min(4096);}
+        self.pack_threshold = cfg.pack_threshold.to_string();
+
+        let s = self.purge_max_percent.trim();
+        let fs = u32::from_str(s);
+        if let Ok(n) = fs {cfg.purge_max_percent = n.min(100);}
+        self.purge_max_percent = cfg.purge_max_percent.to_string();
+
+        let s = self.purge_max_count.trim();
+        let fs = u32::from_str(s);
+        if let Ok(n) = fs {cfg.purge_max_count = n;}
+        self.purge_max_count = cfg.purge_max_count.to_string();
+
+        let s = self.purge_grace_days.trim();
+        let fs = u32::from_str(s);
+        if let Ok(n) = fs {cfg.purge_grace_days = n.min(3650);}
+        self.purge_grace_days = cfg.purge_grace_days.to_string();
+
+        destring_count(&mut self.retention_last, &mut cfg.retention_last, 10000);
+        destring_count(&mut self.retention_daily, &mut cfg.retention_daily, 10000);
+        destring_count(&mut self.retention_weekly, &mut cfg.retention_weekly, 10000);
+        destring_count(&mut self.retention_monthly, &mut cfg.retention_monthly, 10000);
+
+        let s = self.remote_prefix.trim().trim_matches('/');
+        cfg.remote_prefix = Some(s.to_string());
+        self.remote_prefix = s.to_string();
+
+        let s = self.filter_max_size.trim();
+        let fs = u32::from_str(s);
+        if let Ok(n) = fs {cfg.filter_max_size = n;}
+        self.filter_max_size = cfg.filter_max_size.to_string();
+
+        let s = self.filter_max_age.trim();
+        let fs = u32::from_str(s);
+        if let Ok(n) = fs {cfg.filter_max_age = n.min(36500);}
+        self.filter_max_age = cfg.filter_max_age.to_string();
+
+        // Separated by commas or spaces
+        cfg.filter_types = self.filter_types.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|t| !t.is_empty())
+            .map(|t| t.to_lowercase())
+            .collect();
+        self.filter_types = cfg.filter_types.join(", ");
     }
 }
 
+// Updates 'value' from the input if it is a number, capped at 'max', and shows the value in effect
+fn destring_count(input: &mut String, value: &mut u32, max: u32) {
+    if let Ok(n) = u32::from_str(input.trim()) {*value = n.min(max);}
+    *input = value.to_string();
+}
 
 impl GUIConfig {
     /// Instance a UIConfig from the given file, or a default if no such file exists
@@ -201,10 +446,22 @@ impl GUIConfig {
             Ok(s) => s,
             Err(_e) => return Self::default(),
         };
-        match DeJson::deserialize_json(&json) {
+        let mut cfg: Self = match DeJson::deserialize_json(&json) {
             Ok(s) => s,
             Err(_e) => Self::default()
+        };
+        // Configs from before remote prefixes existed keep storing files at the top of the bucket, where their backups are
+        // Moving them under a prefix is up to the user, see migrate.rs. New configs get the default prefix
+        if cfg.remote_prefix.is_none() {
+            println!("No remote prefix is set, files stay at the top of the bucket. To store them under {:?}, set it in the advanced options \
+                      and run 'migrate-prefix'", crate::files::naming::default_prefix());
+            cfg.remote_prefix = Some(String::new());
+        }
+        for problem in crate::files::naming::mapping_problems(&cfg.path_mappings) {
+            println!("{}", problem);
         }
+        crate::files::apply_config(&cfg);
+        cfg
     }
 }
 
@@ -219,6 +476,27 @@ impl Default for GUIConfig {
             bandwidth_limit: 0,
             hide_file_names: false,
             consented: false,
+            compression: false,
+            repository_mode: RepositoryMode::Files,
+            pack_threshold: 0,
+            purge_max_percent: 10,
+            purge_max_count: 1000,
+            purge_grace_days: 7,
+            purge_prefixes: vec![],
+            retention_last: 0,
+            retention_daily: 0,
+            retention_weekly: 0,
+            retention_monthly: 0,
+            remote_prefix: Some(crate::files::naming::default_prefix()),
+            path_mappings: vec![],
+            respect_gitignore: false,
+            filter_max_size: 0,
+            filter_max_age: 0,
+            filter_types: vec![],
+            skip_cachedir: true,
+            skip_nodump: true,
+            one_file_system: false,
+            symlinks: SymlinkPolicy::Skip,
         }
     }
 }
@@ -2,7 +2,7 @@ use wgpu::BufferUsage;
 use zerocopy::AsBytes;
 
 use crate::gui::{GuiProgram, Vertex};
-use crate::ui::UIState;
+use crate::ui::{GUIConfigStrings, UIState};
 use crate::ui::align::Anchor;
 use winit::event::{VirtualKeyCode, ModifiersState};
 
@@ -39,6 +39,14 @@ pub fn render(
     }
 
     vertices.append(&mut gui.align.rectangle(Anchor::CenterGlobal, 173.0, 248.0,173.0,175.0, [0.8,0.8,0.8,1.0]));
+    // 'Advanced' button
+    vertices.append(&mut gui.align.rectangle(Anchor::TopLeft, 0.0, 0.0, 160.0, 32.0, [0.2,0.2,0.2,1.0]));
+    // 'Snapshots' button
+    vertices.append(&mut gui.align.rectangle(Anchor::TopLeft, 0.0, 40.0, 160.0, 32.0, [0.2,0.2,0.2,1.0]));
+    // 'Undo purge' button
+    vertices.append(&mut gui.align.rectangle(Anchor::TopLeft, 0.0, 80.0, 160.0, 32.0, [0.2,0.2,0.2,1.0]));
+    // 'Rules' button
+    vertices.append(&mut gui.align.rectangle(Anchor::TopLeft, 0.0, 120.0, 160.0, 32.0, [0.2,0.2,0.2,1.0]));
 
 
 
@@ -98,6 +106,15 @@ pub fn render(
     th.draw_centered("Options", gui.align.win_width/2.0, gui.align.win_height/2.0 - 300.0,
                                                                  96.0, f32::INFINITY, [0.05,0.05,0.05,1.0]);
 
+    th.draw_centered("Advanced", 80.0, 16.0,
+                     24.0, 160.0, [0.95,0.95,0.95,1.0]);
+    th.draw_centered("Snapshots", 80.0, 56.0,
+                     24.0, 160.0, [0.95,0.95,0.95,1.0]);
+    th.draw_centered("Undo purge", 80.0, 96.0,
+                     24.0, 160.0, [0.95,0.95,0.95,1.0]);
+    th.draw_centered("Rules", 80.0, 136.0,
+                     24.0, 160.0, [0.95,0.95,0.95,1.0]);
+
     // Draw options
     th.draw_centered("Font size", gui.align.win_width/2.0 - 150.0, gui.align.win_height/2.0 - 200.0 ,
                                                                  24.0, f32::INFINITY, [0.05,0.05,0.05,1.0]);
@@ -238,6 +255,24 @@ pub fn handle_click(gui: &mut GuiProgram) -> Option<UIState> {
     if gui.align.was_area_clicked(Anchor::TopRight, gui.state_manager.cx, gui.state_manager.cy, 0.0, 0.0, 64.0, 32.0) {
         gui.save_config();
         return Some(UIState::Main)
+    } else if gui.align.was_area_clicked(Anchor::TopLeft, gui.state_manager.cx, gui.state_manager.cy, 0.0, 0.0, 160.0, 32.0) {
+        gui.save_config();
+        gui.state_manager.scroll = 0.0;
+        return Some(UIState::Advanced)
+    } else if gui.align.was_area_clicked(Anchor::TopLeft, gui.state_manager.cx, gui.state_manager.cy, 0.0, 40.0, 160.0, 32.0) {
+        gui.save_config();
+        gui.state_manager.scroll = 0.0;
+        gui.state_manager.diff.open();
+        return Some(UIState::Diff)
+    } else if gui.align.was_area_clicked(Anchor::TopLeft, gui.state_manager.cx, gui.state_manager.cy, 0.0, 80.0, 160.0, 32.0) {
+        gui.save_config();
+        crate::ui::purge::start_undo_thread(gui);
+        return Some(UIState::Purge)
+    } else if gui.align.was_area_clicked(Anchor::TopLeft, gui.state_manager.cx, gui.state_manager.cy, 0.0, 120.0, 160.0, 32.0) {
+        gui.save_config();
+        gui.state_manager.scroll = 0.0;
+        gui.state_manager.rules.open();
+        return Some(UIState::Rules)
     } else if gui.align.was_area_clicked(Anchor::CenterGlobal, gui.state_manager.cx, gui.state_manager.cy, 173.0, 248.0, 173.0, 175.0,) {
         gui.save_config();
         crate::ui::purge::start_purge_thread(gui);
@@ -247,17 +282,12 @@ pub fn handle_click(gui: &mut GuiProgram) -> Option<UIState> {
 }
 
 pub fn handle_keypress(gui: &mut GuiProgram, key: &VirtualKeyCode, mods: &ModifiersState) {
+    let active_field = gui.state_manager.strings.active_field;
     match key {
         // Backspace key
         VirtualKeyCode::Back => {
-            match gui.state_manager.strings.active_field {
-                1 => {gui.state_manager.strings.font_size.pop();},
-                2 => {gui.state_manager.strings.scroll_factor.pop();},
-                3 => {gui.state_manager.strings.app_key_id.pop();},
-                4 => {gui.state_manager.strings.app_key.pop();},
-                5 => {gui.state_manager.strings.bucket_id.pop();},
-                6 => {gui.state_manager.strings.bandwidth_limit.pop();},
-                _ => ()
+            if let Some(s) = gui.state_manager.strings.field_mut(active_field) {
+                s.pop();
             }
         },
         _ => {
@@ -299,22 +329,21 @@ pub fn handle_keypress(gui: &mut GuiProgram, key: &VirtualKeyCode, mods: &Modifi
                 VirtualKeyCode::Key7 => '7',
                 VirtualKeyCode::Key8 => '8',
                 VirtualKeyCode::Key9 => '9',
+                VirtualKeyCode::Comma => ',',
+                VirtualKeyCode::Space => ' ',
                 _ => return,
             };
+            if (ch == ',' || ch == ' ') && !GUIConfigStrings::is_list(active_field) {
+                return;
+            }
             if mods.ctrl() && ch == 'v' {
                 let ctx: Result<ClipboardContext, Box<dyn Error>>  = ClipboardProvider::new();
                 match ctx {
                     Ok(mut c) => {
                         match c.get_contents() {
                             Ok(s) => {
-                                match gui.state_manager.strings.active_field {
-                                    1 => {gui.state_manager.strings.font_size.push_str(&s);},
-                                    2 => {gui.state_manager.strings.scroll_factor.push_str(&s);},
-                                    3 => {gui.state_manager.strings.app_key_id.push_str(&s);},
-                                    4 => {gui.state_manager.strings.app_key.push_str(&s);},
-                                    5 => {gui.state_manager.strings.bucket_id.push_str(&s);},
-                                    6 => {gui.state_manager.strings.bandwidth_limit.push_str(&s);},
-                                    _ => ()
+                                if let Some(field) = gui.state_manager.strings.field_mut(active_field) {
+                                    field.push_str(&s);
                                 }
                             },
                             Err(_e) => ()
@@ -324,14 +353,8 @@ pub fn handle_keypress(gui: &mut GuiProgram, key: &VirtualKeyCode, mods: &Modifi
                 };
             } else {
                 if mods.shift() { ch = ch.to_ascii_uppercase(); }
-                match gui.state_manager.strings.active_field {
-                    1 => {gui.state_manager.strings.font_size.push(ch);},
-                    2 => {gui.state_manager.strings.scroll_factor.push(ch);},
-                    3 => {gui.state_manager.strings.app_key_id.push(ch);},
-                    4 => {gui.state_manager.strings.app_key.push(ch);},
-                    5 => {gui.state_manager.strings.bucket_id.push(ch);},
-                    6 => {gui.state_manager.strings.bandwidth_limit.push(ch);},
-                    _ => ()
+                if let Some(field) = gui.state_manager.strings.field_mut(active_field) {
+                    field.push(ch);
                 }
             }
         }
@@ -1,20 +1,87 @@
-use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
 
-use scoped_pool::Pool;
 use wgpu::BufferUsage;
 use zerocopy::AsBytes;
-use std::sync::mpsc::Sender;
 
-use crate::gui::GuiProgram;
+use crate::files::manifest;
+use crate::gui::{GuiProgram, Vertex};
+use crate::purge::{ItemKind, PurgeItem, PurgePlan};
+use crate::ui::UIState;
 use crate::ui::align::Anchor;
 
+// Purge page, reached from the options menu
+// While the purge is planned and while it runs, a progress indicator is shown
+// In between, the plan is shown for review: every file can be unticked, or a whole directory by clicking its line
+// Nothing is hidden until the purge is confirmed
+// If the plan has warnings (see PurgePlan::warnings), the 'Confirm' button first has to be clicked as 'Override'
+// The progress indicator is also shown while undoing the last purge
+
+const LINE_HEIGHT: f32 = 24.0;
+// Where the list starts, leaves room for the header
+const TOP: f32 = 140.0;
+
+/// Plan being reviewed, shared with the planning thread
+#[derive(Default)]
+pub struct PurgeState {
+    // Set by the planning thread once the plan is ready, taken when the purge is confirmed or cancelled
+    plan: Arc<Mutex<Option<PurgePlan>>>,
+    // Whether the purge has been confirmed, and is running
+    confirmed: bool,
+    // Whether the user chose to purge despite the warnings of the plan
+    overridden: bool,
+    // Whether the last purge is being undone, rather than a new one planned
+    undoing: bool,
+}
+
+// A line in the review list
+// Dir: a directory header, with the range of items in it
+// Item: a single item, by index
+enum Line {
+    Dir(usize, usize),
+    Item(usize),
+}
+
+// Lays out the items as lines, with a header before the items of each directory
+fn lines(items: &[PurgeItem]) -> Vec<Line> {
+    let mut lines = vec![];
+    for i in 0..items.len() {
+        if i == 0 || items[i].dir() != items[i-1].dir() {
+            let end = i + items[i..].iter().take_while(|x| x.dir() == items[i].dir()).count();
+            lines.push(Line::Dir(i, end));
+        }
+        lines.push(Line::Item(i));
+    }
+    lines
+}
+
 pub fn render(
     gui: &mut GuiProgram,
     frame: &wgpu::SwapChainOutput,
     device: &wgpu::Device,
 ) -> Vec<wgpu::CommandBuffer> {
+    let plan = gui.state_manager.purge.plan.clone();
+    let plan = plan.lock().unwrap();
+    match &*plan {
+        Some(plan) if !gui.state_manager.purge.confirmed => render_review(gui, plan, frame, device),
+        _ => {
+            let title = if gui.state_manager.purge.undoing {
+                "Restoring purged files..."
+            } else if gui.state_manager.purge.confirmed {
+                "Clearing unused files..."
+            } else {
+                "Looking for unused files..."
+            };
+            render_progress(gui, title, frame, device)
+        },
+    }
+}
+
+fn render_progress(
+    gui: &mut GuiProgram,
+    title: &str,
+    frame: &wgpu::SwapChainOutput,
+    device: &wgpu::Device,
+) -> Vec<wgpu::CommandBuffer> {
 
     // Images
     let mut encoder =
@@ -72,7 +139,7 @@ pub fn render(
         );
     }
 
-    gui.state_manager.text_handler.lock().unwrap().draw_centered("Clearing unused files...", gui.align.win_width/2.0, gui.align.win_height/2.0 - 300.0,
+    gui.state_manager.text_handler.lock().unwrap().draw_centered(title, gui.align.win_width/2.0, gui.align.win_height/2.0 - 300.0,
                                                                  96.0, f32::INFINITY, [0.05,0.05,0.05,1.0]);
     gui.state_manager.text_handler.lock().unwrap().draw_centered("In progress, please wait", gui.align.win_width/2.0, gui.align.win_height/2.0 + 300.0,
                                                                  96.0, f32::INFINITY, [0.05,0.05,0.05,1.0]);
@@ -84,115 +151,265 @@ pub fn render(
     vec![cb1,cb2]
 }
 
-// Start the purge thread to run in the background
-pub fn start_purge_thread(gui: &mut GuiProgram) {
-    println!("Start purge");
 
-    let q = gui.state_manager.upload_state.queue.clone();
-    let bid = gui.state_manager.config.bucket_id.clone();
-    let tx = gui.state_manager.status_channel_tx.clone();
-    let keystring = format!("{}:{}", gui.state_manager.config.app_key_id, gui.state_manager.config.app_key);
+fn render_review(
+    gui: &GuiProgram,
+    plan: &PurgePlan,
+    frame: &wgpu::SwapChainOutput,
+    device: &wgpu::Device,
+) -> Vec<wgpu::CommandBuffer> {
+    let lines = lines(&plan.items);
+    let warnings = plan.warnings();
+    let needs_override = !warnings.is_empty() && !gui.state_manager.purge.overridden;
+    let visible = |i: usize| {
+        let y = TOP + gui.state_manager.scroll + LINE_HEIGHT * i as f32;
+        (y, y + LINE_HEIGHT >= TOP && y <= gui.align.win_height)
+    };
 
-    std::thread::spawn(move || purge_task(q, bid, tx, keystring));
-}
+    ///// Polygons
+    let mut vertices = vec![];
+    // Checkboxes, filled when selected
+    for (i, line) in lines.iter().enumerate() {
+        let (y, visible) = visible(i);
+        if !visible {
+            continue;
+        }
+        let (x, selected) = match line {
+            Line::Dir(start, end) => (10.0, plan.items[*start..*end].iter().all(|x| x.selected)),
+            Line::Item(n) => (34.0, plan.items[*n].selected),
+        };
+        let col = match selected {
+            true => [0.2,0.6,0.2,1.0],
+            false => [0.8,0.8,0.8,1.0],
+        };
+        vertices.append(&mut Vertex::rect(x, y + 4.0, 16.0, 16.0, col));
+    }
+    // Header background, covers lines scrolled above the list
+    vertices.append(&mut Vertex::rect(0.0, 0.0, gui.align.win_width, TOP, [1.0,1.0,1.0,1.0]));
+    // 'Cancel' and 'Confirm' buttons
+    vertices.append(&mut gui.align.rectangle(Anchor::TopLeft, 0.0, 0.0, 160.0, 32.0, [0.2,0.2,0.2,1.0]));
+    let confirm_col = match needs_override {
+        true => [0.6,0.1,0.1,1.0],
+        false => [0.2,0.5,0.2,1.0],
+    };
+    vertices.append(&mut gui.align.rectangle(Anchor::TopRight, 0.0, 0.0, 160.0, 32.0, confirm_col));
+
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+
+    {
+        let buffer = device.create_buffer_with_data(vertices.as_bytes(), BufferUsage::VERTEX);
+
+        let rpass_color_attachment = {
+            wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &frame.view,
+                resolve_target: None,
+                load_op: wgpu::LoadOp::Clear,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::WHITE,
+            }
+        };
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[rpass_color_attachment],
+            depth_stencil_attachment: None,
+        });
 
-fn purge_task(q: Arc<Mutex<Vec<PathBuf>>>, bid: String, tx: Sender<String>, keystring: String) {
-    // Get local files
-    // Make sure the filetree is exactly the stored list
-    let root = crate::files::get_roots().unwrap();
-    match root.deserialize("backuplist.dat") {
-        Ok(_) => {},
-        Err(e) => {
-            println!("Error reading backuplist.dat - Reason: {:?}", e);
-            tx.send("Got no files for upload, make sure you've selected some".to_string()).unwrap();
-            return
+        rpass.set_pipeline(&gui.pipeline);
+        rpass.set_bind_group(0, &gui.uniforms, &[]);
+        rpass.set_vertex_buffer(0, &buffer, 0, 0);
+
+        rpass.draw(0..vertices.len() as u32, 0..1);
+    }
+
+    let cb1 = encoder.finish();
+
+    ///// Text
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("Text") });
+
+    {
+        let _ = encoder.begin_render_pass(
+            &wgpu::RenderPassDescriptor {
+                color_attachments: &[
+                    wgpu::RenderPassColorAttachmentDescriptor {
+                        attachment: &frame.view,
+                        resolve_target: None,
+                        load_op: wgpu::LoadOp::Load,
+                        store_op: wgpu::StoreOp::Store,
+                        clear_color: wgpu::Color::WHITE,
+                    },
+                ],
+                depth_stencil_attachment: None,
+            },
+        );
+    }
+
+    let mut th = gui.state_manager.text_handler.lock().unwrap();
+    th.draw_centered("Review purge", gui.align.win_width/2.0, 40.0,
+                     48.0, f32::INFINITY, [0.05,0.05,0.05,1.0]);
+    th.draw_centered("Cancel", 80.0, 16.0,
+                     24.0, 160.0, [0.95,0.95,0.95,1.0]);
+    th.draw_centered(if needs_override { "Override" } else { "Confirm" }, gui.align.win_width - 80.0, 16.0,
+                     24.0, 160.0, [0.95,0.95,0.95,1.0]);
+    let (count, size) = plan.selected();
+    // Backups the retention rules retire are hidden along with the files, so how many is shown before confirming
+    let retired = match plan.retired() {
+        0 => String::new(),
+        n => format!(", {} of them old backups the retention rules no longer keep", n),
+    };
+    let summary = format!("{} of {} files selected ({}){}, the bucket holds {} files",
+                          count, plan.items.len(), manifest::format_size(size), retired, plan.remote_count);
+    th.draw_centered(&summary, gui.align.win_width/2.0, 90.0,
+                     24.0, gui.align.win_width - 20.0, [0.05,0.05,0.05,1.0]);
+    let waiting = match plan.waiting {
+        0 => String::new(),
+        n => format!(", {} recently missing files are kept for now", n),
+    };
+    match warnings.first() {
+        Some(w) => {
+            let more = match warnings.len() {
+                1 => String::new(),
+                n => format!(" (and {} more)", n - 1),
+            };
+            th.draw_centered(&format!("Warning: {}{}", w, more), gui.align.win_width/2.0, 118.0,
+                             20.0, gui.align.win_width - 20.0, [0.7,0.1,0.1,1.0]);
         },
+        None => th.draw_centered(&format!("Click a file to untick it, or a directory to untick everything in it{}", waiting), gui.align.win_width/2.0, 118.0,
+                                 20.0, gui.align.win_width - 20.0, [0.3,0.3,0.3,1.0]),
     }
-    root.get_files_for_upload(&q);
-
-    // Collect all files that are supposed to be uploaded
-    // On Unix, all paths start with '/' (the root). B2 will not emulate folders if we start file
-    // paths with a slash, so we remove it during the upload process. 
-    // This naturally means we have to remove it here to compare
-    let lf = q.lock().unwrap();
-    let mut local_files: Vec<String>;
-    if cfg!(windows) {
-        local_files = lf.iter().map(|x| x.to_string_lossy().replace("\\", "/")).collect();
-    } else {
-        local_files = lf.iter().map(|x| x.to_string_lossy().replace("\\", "/")[1..].to_string()).collect();
+
+    for (i, line) in lines.iter().enumerate() {
+        let (y, visible) = visible(i);
+        // Skip lines hidden behind the header, text isn't covered by it
+        if !visible || y < TOP {
+            continue;
+        }
+        match line {
+            Line::Dir(start, end) => {
+                let dir = plan.items[*start].dir();
+                let size: u64 = plan.items[*start..*end].iter().map(|x| x.size).sum();
+                let text = format!("{} ({} files, {})", if dir.is_empty() { "/" } else { dir }, end - start, manifest::format_size(size));
+                th.draw(&text, 34.0, y, 20.0, gui.align.win_width - 44.0, [0.05,0.05,0.05,1.0]);
+            },
+            Line::Item(n) => {
+                let item = &plan.items[*n];
+                let packed = match item.kind {
+                    ItemKind::Object => "",
+                    ItemKind::Packed => "  (packed)",
+                };
+                let text = format!("{}  {}{}", &item.name[item.dir().len()..], manifest::format_size(item.size), packed);
+                th.draw(&text, 58.0, y, 20.0, gui.align.win_width - 68.0, [0.2,0.2,0.2,1.0]);
+            },
+        }
     }
-    local_files.sort();
-    println!("Collected local files");
 
-    // Get list of files on server
-    let client = reqwest::blocking::Client::builder().timeout(Duration::from_secs_f32(30.0)).build().unwrap();
+    th.flush(device, &mut encoder, frame, (gui.sc_desc.width,gui.sc_desc.height));
 
-    let auth = match raze::api::b2_authorize_account(&client,keystring) {
-        Ok(a) => a,
-        Err(_e) => {
-            tx.send("Authentication Failed".to_string()).unwrap();
-            return;
-        },
-    };
+    let cb2 = encoder.finish();
 
-    // Get list of files stored
-    let remote_files = match raze::util::list_all_files(&client, &auth, &bid, 1000) {
-        Ok(f) => f,
-        Err(e) => {
-            println!("Failed to get remote files - {:?}", e);
-            tx.send("Failed talking to B2 - Check your Bucket ID".to_string()).unwrap();
-            return
-        },
+    vec![cb1,cb2]
+}
+
+pub fn handle_click(gui: &mut GuiProgram) -> Option<UIState> {
+    if gui.state_manager.purge.confirmed || gui.state_manager.purge.undoing {
+        return None;
+    }
+    let slot = gui.state_manager.purge.plan.clone();
+    let mut plan = slot.lock().unwrap();
+    let items = match &mut *plan {
+        Some(p) => &mut p.items,
+        None => return None, // Still planning
     };
-    println!("Collected remote files");
-
-    // Compare the two lists:
-    // Check each file in the cloud; if it isn't in the upload list, queue it for hiding
-    let mut hide_list = vec![];
-    for file in remote_files {
-        match local_files.binary_search(&file.file_name) {
-            Ok(_) => (),
-            Err(_) => hide_list.push(file.file_name),
+
+    if gui.align.was_area_clicked(Anchor::TopLeft, gui.state_manager.cx, gui.state_manager.cy, 0.0, 0.0, 160.0, 32.0) {
+        *plan = None;
+        gui.state_manager.scroll = 0.0;
+        return Some(UIState::Options)
+    } else if gui.align.was_area_clicked(Anchor::TopRight, gui.state_manager.cx, gui.state_manager.cy, 0.0, 0.0, 160.0, 32.0) {
+        let warnings = plan.as_ref().unwrap().warnings();
+        if !warnings.is_empty() && !gui.state_manager.purge.overridden {
+            println!("Purge warnings overridden - {:?}", warnings);
+            gui.state_manager.purge.overridden = true;
+            return None
         }
+        let plan = plan.take().unwrap();
+        gui.state_manager.purge.confirmed = true;
+        gui.state_manager.scroll = 0.0;
+        let tx = gui.state_manager.status_channel_tx.clone();
+        std::thread::spawn(move || {
+            let msg = match crate::purge::execute(plan) {
+                Ok(0) => "Purge completed".to_string(),
+                Ok(failed) => format!("Purge completed, {} files could not be hidden", failed),
+                Err(e) => {
+                    println!("Purge failed - {}", e);
+                    "Purge failed, nothing was hidden".to_string()
+                },
+            };
+            tx.send(msg).unwrap();
+        });
+        return None
     }
-    println!("Ready to hide {} files", hide_list.len());
-    let hide_list = Arc::new(Mutex::new(hide_list));
-
-
-    let pool = Pool::new(16);
-    // Spawn hide threads
-    pool.scoped(|scope| {
-        for _i in 0..pool.workers() {
-            let hl = hide_list.clone();
-            let bid = bid.clone();
-            let client = &client;
-            let auth = &auth;
-            scope.execute(move || {
-                loop {
-                    let p = {
-                        hl.lock().unwrap().pop()
-                    };
-                    let file = match p {
-                        Some(f) => f,
-                        None => break, // No more files to hide
-                    };
-
-                    println!("Hiding {:?}", file);
-                    for _i in 0..5 {
-                        let res = raze::api::b2_hide_file(&client, &auth, &bid, &file);
-                        match res {
-                            Ok(_) => break, // Break on success = do not retry
-                            Err(e) => { // Continue on failure = retry
-                                println!("Err {:?}, retrying {:?}", e, file);
-                                continue
-                            },
-                        }
-                    }
-                }
-            });
+
+    // Lines hidden behind the header can't be clicked
+    if gui.state_manager.cy < TOP {
+        return None;
+    }
+    let i = ((gui.state_manager.cy - TOP - gui.state_manager.scroll) / LINE_HEIGHT) as usize;
+    match lines(items).get(i) {
+        Some(Line::Dir(start, end)) => {
+            let select = !items[*start..*end].iter().all(|x| x.selected);
+            for item in items[*start..*end].iter_mut() {
+                item.selected = select;
+            }
+        },
+        Some(Line::Item(n)) => items[*n].selected = !items[*n].selected,
+        None => (),
+    }
+    None
+}
+
+// Returns the maximum amount that we can scroll down
+pub fn compute_max_scroll(gui: &GuiProgram) -> f32 {
+    let lines = match &*gui.state_manager.purge.plan.lock().unwrap() {
+        Some(p) => lines(&p.items).len(),
+        None => 0,
+    };
+    (TOP + LINE_HEIGHT * lines as f32 - gui.align.win_height).max(0.0)
+}
+
+// Start planning the purge in the background, the plan is shown for review once it's ready
+pub fn start_purge_thread(gui: &mut GuiProgram) {
+    println!("Start purge");
+
+    let slot = Arc::new(Mutex::new(None));
+    gui.state_manager.purge = PurgeState { plan: slot.clone(), ..Default::default() };
+    gui.state_manager.scroll = 0.0;
+    let cfg = gui.state_manager.config.clone();
+    let tx = gui.state_manager.status_channel_tx.clone();
+
+    std::thread::spawn(move || {
+        match crate::purge::plan(&cfg) {
+            Ok(plan) if plan.items.is_empty() => tx.send(plan.nothing_to_purge()).unwrap(),
+            Ok(plan) => *slot.lock().unwrap() = Some(plan),
+            Err(e) => tx.send(e).unwrap(),
         }
     });
+}
+
+// Undo the last purge in the background, see purge::undo_last
+pub fn start_undo_thread(gui: &mut GuiProgram) {
+    println!("Undo purge");
 
-    println!("Done purging");
-    tx.send("Purge completed".to_string()).unwrap();
+    gui.state_manager.purge = PurgeState { undoing: true, ..Default::default() };
+    let cfg = gui.state_manager.config.clone();
+    let tx = gui.state_manager.status_channel_tx.clone();
+
+    std::thread::spawn(move || {
+        let msg = match crate::purge::undo_last(&cfg) {
+            Ok(n) => format!("Undo completed, restored {} files", n),
+            Err(e) => e,
+        };
+        tx.send(msg).unwrap();
+    });
 }
@@ -0,0 +1,255 @@
+use wgpu::BufferUsage;
+use zerocopy::AsBytes;
+
+use clipboard::ClipboardProvider;
+use clipboard::ClipboardContext;
+use std::error::Error;
+
+use crate::files::patterns::{self, Pattern};
+use crate::gui::{GuiProgram, Vertex};
+use crate::ui::UIState;
+use crate::ui::align::Anchor;
+
+// Pattern rules page, reached from the options menu
+// Lists the glob and regex rules that exclude files everywhere in the selection, see files::patterns
+// A rule is typed into the box and added with one of the buttons (Enter adds a glob), clicking the red box removes it
+// Changes are written to backuplist.dat right away
+
+const LINE_HEIGHT: f32 = 28.0;
+const INPUT_WIDTH: f32 = 500.0;
+const BUTTON_WIDTH: f32 = 120.0;
+const BOX_HEIGHT: f32 = 32.0;
+const BOX_Y: f32 = 70.0;
+// Where the list starts, leaves room for the header
+const TOP: f32 = 150.0;
+
+/// The rules being edited and the text typed so far
+#[derive(Default)]
+pub struct RulesState {
+    // Pattern rule lines, as stored in backuplist.dat
+    rules: Vec<String>,
+    input: String,
+    // Result of the last change, e.g. why a pattern was rejected
+    message: String,
+}
+
+impl RulesState {
+    /// Loads the rules from backuplist.dat
+    pub fn open(&mut self) {
+        self.rules = patterns::read("backuplist.dat");
+        self.input.clear();
+        self.message = match self.rules.is_empty() {
+            true => "No rules yet, e.g. **/node_modules/ or *.tmp".to_string(),
+            false => String::new(),
+        };
+    }
+
+    fn add(&mut self, regex: bool) {
+        let input = self.input.trim();
+        let pattern = match regex {
+            true => Pattern::regex(input),
+            false => Pattern::glob(input),
+        };
+        match pattern {
+            Ok(p) if self.rules.iter().any(|r| r == p.rule()) => {
+                self.message = format!("{} is already a rule", p.rule());
+            },
+            Ok(p) => {
+                self.rules.push(p.rule().to_string());
+                self.input.clear();
+                self.save();
+            },
+            Err(e) => self.message = e,
+        }
+    }
+
+    fn remove(&mut self, i: usize) {
+        if i < self.rules.len() {
+            self.rules.remove(i);
+            self.save();
+        }
+    }
+
+    fn save(&mut self) {
+        self.message = match patterns::write("backuplist.dat", &self.rules) {
+            Ok(_) => format!("Saved {} rules", self.rules.len()),
+            Err(e) => format!("Failed to save the rules - {}", e),
+        };
+    }
+}
+
+pub fn render(
+    gui: &mut GuiProgram,
+    frame: &wgpu::SwapChainOutput,
+    device: &wgpu::Device,
+) -> Vec<wgpu::CommandBuffer> {
+    let input_x = gui.align.win_width/2.0 - (INPUT_WIDTH + 2.0*BUTTON_WIDTH + 20.0)/2.0;
+
+    ///// Polygons
+    let mut vertices = vec![];
+    vertices.append(&mut Vertex::rect(input_x, BOX_Y, INPUT_WIDTH, BOX_HEIGHT, [0.5,0.5,0.5,1.0]));
+    vertices.append(&mut Vertex::rect(input_x + INPUT_WIDTH + 10.0, BOX_Y, BUTTON_WIDTH, BOX_HEIGHT, [0.2,0.2,0.2,1.0]));
+    vertices.append(&mut Vertex::rect(input_x + INPUT_WIDTH + BUTTON_WIDTH + 20.0, BOX_Y, BUTTON_WIDTH, BOX_HEIGHT, [0.2,0.2,0.2,1.0]));
+    for i in 0..gui.state_manager.rules.rules.len() {
+        let y = TOP + gui.state_manager.scroll + LINE_HEIGHT * i as f32;
+        if y < TOP || y > gui.align.win_height {
+            continue;
+        }
+        vertices.append(&mut Vertex::rect(10.0, y + 2.0, 20.0, 20.0, [0.7,0.1,0.1,1.0]));
+    }
+
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+
+    {
+        let buffer = device.create_buffer_with_data(vertices.as_bytes(), BufferUsage::VERTEX);
+
+        let rpass_color_attachment = {
+            wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &frame.view,
+                resolve_target: None,
+                load_op: wgpu::LoadOp::Clear,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::WHITE,
+            }
+        };
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[rpass_color_attachment],
+            depth_stencil_attachment: None,
+        });
+
+        rpass.set_pipeline(&gui.pipeline);
+        rpass.set_bind_group(0, &gui.uniforms, &[]);
+        rpass.set_vertex_buffer(0, &buffer, 0, 0);
+
+        rpass.draw(0..vertices.len() as u32, 0..1);
+    }
+
+    let cb1 = encoder.finish();
+
+    ///// Text
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("Text") });
+
+    {
+        let _ = encoder.begin_render_pass(
+            &wgpu::RenderPassDescriptor {
+                color_attachments: &[
+                    wgpu::RenderPassColorAttachmentDescriptor {
+                        attachment: &frame.view,
+                        resolve_target: None,
+                        load_op: wgpu::LoadOp::Load,
+                        store_op: wgpu::StoreOp::Store,
+                        clear_color: wgpu::Color::WHITE,
+                    },
+                ],
+                depth_stencil_attachment: None,
+            },
+        );
+    }
+
+    let state = &gui.state_manager.rules;
+    let mut th = gui.state_manager.text_handler.lock().unwrap();
+    th.draw_centered("Exclusion rules", gui.align.win_width/2.0, 32.0,
+                     48.0, f32::INFINITY, [0.05,0.05,0.05,1.0]);
+    th.draw_centered(&state.input, input_x + INPUT_WIDTH/2.0, BOX_Y + BOX_HEIGHT/2.0,
+                     20.0, INPUT_WIDTH - 4.0, [0.05,0.05,0.05,1.0]);
+    th.draw_centered("Add glob", input_x + INPUT_WIDTH + 10.0 + BUTTON_WIDTH/2.0, BOX_Y + BOX_HEIGHT/2.0,
+                     20.0, BUTTON_WIDTH, [0.95,0.95,0.95,1.0]);
+    th.draw_centered("Add regex", input_x + INPUT_WIDTH + 20.0 + BUTTON_WIDTH*1.5, BOX_Y + BOX_HEIGHT/2.0,
+                     20.0, BUTTON_WIDTH, [0.95,0.95,0.95,1.0]);
+    th.draw_centered(&state.message, gui.align.win_width/2.0, BOX_Y + BOX_HEIGHT + 24.0,
+                     24.0, gui.align.win_width - 20.0, [0.05,0.05,0.05,1.0]);
+
+    for (i, rule) in state.rules.iter().enumerate() {
+        let y = TOP + gui.state_manager.scroll + LINE_HEIGHT * i as f32;
+        // Skip lines scrolled into the header or below the window
+        if y < TOP || y > gui.align.win_height {
+            continue;
+        }
+        th.draw(rule, 40.0, y, 20.0, gui.align.win_width - 50.0, [0.05,0.05,0.05,1.0]);
+    }
+
+    th.flush(device, &mut encoder, frame, (gui.sc_desc.width,gui.sc_desc.height));
+
+    let cb2 = encoder.finish();
+
+    ///// Images
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    let vertices = gui.align.image(Anchor::TopRight, 0.0, 0.0, 64.0, 32.0, 0.0, Some([0.0,651.0,128.0,64.0]));
+    let buffer = device.create_buffer_with_data(vertices.as_bytes(), BufferUsage::VERTEX);
+
+    let rpass_color_attachment =  {
+        wgpu::RenderPassColorAttachmentDescriptor {
+            attachment: &frame.view,
+            resolve_target: None,
+            load_op: wgpu::LoadOp::Load,
+            store_op: wgpu::StoreOp::Store,
+            clear_color: wgpu::Color::WHITE,
+        }
+    };
+
+    {
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[rpass_color_attachment],
+            depth_stencil_attachment: None,
+        });
+
+        rpass.set_pipeline(&gui.tex_pipeline);
+        rpass.set_bind_group(0, &gui.uniforms, &[]);
+        rpass.set_bind_group(1, &gui.texture_bind_group, &[]);
+        rpass.set_vertex_buffer(0, &buffer, 0, 0);
+
+        rpass.draw(0..vertices.len() as u32, 0..1);
+    }
+
+    let cb3 = encoder.finish();
+
+    vec![cb1,cb2,cb3]
+}
+
+pub fn handle_click(gui: &mut GuiProgram) -> Option<UIState> {
+    let (cx, cy) = (gui.state_manager.cx, gui.state_manager.cy);
+    if gui.align.was_area_clicked(Anchor::TopRight, cx, cy, 0.0, 0.0, 64.0, 32.0) {
+        gui.state_manager.scroll = 0.0;
+        return Some(UIState::Options)
+    }
+
+    let input_x = gui.align.win_width/2.0 - (INPUT_WIDTH + 2.0*BUTTON_WIDTH + 20.0)/2.0;
+    if gui.align.was_area_clicked(Anchor::TopLeft, cx, cy, input_x + INPUT_WIDTH + 10.0, BOX_Y, BUTTON_WIDTH, BOX_HEIGHT) {
+        gui.state_manager.rules.add(false);
+    } else if gui.align.was_area_clicked(Anchor::TopLeft, cx, cy, input_x + INPUT_WIDTH + BUTTON_WIDTH + 20.0, BOX_Y, BUTTON_WIDTH, BOX_HEIGHT) {
+        gui.state_manager.rules.add(true);
+    } else if cy >= TOP && (10.0..30.0).contains(&cx) {
+        let i = ((cy - TOP - gui.state_manager.scroll) / LINE_HEIGHT) as usize;
+        gui.state_manager.rules.remove(i);
+    }
+    None
+}
+
+// Typing goes into the input box, the options menu's key handling only covers letters and digits
+pub fn handle_char(gui: &mut GuiProgram, c: char) {
+    let state = &mut gui.state_manager.rules;
+    match c {
+        // Backspace
+        '\u{8}' => { state.input.pop(); },
+        // Enter
+        '\r' | '\n' => state.add(false),
+        // Ctrl+V
+        '\u{16}' => {
+            let ctx: Result<ClipboardContext, Box<dyn Error>>  = ClipboardProvider::new();
+            if let Ok(s) = ctx.and_then(|mut c| c.get_contents()) {
+                state.input.push_str(s.trim_end_matches(&['\r', '\n'][..]));
+            }
+        },
+        c if c.is_control() => (),
+        c => state.input.push(c),
+    }
+}
+
+// Returns the maximum amount that we can scroll down
+pub fn compute_max_scroll(gui: &GuiProgram) -> f32 {
+    (TOP + LINE_HEIGHT * gui.state_manager.rules.rules.len() as f32 - gui.align.win_height).max(0.0)
+}
@@ -1,5 +1,8 @@
+use std::io::Read;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
 use raze::api::{Sha1Variant};
@@ -8,9 +11,19 @@ use wgpu::BufferUsage;
 use zerocopy::AsBytes;
 use std::sync::mpsc::Sender;
 
+use crate::files::chunked::ChunkedUpload;
+use crate::files::compression;
+use crate::files::filters::Filters;
+use crate::files::hardlinks::HardLinks;
+use crate::files::{links, objects, posix, sparse};
+use crate::files::manifest::{self, Manifest, ManifestEntry};
+use crate::files::naming::Naming;
+use crate::files::packed::PackedUpload;
+use crate::files::sparse::DataReader;
+use crate::files::patterns::Patterns;
 use crate::files::tracked_reader::TrackedReader;
 use crate::gui::{GuiProgram, Vertex};
-use crate::ui::UploadInstance;
+use crate::ui::{RepositoryMode, UploadInstance};
 
 pub fn render(
     gui: &mut GuiProgram,
@@ -79,6 +92,12 @@ pub fn render(
     gui.state_manager.text_handler.lock().unwrap().draw_centered(&format!("Remaining: {}",rem), gui.align.win_width/2.0, gui.align.win_height/2.0 + 300.0,
                                                                  64.0, f32::INFINITY, [0.05,0.05,0.05,1.0]);
 
+    // What the filters skipped, so large files don't go missing unnoticed
+    if let Some(s) = gui.state_manager.upload_state.filters.summary() {
+        gui.state_manager.text_handler.lock().unwrap().draw_centered(&s, gui.align.win_width/2.0, gui.align.win_height/2.0 + 350.0,
+                                                                     24.0, gui.align.win_width - 20.0, [0.05,0.05,0.05,1.0]);
+    }
+
     gui.state_manager.text_handler.lock().unwrap().flush(&device,&mut encoder, frame, (gui.sc_desc.width,gui.sc_desc.height));
     let cb2 = encoder.finish();
 
@@ -130,29 +149,50 @@ pub fn start(gui: &mut GuiProgram) {
 
     // Start the thread that queues files for upload
     // First, make sure the file-tree is read
-    let root = crate::files::get_roots().unwrap();
-    match root.deserialize("backuplist.dat") {
+    let gitignore = gui.state_manager.config.respect_gitignore;
+    let root = crate::files::get_roots(gitignore).unwrap();
+    match root.deserialize("backuplist.dat", gitignore) {
         Ok(_) => {},
         Err(e) => {
-            println!("Error reading backuplist.dat - Reason: {:?}", e);
+            println!("Error reading backuplist.dat - Reason: {}", e);
             gui.state_manager.status_channel_tx.send("Got no files for upload, make sure you've selected some".to_string()).unwrap();
             return
         },
     }
+    // Set once all files are queued, so the upload threads know when they're done
+    let listed = Arc::new(AtomicBool::new(false));
     let q = gui.state_manager.upload_state.queue.clone();
-    std::thread::spawn(move || root.get_files_for_upload(&q));
+    let l = listed.clone();
+    let patterns = Patterns::load("backuplist.dat");
+    let filters = Arc::new(Filters::from_config(&gui.state_manager.config));
+    gui.state_manager.upload_state.filters = filters.clone();
+    let f = filters.clone();
+    std::thread::spawn(move || {
+        root.get_files_for_upload(&q, &patterns, &f);
+        if let Some(s) = f.summary() {
+            println!("{}", s);
+        }
+        l.store(true, Ordering::SeqCst);
+    });
 
     // Start the upload threads
     let q = gui.state_manager.upload_state.queue.clone();
     let i = gui.state_manager.upload_state.instances.clone();
     let bid = gui.state_manager.config.bucket_id.clone();
     let bw = gui.state_manager.config.bandwidth_limit;
+    let compress = gui.state_manager.config.compression;
+    let mode = gui.state_manager.config.repository_mode;
+    let pack_threshold = gui.state_manager.config.pack_threshold as u64 * 1000; // KB to B
+    let naming = Naming::from_config(&gui.state_manager.config);
     let keystring = format!("{}:{}", gui.state_manager.config.app_key_id, gui.state_manager.config.app_key);
     let tx = gui.state_manager.status_channel_tx.clone();
-    std::thread::spawn(move || start_upload_threads(q, i, &bid, bw, keystring, tx));
+    std::thread::spawn(move || start_upload_threads(q, listed, i, &bid, bw, compress, mode, pack_threshold, naming, filters, keystring, tx));
 }
 
-fn start_upload_threads(queue: Arc<Mutex<Vec<PathBuf>>>, instances: Arc<Mutex<Vec<UploadInstance>>>, bucket_id: &str, bw: u32, keystring: String, tx: Sender<String>) {
+#[allow(clippy::too_many_arguments)]
+fn start_upload_threads(queue: Arc<Mutex<Vec<PathBuf>>>, listed: Arc<AtomicBool>, instances: Arc<Mutex<Vec<UploadInstance>>>, bucket_id: &str, bw: u32,
+                        compress: bool, mode: RepositoryMode, pack_threshold: u64, naming: Naming, filters: Arc<Filters>, keystring: String,
+                        tx: Sender<String>) {
     println!("Starting upload, getting file info on stored files");
 
     // Bandwidth per thread
@@ -178,8 +218,9 @@ fn start_upload_threads(queue: Arc<Mutex<Vec<PathBuf>>>, instances: Arc<Mutex<Ve
     // Get all files stored on the server
     // We need this to get the 'last changed' metatdata, which we use to determine
     // if the file has changed and needs to be re-uploaded
+    // Only files under our remote prefix matter, and their names are made relative to it, see files::naming
     let mut stored_file_list = match raze::util::list_all_files(&client, &auth, &bucket_id, 1000) {
-        Ok(f) => Arc::new(f),
+        Ok(f) => Arc::new(naming.strip_all(f)),
         Err(e) => {
             println!("Failed to get remote files - {:?}", e);
             tx.send("Failed talking to B2 - Check your Bucket ID".to_string()).unwrap();
@@ -190,6 +231,55 @@ fn start_upload_threads(queue: Arc<Mutex<Vec<PathBuf>>>, instances: Arc<Mutex<Ve
     Arc::get_mut(&mut stored_file_list).unwrap().sort();
     println!("Got {} files from remote", stored_file_list.len());
 
+    // In the chunked format, the previous manifest tells us which files changed
+    let chunked = match mode {
+        RepositoryMode::Files => None,
+        RepositoryMode::Chunked => match crate::files::manifest::latest(&client, &auth, &stored_file_list) {
+            Ok(previous) => Some(ChunkedUpload::new(&stored_file_list, previous, naming.clone())),
+            Err(e) => {
+                println!("Failed to get the latest manifest - {}", e);
+                tx.send("Failed to read the previous backup manifest".to_string()).unwrap();
+                return
+            },
+        },
+    };
+    // In the plain format, the previous manifest records what each stored object holds
+    // Only entries of plain objects are kept, by name
+    let previous: HashMap<String, ManifestEntry> = match mode {
+        RepositoryMode::Chunked => HashMap::new(),
+        RepositoryMode::Files => match manifest::latest(&client, &auth, &stored_file_list) {
+            Ok(m) => m.map_or(vec![], |m| m.files).into_iter()
+                .filter(|f| f.file_id.is_some() && f.link.is_none())
+                .map(|f| (f.name.clone(), f))
+                .collect(),
+            Err(e) => {
+                println!("Failed to get the latest manifest - {}", e);
+                tx.send("Failed to read the previous backup manifest".to_string()).unwrap();
+                return
+            },
+        },
+    };
+    // In the plain format, small files may be packed
+    // The index is read even if packing is disabled, as files packed before then have to be removed from it
+    let packed = match mode {
+        RepositoryMode::Chunked => None,
+        RepositoryMode::Files => match crate::files::packed::latest_index(&client, &auth, &stored_file_list) {
+            Ok(previous) => Some(PackedUpload::new(previous, pack_threshold, compress, bandwidth, naming.clone())),
+            Err(e) => {
+                println!("Failed to get the pack index - {}", e);
+                tx.send("Failed to read the pack index".to_string()).unwrap();
+                return
+            },
+        },
+    };
+
+    // Files stored in the plain format during this run, and the directories in any format, for the manifest
+    let plain_files: Mutex<Vec<ManifestEntry>> = Mutex::new(vec![]);
+    // Names of files with several hard links, see files::hardlinks
+    let hard_links = HardLinks::default();
+    // Rules are read now, so the manifest matches what was queued
+    let rules = manifest::read_rules("backuplist.dat");
+
     println!("Starting upload threads");
     // TODO(?) don't hardcode thread count as 8
     let pool = Pool::new(8); // Number of upload threads = number of concurrent uploads
@@ -197,20 +287,40 @@ fn start_upload_threads(queue: Arc<Mutex<Vec<PathBuf>>>, instances: Arc<Mutex<Ve
         // Spawn 1 upload task per worker
         for i in 0..pool.workers() {
             let q = queue.clone();
+            let listed = listed.clone();
             let sfl = stored_file_list.clone();
             let client = &client;
             let auth = &auth;
+            let chunked = &chunked;
+            let packed = &packed;
+            let plain_files = &plain_files;
+            let previous = &previous;
+            let naming = &naming;
+            let hard_links = &hard_links;
+            let filters = &filters;
             let instance_handle = instances.clone();
             let instance_num = i;
             scope.execute(move || {
                 let upauth = raze::api::b2_get_upload_url(&client, &auth, bucket_id).unwrap();
                 loop {
                     // Try and get work, if it fails, sleep and check again
+                    // Once all files have been queued, an empty queue means we're done
+                    // 'listed' is read before popping, as files are queued before it is set
+                    let finished = listed.load(Ordering::SeqCst);
                     let p = {
                         q.lock().unwrap().pop()
                     };
                     let path = match p {
                         Some(p) => p,
+                        // Files with several names were set aside until all names are known, see files::hardlinks
+                        None if finished => {
+                            let first_names = hard_links.release();
+                            if first_names.is_empty() {
+                                break;
+                            }
+                            q.lock().unwrap().extend(first_names);
+                            continue;
+                        },
                         None => {
                             std::thread::sleep(Duration::from_millis(5000));
                             continue;
@@ -218,21 +328,17 @@ fn start_upload_threads(queue: Arc<Mutex<Vec<PathBuf>>>, instances: Arc<Mutex<Ve
                     };
                     let path_str = path.to_string_lossy().replace("\\", "/");
 
-                    // Under Unix, all paths are naturally prefix with '/' (the root)
-                    // B2 will not emulate folders if we start the path with a slash,
-                    // so we strip it here to make it behave correctly
-                    let name_in_b2 = if cfg!(windows) {
-                        &path_str
-                    } else {
-                        &path_str[1..]
-                    };
+                    // Name relative to the remote prefix, which the stored file list and the manifest use
+                    let name_in_b2 = &naming.name_for_path(&path);
+                    // Name of the plain object, which differs if the name is too long for B2, see files::naming
+                    let object_name = naming.object_name(name_in_b2);
 
                     // Construct a StoredFile with the target name so we can binary search for it
                     // If found, check if it has been modified since it was uploaded
                     // If it has: upload it, if it hasn't: skip it
                     // Note that only file_name matters for comparing
                     let sf = raze::api::B2FileInfo {
-                        file_name: name_in_b2.to_string(),
+                        file_name: object_name.clone(),
                         file_id: None,
                         account_id: auth.account_id.clone(),
                         bucket_id: bucket_id.to_string(),
@@ -242,8 +348,46 @@ fn start_upload_threads(queue: Arc<Mutex<Vec<PathBuf>>>, instances: Arc<Mutex<Ve
                         action: "".to_owned(),
                         upload_timestamp: 0
                     };
+                    // Stored links are uploaded as their target, whatever the storage format, see files::links
+                    if let Some(target) = links::stored_target(&path) {
+                        let modified = links::modified(&path);
+                        if let Some(chunked) = chunked {
+                            chunked.add_link(name_in_b2, &target, modified);
+                            continue;
+                        }
+                        let stored = sfl.binary_search(&sf).ok().map(|v| &sfl[v])
+                            .filter(|s| s.content_type.as_deref() == Some(links::CONTENT_TYPE) && modified <= s.upload_timestamp);
+                        let entry = match stored {
+                            Some(s) => Some(ManifestEntry::plain(name_in_b2, target.len() as u64, modified, s)),
+                            None => {
+                                println!("Storing link {:?} -> {:?}", path_str, target);
+                                match objects::upload(client, &upauth, &naming.remote(&object_name), target.clone().into_bytes(),
+                                                      links::CONTENT_TYPE, false, 0, None) {
+                                    Ok(f) => Some(ManifestEntry::plain(name_in_b2, target.len() as u64, modified, &f)),
+                                    Err(e) => {
+                                        println!("Failed to store link {:?} - {}", path, e);
+                                        None
+                                    },
+                                }
+                            },
+                        };
+                        if let Some(entry) = entry {
+                            plain_files.lock().unwrap().push(entry.with_link(&target));
+                        }
+                        continue;
+                    }
+
+                    // Directories are only recorded in the manifest, whatever the storage format
+                    if path.is_dir() {
+                        let modified = std::fs::metadata(&path).ok()
+                            .and_then(|m| m.modified().ok())
+                            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                            .map_or(0, |d| d.as_millis() as u64);
+                        plain_files.lock().unwrap().push(ManifestEntry::directory(name_in_b2, modified, posix::read(&path)));
+                        continue;
+                    }
+
                     // Compare modified time
-                    let do_upload: bool;
                     let metadata = match std::fs::metadata(&path) {
                         Ok(m) => m,
                         Err(e) => {
@@ -256,31 +400,140 @@ fn start_upload_threads(queue: Arc<Mutex<Vec<PathBuf>>>, instances: Arc<Mutex<Ve
                         Err(_e) => 0u64
                     };
                     let filesize = metadata.len(); // Used later as well
+                    // Permissions, ownership etc. are recorded even if the file is unchanged, see files::posix
+                    let posix = posix::read(&path);
+
+                    // Files with several names are set aside, only the first name is uploaded, see files::hardlinks
+                    if hard_links.defer(&path, name_in_b2, filesize, modified_time, &posix) {
+                        continue;
+                    }
+
+                    if let Some(chunked) = chunked {
+                        if chunked.reuse_unchanged(name_in_b2, filesize, modified_time, &posix) {
+                            continue;
+                        }
+                        if filters.skip_nodump(&path, filesize) {
+                            chunked.keep_previous(name_in_b2);
+                            continue;
+                        }
+                        println!("Uploading {:?}", path_str);
+                        let tx = {
+                            let inst = &mut instance_handle.lock().unwrap()[instance_num];
+                            inst.name = path_str.clone();
+                            inst.size = filesize;
+                            inst.progress = 0;
+                            inst.sender.clone()
+                        };
+                        if let Err(e) = chunked.upload_file(client, &upauth, &path, name_in_b2, filesize, modified_time, posix, compress, bandwidth, tx) {
+                            println!("Failed to upload {:?} - {}", path, e);
+                            chunked.keep_previous(name_in_b2);
+                        }
+                        continue;
+                    }
 
-                    match sfl.binary_search(&sf) {
-                        Ok(v) => { // A file with the same path+name exists
-                            // Check if the local file was modified since it was last uploaded
-                            if modified_time > sfl[v].upload_timestamp {
-                                do_upload = true;
-                            } else {
-                                do_upload = false;
+                    if let Some(packed) = packed {
+                        if packed.should_pack(filesize) {
+                            if packed.reuse_unchanged(name_in_b2, filesize, modified_time, &posix) {
+                                continue;
                             }
-                        },
-                        Err(_e) => { // No matching path+name exists
-                            do_upload = true;
+                            if filters.skip_nodump(&path, filesize) {
+                                packed.keep_previous(name_in_b2);
+                                continue;
+                            }
+                            println!("Packing {:?}", path_str);
+                            let tx = {
+                                let inst = &mut instance_handle.lock().unwrap()[instance_num];
+                                inst.name = path_str.clone();
+                                inst.size = filesize;
+                                inst.progress = 0;
+                                inst.sender.clone()
+                            };
+                            if let Err(e) = packed.add_file(client, &upauth, &path, name_in_b2, modified_time, posix, tx) {
+                                println!("Failed to pack {:?} - {}", path, e);
+                            }
+                            continue;
                         }
                     }
+
+                    // The currently stored version, if any
+                    let stored = sfl.binary_search(&sf).ok().map(|v| &sfl[v]);
+                    // What the previous manifest recorded for it, if it's still the same object
+                    let recorded = stored.and_then(|s| previous.get(name_in_b2).filter(|e| e.file_id == s.file_id));
+                    let do_upload = match stored {
+                        Some(stored) => { // A file with the same path+name exists
+                            // Check if the local file was modified since it was last uploaded,
+                            // or its metadata changed, which the object carries as file info
+                            // What an object no manifest recorded carries isn't known, so it is uploaded again if there is metadata
+                            let posix_changed = match (recorded.map(|e| &e.posix), &posix) {
+                                (Some(Some(old)), Some(new)) => old.changed(new),
+                                (Some(old), new) => old.is_some() != new.is_some(),
+                                (None, new) => new.is_some(),
+                            };
+                            modified_time > stored.upload_timestamp || posix_changed
+                        },
+                        None => true, // No matching path+name exists
+                    };
                     if !do_upload {
                         //println!("Skipping {:?}", path_str);
+                        // The metadata and extents the object carries, the metadata only differs from 'posix' in the access time
+                        let posix = recorded.and_then(|e| e.posix.clone());
+                        let extents = recorded.and_then(|e| e.sparse.clone());
+                        let entry = ManifestEntry::plain(name_in_b2, filesize, modified_time, stored.unwrap()).with_posix(posix);
+                        plain_files.lock().unwrap().push(entry.with_sparse(extents));
+                        continue;
+                    }
+                    // Files set to nodump since they were stored keep the stored version, like other filtered files
+                    if filters.skip_nodump(&path, filesize) {
+                        if let Some(e) = recorded {
+                            plain_files.lock().unwrap().push(e.clone());
+                        }
                         continue;
                     }
                     println!("Uploading {:?}", path_str);
 
+                    // Only the data of sparse files is uploaded, their extents are stored as file info, see files::sparse
+                    let extents = sparse::data_extents(&path, filesize);
+                    let data_size = extents.as_ref().map_or(filesize, |e| sparse::data_len(e));
+                    let data_reader = |file: std::fs::File| -> Box<dyn Read + Send> {
+                        match &extents {
+                            Some(e) => Box::new(DataReader::new(file, e.clone())),
+                            None => Box::new(file),
+                        }
+                    };
+
+                    // Compress to a temporary file if enabled and the file looks compressible
+                    // If compression doesn't make the file smaller, the original is uploaded instead
+                    let temp = compression::temp_path(instance_num);
+                    let try_compress = compress && compression::should_compress(&path, filesize);
+                    let compressed_size = if try_compress {
+                        match std::fs::File::open(&path).and_then(|f| compression::compress_to(data_reader(f), &temp)) {
+                            Ok(size) if size < data_size => Some(size),
+                            Ok(_) => None,
+                            Err(e) => {
+                                println!("Failed to compress {:?} ({:?}) - Uploading it uncompressed", path, e);
+                                None
+                            }
+                        }
+                    } else {
+                        None
+                    };
+                    let (upload_path, upload_size) = match compressed_size {
+                        Some(size) => (temp.clone(), size),
+                        None => (path.clone(), data_size),
+                    };
 
                     // Try uploading up to 5 times
+                    // If it fails, the previously stored version (if any) is what the backup holds
+                    let remote_name = naming.remote(&object_name);
+                    let posix_info = match &posix {
+                        Some(p) => p.file_info(client, &upauth, naming, &sfl),
+                        None => vec![],
+                    };
+                    let mut uploaded = None;
                     for attempts in 0..5 {
-                        let file = match std::fs::File::open(&path) {
-                            Ok(f) => f,
+                        let file: Box<dyn Read + Send> = match std::fs::File::open(&upload_path) {
+                            Ok(f) if compressed_size.is_some() => Box::new(f),
+                            Ok(f) => data_reader(f),
                             Err(e) => {
                                 println!("Failed to open file {:?} ({:?}) - It will not be uploaded", path, e);
                                 break;
@@ -291,17 +544,26 @@ fn start_upload_threads(queue: Arc<Mutex<Vec<PathBuf>>>, instances: Arc<Mutex<Ve
                         let tx = {
                             let inst = &mut instance_handle.lock().unwrap()[instance_num];
                             inst.name = path_str.clone();
-                            inst.size = filesize;
+                            inst.size = upload_size;
                             inst.progress = 0;
                             inst.sender.clone()
                         };
 
-                        let params = raze::api::FileParameters {
-                            file_path: name_in_b2,
-                            file_size: filesize,
-                            content_type: None, // auto
+                        // Mark compressed files, so restore knows to decompress them
+                        let mut file_info = posix_info.clone();
+                        if compressed_size.is_some() {
+                            file_info.push((compression::INFO_KEY, compression::ZSTD.to_string()));
+                        }
+                        if let Some(e) = &extents {
+                            file_info.push((sparse::INFO_KEY, sparse::encode(filesize, e)));
+                        }
+                        let params = crate::b2::FileParameters {
+                            file_path: &remote_name,
+                            file_size: upload_size,
+                            content_type: if compressed_size.is_some() { Some("application/zstd") } else { None }, // None = auto
                             content_sha1: Sha1Variant::HexAtEnd,
-                            last_modified_millis: modified_time
+                            last_modified_millis: modified_time,
+                            file_info,
                         };
                         // Note that 'TrackedReader' has to be _after_ 'HashAtEnd' or it would read 40 bytes extra from the hash!
                         // If bandwidth == 0, do not throttle
@@ -312,16 +574,19 @@ fn start_upload_threads(queue: Arc<Mutex<Vec<PathBuf>>>, instances: Arc<Mutex<Ve
                                 ),
                                 bandwidth
                             );
-                            raze::api::b2_upload_file(&client, &upauth, file, params)
+                            crate::b2::b2_upload_file(&client, &upauth, file, params)
                         } else {
                             let file = raze::util::ReadHashAtEnd::wrap(
                                 TrackedReader::wrap(file, tx),
                             );
-                            raze::api::b2_upload_file(&client, &upauth, file, params)
+                            crate::b2::b2_upload_file(&client, &upauth, file, params)
                         };
 
                         match result {
-                            Ok(_) => break,
+                            Ok(f) => {
+                                uploaded = Some(f);
+                                break
+                            },
                             Err(e) => {
                                 println!("Upload failed: {:?}", e);
                                 match e {
@@ -341,9 +606,80 @@ fn start_upload_threads(queue: Arc<Mutex<Vec<PathBuf>>>, instances: Arc<Mutex<Ve
                             }
                         }
                     }
+                    // Also when the original was uploaded instead, the temporary file may hold all or part of it
+                    if try_compress {
+                        let _ = std::fs::remove_file(&temp);
+                    }
+                    if let Some(f) = &uploaded {
+                        let entry = ManifestEntry::plain(name_in_b2, filesize, modified_time, f).with_posix(posix);
+                        plain_files.lock().unwrap().push(entry.with_sparse(extents));
+                    } else if let Some(e) = recorded {
+                        // The backup still holds the stored version, as it was recorded, not as the local file is now
+                        plain_files.lock().unwrap().push(e.clone());
+                    } else if stored.is_some() {
+                        println!("Leaving {:?} out of the manifest, the stored version wasn't recorded by a previous one", path);
+                    }
                 }
             });
 
         }
     });
+
+    // Files the filters skipped weren't looked at, the backup still holds what the previous manifest recorded for them
+    // Plain objects are only kept if they are still stored, see files::filters
+    let skipped = filters.take_skipped();
+    if !skipped.is_empty() {
+        let stored_ids: HashSet<&str> = stored_file_list.iter().filter_map(|s| s.file_id.as_deref()).collect();
+        for path in skipped {
+            let name = naming.name_for_path(&path);
+            if let Some(chunked) = &chunked {
+                chunked.keep_previous(&name);
+                continue;
+            }
+            if packed.as_ref().is_some_and(|p| p.keep_previous(&name)) {
+                continue;
+            }
+            if let Some(e) = previous.get(&name).filter(|e| e.file_id.as_deref().is_some_and(|id| stored_ids.contains(id))) {
+                plain_files.lock().unwrap().push(e.clone());
+            }
+        }
+    }
+
+    // All threads are done, record what this run uploaded
+    let mut files = plain_files.into_inner().unwrap();
+    if let Some(chunked) = chunked {
+        files.append(&mut chunked.finish());
+    }
+    if let Some(packed) = packed {
+        match packed.finish(&client, &auth, bucket_id) {
+            Ok(p) => files.extend(p.into_iter().map(ManifestEntry::from)),
+            Err(e) => {
+                println!("Failed to upload pack index - {}", e);
+                tx.send("Failed to upload the pack index".to_string()).unwrap();
+                return;
+            }
+        }
+    }
+    // Other names of a file that failed to upload have nothing to refer to, they are left out until it is uploaded
+    files.append(&mut hard_links.entries());
+    let stored: HashSet<String> = files.iter().filter(|f| f.hardlink.is_none()).map(|f| f.name.clone()).collect();
+    files.retain(|f| match &f.hardlink {
+        Some(first) if !stored.contains(first) => {
+            println!("Leaving out {:?}, as {:?} which it is a hard link to failed to upload", f.name, first);
+            false
+        },
+        _ => true,
+    });
+    let manifest = Manifest::new(files, rules);
+    println!("Uploading manifest with {} files", manifest.files.len());
+    if let Err(e) = manifest.upload(&client, &auth, bucket_id, &naming) {
+        println!("Failed to upload manifest - {}", e);
+        tx.send("Failed to upload the backup manifest".to_string()).unwrap();
+        return;
+    }
+    if let Err(e) = manifest.save_local() {
+        println!("Failed to save a local copy of the manifest - {:?}", e);
+    }
+    println!("Upload completed");
+    tx.send("Upload completed".to_string()).unwrap();
 }